@@ -0,0 +1,58 @@
+//! cargo bench --bench sharded, BENCH_ROWS sets the size of the synthetic input, 4M rows by default
+use std::env;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::thread;
+use std::time::Instant;
+
+use bkeeper::model::{Bookkeeper, InputFormat, RejectionLog};
+
+/// write_input writes rows spread over 10K clients, mostly deposits and withdrawals, with some disputes on the way
+fn write_input(path: &Path, rows: u32) {
+    let mut w = BufWriter::new(File::create(path).unwrap());
+    writeln!(w, "type, client, tx, amount").unwrap();
+    for tx in 1..=rows {
+        let client = tx % 10_000;
+        match tx % 17 {
+            3 => writeln!(w, "withdrawal, {}, {}, 0.5", client, tx),
+            7 if tx > 10_000 => writeln!(w, "dispute, {}, {},", client, tx - 10_000),
+            11 if tx > 20_000 => writeln!(w, "resolve, {}, {},", client, tx - 20_000),
+            _ => writeln!(w, "deposit, {}, {}, {}.{}", client, tx, tx % 100, tx % 9),
+        }
+        .unwrap();
+    }
+    w.flush().unwrap();
+}
+
+fn main() {
+    let rows = env::var("BENCH_ROWS").ok().and_then(|r| r.parse().ok()).unwrap_or(4_000_000);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("transactions.csv");
+    write_input(&path, rows);
+
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    println!("{} rows, {} core(s)", rows, cores);
+
+    let mut baseline = None;
+    for threads in [1, 2, 4, 8, 16] {
+        let mut bkeeper = Bookkeeper::new();
+        let mut rejections = RejectionLog::default();
+        let f = BufReader::new(File::open(&path).unwrap());
+
+        let start = Instant::now();
+        bkeeper.process_input_parallel(f, InputFormat::Csv, &mut rejections, threads).unwrap();
+        let elapsed = start.elapsed().as_secs_f64();
+
+        let baseline = *baseline.get_or_insert(elapsed);
+        println!(
+            "{:>2} thread(s): {:>6.2}s {:>10.0} rows/s x{:.2} ({} accounts, {} rejections)",
+            threads,
+            elapsed,
+            rows as f64 / elapsed,
+            baseline / elapsed,
+            bkeeper.accounts.len(),
+            rejections.rejections.len()
+        );
+    }
+}
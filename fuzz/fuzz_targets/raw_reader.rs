@@ -0,0 +1,37 @@
+#![no_main]
+
+use bkeeper::model::{Bookkeeper, InputFormat, Rejection, RejectionLog, ReportFormat, ReportOrder, ReportWriter};
+use libfuzzer_sys::fuzz_target;
+
+fn report(keeper: &Bookkeeper) -> Vec<u8> {
+    let mut buf = Vec::new();
+    keeper.write_report(&mut ReportWriter::new(&mut buf, ReportFormat::Csv), ReportOrder::Insertion).unwrap();
+    buf
+}
+
+fn rejected(rejections: &[Rejection]) -> Vec<(u64, &'static str)> {
+    rejections.iter().map(|r| (r.line, r.reason.code())).collect()
+}
+
+// any partner file, as csv and as JSON lines: it's processed or it's an io error, and the ledger always holds
+fuzz_target!(|data: &[u8]| {
+    for format in [InputFormat::Csv, InputFormat::JsonLines] {
+        let mut keeper = Bookkeeper::new();
+        let mut rejections = RejectionLog::default();
+        let result = keeper.process_input(data, format, &mut rejections);
+
+        // the rows before an unreadable one are applied, and must be consistent too
+        assert!(keeper.trial_balance().is_balanced());
+        assert_eq!(keeper.audit(), vec![]);
+
+        // the parallel engine gives the same result, even when the general ledger can't take a row
+        if result.is_ok() {
+            let mut parallel = Bookkeeper::new();
+            let mut parallel_rejections = RejectionLog::default();
+            parallel.process_input_parallel(data, format, &mut parallel_rejections, 2).unwrap();
+            assert_eq!(report(&parallel), report(&keeper));
+            assert_eq!(rejected(&parallel_rejections.rejections), rejected(&rejections.rejections));
+            assert!(parallel.trial_balance() == keeper.trial_balance());
+        }
+    }
+});
@@ -0,0 +1,107 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use bkeeper::model::{AccountPolicy, AuditMode, Bookkeeper, Currency, FeeRule, FeeSchedule, Precision, PrecisionRule, RoundingMode, Timestamp, Transaction, TxType};
+use libfuzzer_sys::fuzz_target;
+use rust_decimal::Decimal;
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    any_row: bool,
+    strict_time: bool,
+    /// the places and the rounding mode of every currency, the default one if None
+    precision: Option<(u8, u8)>,
+    /// a flat fee and a percentage, on the deposits/withdrawals/disputes/resolves/chargebacks by the type of each
+    fees: Vec<(u8, u16, u8)>,
+    txs: Vec<Tx>,
+}
+
+/// Tx is a transaction on a few clients, tx ids and currencies, so that they collide, with any amount a Decimal can hold
+#[derive(Arbitrary, Debug)]
+struct Tx {
+    r#type: u8,
+    client: u8,
+    tx: u8,
+    amount: Option<(i128, u8)>,
+    currency: Option<u8>,
+    reason: Option<String>,
+    /// seconds since the epoch, long before the time of the clock, which the ones without a timestamp get
+    timestamp: Option<u16>,
+}
+
+/// tx_type maps any byte to a type, the ones on funds first
+fn tx_type(n: u8) -> TxType {
+    match n % 8 {
+        0 => TxType::Deposit,
+        1 => TxType::Withdrawal,
+        2 => TxType::Dispute,
+        3 => TxType::Resolve,
+        4 => TxType::ChargeBack,
+        5 => TxType::Unlock,
+        6 => TxType::Freeze,
+        _ => TxType::Close,
+    }
+}
+
+impl Tx {
+    fn transaction(&self) -> Transaction {
+        Transaction {
+            r#type: tx_type(self.r#type),
+            client_id: (self.client % 8) as u16,
+            tx_id: (self.tx % 32) as u32,
+            amount: self.amount.and_then(|(m, scale)| Decimal::try_from_i128_with_scale(m, (scale % 29) as u32).ok()),
+            currency: self.currency.map(|c| [Currency::USD, Currency::EUR, Currency::GBP][(c % 3) as usize]),
+            reason: self.reason.clone(),
+            timestamp: self.timestamp.map(|t| Timestamp(t as u64)),
+        }
+    }
+}
+
+// any sequence of transactions: a rejected one changes nothing, and no invariant is ever broken
+fuzz_target!(|input: Input| {
+    let policy = if input.any_row { AccountPolicy::AnyRow } else { AccountPolicy::OnDeposit };
+    let mut keeper = Bookkeeper::with_policy(policy);
+    keeper.set_audit(AuditMode::EachTx);
+    keeper.set_strict_time(input.strict_time);
+    if let Some((places, mode)) = input.precision {
+        let mode = [RoundingMode::Bankers, RoundingMode::HalfUp, RoundingMode::Truncate, RoundingMode::Reject][(mode % 4) as usize];
+        keeper.set_precision(Precision {
+            default: PrecisionRule { places: (places % 29) as u32, mode },
+            ..Precision::new()
+        });
+    }
+
+    let fees = input.fees.iter().map(|&(r#type, flat, percent)| FeeRule {
+        r#type: tx_type(r#type % 5),
+        currency: None,
+        flat: Decimal::new(flat as i64, 2),
+        percent: Decimal::from(percent % 101),
+        tiers: Vec::new(),
+        min: None,
+        max: None,
+    });
+    keeper.set_fees(FeeSchedule { fees: fees.collect() });
+
+    for (line, tx) in input.txs.iter().enumerate() {
+        let tx = tx.transaction();
+        let fingerprint = |keeper: &Bookkeeper| keeper.accounts.get(&tx.client_id).map(|a| (a.buckets.clone(), a.state, a.events().len()));
+
+        let before = fingerprint(&keeper);
+        let result = keeper.process_tx(line as u64 + 2, &tx).unwrap();
+        if result.is_err() && before.is_some() {
+            assert_eq!(fingerprint(&keeper), before, "{:?}", tx);
+        }
+    }
+
+    // the account as of after everything is the account now, and in strict mode its events are in time order
+    for acct in keeper.accounts.values() {
+        let now = acct.at(u64::MAX).unwrap();
+        for currency in acct.currencies() {
+            assert_eq!((now.balances(currency), now.fees(currency)), (acct.balances(currency), acct.fees(currency)));
+        }
+        assert!(!input.strict_time || acct.events().windows(2).all(|w| w[0].time <= w[1].time));
+    }
+
+    assert!(keeper.trial_balance().is_balanced());
+    assert_eq!(keeper.finish_audit(), vec![]);
+});
@@ -1,39 +1,313 @@
-use std::env;
-use std::ffi::OsString;
-use std::fs::File;
-use std::io::{BufReader, Error, ErrorKind};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
 
 use anyhow::*;
-use log::*;
+use clap::{Parser, Subcommand};
+use tokio::{net::TcpListener, sync::watch};
 
-use bkeeper::model::Bookkeeper;
+use bkeeper::model::{
+    AccountPolicy, AuditMode, Bookkeeper, FeeSchedule, InputFormat, Journal, Limits, LogRejections, Precision, PrecisionSetting, RejectionFormat, RejectionWriter, ReportFormat, ReportOrder, ReportWriter,
+    StatementFormat, SyncPolicy, Timestamp, MAX_SHARDS,
+};
+use bkeeper::server::{self, Ledger};
+
+/// bkeeper transactions.csv > accounts.csv
+/// bkeeper transactions.ndjson > accounts.csv
+/// bkeeper transactions.csv statement 7
+#[derive(Parser)]
+#[command(version, about, subcommand_negates_reqs = true)]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// the transactions file, csv or JSON lines
+    #[arg(required_unless_present_any = ["listen", "http"])]
+    input: Option<PathBuf>,
+
+    /// instead of reading a file, accept csv or JSON lines feeds on this address, e.g., 127.0.0.1:7878, and ack every line.
+    /// The report is written, and the snapshot saved, on ctrl-c.
+    #[arg(long, conflicts_with_all = ["input", "threads", "rejections"])]
+    listen: Option<String>,
+
+    /// serve the REST API on this address, e.g., 127.0.0.1:8080, on the same ledger as --listen if both are given
+    #[arg(long, conflicts_with_all = ["input", "threads", "rejections"])]
+    http: Option<String>,
+
+    /// format of the input: csv or json (one object per line). Defaults to json for a .json, .jsonl or .ndjson file, otherwise csv
+    #[arg(long)]
+    input_format: Option<InputFormat>,
+
+    /// write the account report to this file instead of stdout
+    #[arg(long, short)]
+    output: Option<PathBuf>,
+
+    /// format of the account report: csv, json (a single array) or ndjson (one object per line)
+    #[arg(long, default_value = "csv")]
+    output_format: ReportFormat,
+
+    /// order of the account report: client (by client id), total-desc:<currency> (by the total in that currency) or insertion (as the accounts were opened)
+    #[arg(long, default_value = "client")]
+    order: ReportOrder,
+
+    /// write the account report with the balances at this time, RFC 3339 or seconds since the unix epoch, e.g., 2026-09-30T23:59:59Z
+    #[arg(long)]
+    as_of: Option<Timestamp>,
+
+    /// write the statement of every client to this directory, one file per client, e.g., 7.csv. A statement covers the input,
+    /// from the balances of --from-snapshot, if any, e.g., a month.
+    #[arg(long)]
+    statements: Option<PathBuf>,
+
+    /// format of the statements: csv or text
+    #[arg(long, default_value = "csv")]
+    statements_format: StatementFormat,
+
+    /// the decimal places of the amounts, and how the extra ones are rounded: bankers, half-up, truncate or reject.
+    /// [currency=]places[:mode], e.g., 2:bankers for every currency and JPY=0:reject for one. Defaults to 4:half-up.
+    #[arg(long)]
+    precision: Vec<PrecisionSetting>,
+
+    /// charge the fees of this schedule, a JSON file, on the accepted transactions, e.g.,
+    /// {"fees": [{"type": "withdrawal", "flat": "0.5", "percent": "1", "min": "1", "max": "20"}]}
+    #[arg(long)]
+    fees: Option<PathBuf>,
+
+    /// check the limits of this JSON file before every deposit/withdrawal, e.g.,
+    /// {"limits": [{"type": "withdrawal", "max_amount": "1000"}, {"type": "withdrawal", "window": "1m", "max_count": 10}]}
+    #[arg(long)]
+    limits: Option<PathBuf>,
+
+    /// reject a transaction whose timestamp is before the one of the last transaction of its client, with out_of_order.
+    /// A transaction without a timestamp has the time it's accepted at.
+    #[arg(long)]
+    strict_time: bool,
+
+    /// write the trial balance of the general ledger to this file, as csv, with the rounding residue of every currency.
+    /// It fails if it doesn't net to zero in every currency.
+    #[arg(long)]
+    trial_balance: Option<PathBuf>,
+
+    /// check the invariants of the ledger, e.g., held is what is in dispute, after each transaction with --audit=each
+    /// or once at the end with --audit. The violations are logged, and it fails if there is any.
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "end")]
+    audit: Option<AuditMode>,
+
+    /// write every rejected row to this file instead of logging it
+    #[arg(long)]
+    rejections: Option<PathBuf>,
+
+    /// format of the rejection report: csv or json (one object per line)
+    #[arg(long, default_value = "csv")]
+    rejections_format: RejectionFormat,
+
+    /// when a client gets an account: on-deposit, any-row or registered. Defaults to registered with --clients, otherwise on-deposit
+    #[arg(long)]
+    account_policy: Option<AccountPolicy>,
+
+    /// the client master file, a csv with a client column, whose clients are registered before processing
+    #[arg(long)]
+    clients: Option<PathBuf>,
+
+    /// start from the state saved by a previous run with --save-snapshot
+    #[arg(long)]
+    from_snapshot: Option<PathBuf>,
+
+    /// save the state after processing, so that the next batch can go on from it
+    #[arg(long)]
+    save_snapshot: Option<PathBuf>,
+
+    /// process the rows on this many threads, each owning the accounts of a subset of the clients
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..=MAX_SHARDS as i64), conflicts_with = "journal")]
+    threads: u16,
+
+    /// journal every accepted transaction to this file before applying it, and every rejected row
+    #[arg(long)]
+    journal: Option<PathBuf>,
+
+    /// replay the journal, if it exists, and go on after its last line, e.g., after a crash.
+    /// Use the same input, snapshot and clients as the crashed run. The journaled rejections are written again.
+    #[arg(long, requires = "journal")]
+    recover: bool,
+
+    /// how often the journal is fsynced: always, never or batch=<n>
+    #[arg(long, default_value = "batch=1000")]
+    journal_sync: SyncPolicy,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// print the statement of a client instead of the account report: every transaction applied, with the running balances.
+    /// The input is optional with --from-snapshot.
+    Statement {
+        /// the client id
+        client: u16,
+    },
+}
 
 fn main() -> Result<()> {
-    env_logger::builder().format_timestamp_nanos().target(env_logger::Target::Stdout).init();
-    if env::args().count() != 2 {
-        print_usage();
-        return Ok(());
+    // the account report goes to stdout, so keep the logs away from it
+    env_logger::builder().format_timestamp_nanos().target(env_logger::Target::Stderr).init();
+
+    let args = Args::parse();
+
+    let policy = args.account_policy.unwrap_or(match args.clients {
+        Some(_) => AccountPolicy::Registered,
+        None => AccountPolicy::OnDeposit,
+    });
+    let mut keeper = Bookkeeper::with_policy(policy);
+    if let Some(path) = &args.from_snapshot {
+        let snapshot = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        keeper.load_snapshot(snapshot).with_context(|| format!("failed to load {}", path.display()))?;
+    }
+    let mut precision = Precision::new();
+    for setting in &args.precision {
+        precision.set(*setting);
+    }
+    keeper.set_precision(precision);
+    if let Some(path) = &args.fees {
+        let fees = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        keeper.set_fees(FeeSchedule::from_reader(BufReader::new(fees)).with_context(|| format!("failed to load {}", path.display()))?);
+    }
+    if let Some(path) = &args.limits {
+        let limits = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        keeper.set_limits(Limits::from_reader(BufReader::new(limits)).with_context(|| format!("failed to load {}", path.display()))?);
+    }
+    keeper.set_strict_time(args.strict_time);
+    if let Some(mode) = args.audit {
+        keeper.set_audit(mode);
+    }
+    if let Some(path) = &args.clients {
+        let clients = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+        keeper.load_clients(BufReader::new(clients))?;
+    }
+    if let Some(path) = &args.journal {
+        if args.recover && path.exists() {
+            keeper.recover_journal(path, args.journal_sync).with_context(|| format!("failed to recover {}", path.display()))?;
+        } else {
+            keeper.attach_journal(Journal::create(path, args.journal_sync).with_context(|| format!("failed to create {}", path.display()))?);
+        }
+    }
+
+    match &args.input {
+        Some(input) => process_file(&mut keeper, input, &args)?,
+        None if args.listen.is_some() || args.http.is_some() => keeper = listen(keeper, &args)?,
+        None => {}
+    }
+    match &args.output {
+        Some(path) => {
+            let w = BufWriter::new(File::create(path).with_context(|| format!("failed to create {}", path.display()))?);
+            write_output(&keeper, w, &args)?;
+        }
+        None => write_output(&keeper, io::stdout().lock(), &args)?,
+    }
+
+    if let Some(path) = &args.trial_balance {
+        let trial_balance = keeper.trial_balance();
+        let w = BufWriter::new(File::create(path).with_context(|| format!("failed to create {}", path.display()))?);
+        trial_balance.write(w)?;
+        ensure!(trial_balance.is_balanced(), "the trial balance doesn't net to zero: {:?}", trial_balance.nets());
+    }
+
+    if let Some(dir) = &args.statements {
+        keeper.write_statements(dir, args.statements_format).with_context(|| format!("failed to write the statements to {}", dir.display()))?;
     }
 
-    let f = File::open(get_first_arg()?)?;
+    if let Some(path) = &args.save_snapshot {
+        save_snapshot(&keeper, path).with_context(|| format!("failed to save {}", path.display()))?;
+    }
 
-    let mut keeper = Bookkeeper::default();
-    keeper.process_reader(BufReader::new(f))?;
-    keeper.report_balance()?;
+    let violations = keeper.finish_audit();
+    ensure!(violations.is_empty(), "{} audit violation(s)", violations.len());
 
     Ok(())
 }
 
-fn print_usage() {
-    info!("bkeeper transactions.csv\nor\ncargo run -- transactions.csv > accounts.csv")
+/// write_output writes the account report, or the statement of the client of the statement command
+fn write_output<W: io::Write>(keeper: &Bookkeeper, w: W, args: &Args) -> Result<()> {
+    match &args.command {
+        Some(Command::Statement { client }) => {
+            let statement = keeper.statement(*client).with_context(|| format!("client {} has no account", client))?;
+            statement.write(w, StatementFormat::Text)?;
+        }
+        None => match args.as_of {
+            Some(time) => keeper.write_report_at(&mut ReportWriter::new(w, args.output_format), args.order, time)?,
+            None => keeper.write_report(&mut ReportWriter::new(w, args.output_format), args.order)?,
+        },
+    }
+
+    Ok(())
 }
 
-fn get_first_arg() -> Result<OsString> {
-    match env::args_os().nth(1) {
-        None => Err(anyhow::Error::new(Error::new(
-            ErrorKind::InvalidData,
-            "the input file is not represented",
-        ))),
-        Some(file_path) => Ok(file_path),
+fn process_file(keeper: &mut Bookkeeper, input: &Path, args: &Args) -> Result<()> {
+    let f = BufReader::new(File::open(input).with_context(|| format!("failed to open {}", input.display()))?);
+    let format = args.input_format.unwrap_or_else(|| InputFormat::from_path(input));
+
+    match &args.rejections {
+        Some(path) => {
+            let w = BufWriter::new(File::create(path).with_context(|| format!("failed to create {}", path.display()))?);
+            let mut rejections = RejectionWriter::new(w, args.rejections_format);
+            keeper.process_input_parallel(f, format, &mut rejections, args.threads as usize)?;
+        }
+        None => keeper.process_input_parallel(f, format, &mut LogRejections, args.threads as usize)?,
     }
+
+    Ok(())
+}
+
+/// listen serves the feeds and the REST API until ctrl-c, see bkeeper::server
+fn listen(keeper: Bookkeeper, args: &Args) -> Result<Bookkeeper> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(async {
+        let (ledger, task) = Ledger::start(keeper);
+        let (stop, stopped) = watch::channel(false);
+        let shutdown = || {
+            let mut stopped = stopped.clone();
+            async move {
+                let _ = stopped.wait_for(|stopped| *stopped).await;
+            }
+        };
+
+        let mut servers = Vec::new();
+        if let Some(addr) = &args.listen {
+            let listener = bind(addr).await?;
+            let (ledger, shutdown) = (ledger.clone(), shutdown());
+            servers.push(tokio::spawn(async move {
+                server::serve_feeds(listener, ledger, shutdown).await;
+                io::Result::Ok(())
+            }));
+        }
+        if let Some(addr) = &args.http {
+            let listener = bind(addr).await?;
+            servers.push(tokio::spawn(server::serve_api(listener, ledger.clone(), shutdown())));
+        }
+        drop(ledger);
+
+        tokio::signal::ctrl_c().await?;
+        stop.send_replace(true);
+        for server in servers {
+            server.await??;
+        }
+
+        Ok(task.await??)
+    })
+}
+
+async fn bind(addr: &str) -> Result<TcpListener> {
+    let listener = TcpListener::bind(addr).await.with_context(|| format!("failed to listen on {}", addr))?;
+    log::info!("listening on {}", listener.local_addr()?);
+    Ok(listener)
+}
+
+/// save_snapshot writes to a temporary file first, so that a crash never leaves a half-written snapshot at path
+fn save_snapshot(keeper: &Bookkeeper, path: &Path) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    let f = File::create(&tmp)?;
+    keeper.save_snapshot(&f)?;
+    f.sync_all()?;
+    fs::rename(&tmp, path)?;
+
+    Ok(())
 }
@@ -1,8 +1,61 @@
+pub mod currency;
+pub use currency::*;
+
+pub mod precision;
+pub use precision::*;
+
+pub mod fee;
+pub use fee::*;
+
+pub mod timestamp;
+pub use timestamp::*;
+
+pub mod clock;
+pub use clock::*;
+
+pub mod limits;
+pub use limits::*;
+
 pub mod transaction;
 pub use transaction::*;
 
+pub mod effect;
+pub use effect::*;
+
+pub mod general_ledger;
+pub use general_ledger::*;
+
+pub mod audit;
+pub use audit::*;
+
 pub mod account;
 pub use account::*;
 
+pub mod txid;
+pub use txid::*;
+
+pub mod rejection;
+pub use rejection::*;
+
+pub mod store;
+pub use store::*;
+
+pub mod report;
+pub use report::*;
+
+pub mod statement;
+pub use statement::*;
+
+pub mod snapshot;
+pub use snapshot::*;
+
+pub mod input;
+pub use input::*;
+
+pub mod journal;
+pub use journal::*;
+
 pub mod bookkeeper;
 pub use bookkeeper::*;
+
+pub mod parallel;
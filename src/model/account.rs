@@ -1,19 +1,21 @@
-use std::collections::HashMap;
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap},
+};
 
 // use anyhow::*;
 use log::*;
 use rust_decimal::Decimal;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use super::{Transaction, TxType};
+use super::{exact_add, Change, Currency, Effect, FeeSchedule, LedgerAccount, PrecisionRule, Timestamp, Transaction, TxType, Window};
 
 const DEFAULT_COUNT: usize = 8096;
-const MAX_DECIMAL_PLACES: u32 = 4;
 
-#[derive(Error, Debug, PartialEq)]
+#[derive(Error, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum TxError {
-    /// Happens when a non-deposit comes, but the client is unexisted
+    /// Happens when the client has no account and the row can't open one, see AccountPolicy
     #[error("invalid client")]
     InvalidClientError,
 
@@ -37,180 +39,451 @@ pub enum TxError {
     #[error("locked account")]
     LockedAccountError,
 
+    /// Happens when trying to deposit/withdraw on a frozen account
+    #[error("frozen account")]
+    FrozenAccountError,
+
+    /// Happens when trying to do anything on a closed account
+    #[error("closed account")]
+    ClosedAccountError,
+
     /// Happens when trying to do transactions on transactions with unexpected statuses, e.g., resolving on an non-disputed transaction
     #[error("invalid operation")]
     InvalidOperatioonError,
+
+    /// Happens when a deposit/withdrawal reuses a tx id which already belongs to another client
+    #[error("duplicate Tx ID across clients")]
+    DuplicateTxIdError,
+
+    /// Happens when a dispute/resolve/chargeback refers to a tx id of another client
+    #[error("Tx ID belongs to client {owner}")]
+    ClientMismatchError { owner: u16 },
+
+    /// Happens when a dispute/resolve/chargeback gives another currency than the one of its deposit/withdrawal
+    #[error("currency of the Tx is another one")]
+    CurrencyMismatchError,
+
+    /// Happens when an amount has more decimal places than its currency, and its rounding mode is reject
+    #[error("too many decimal places")]
+    ExcessPrecisionError,
+
+    /// Happens when a deposit/withdrawal is over the max_amount of a limit, see Limits
+    #[error("amount over the limit of {limit}")]
+    AmountLimitError { limit: Decimal },
+
+    /// Happens when a deposit/withdrawal would take the total of a window over the max_total of a limit
+    #[error("total over the limit of {limit} per {window}")]
+    TotalLimitError { limit: Decimal, window: Window },
+
+    /// Happens when a deposit/withdrawal would be one too many in a window, over the max_count of a limit
+    #[error("over the limit of {limit} transactions per {window}")]
+    CountLimitError { limit: u32, window: Window },
+
+    /// Happens in strict mode when a transaction is older than the last one of its client, see Bookkeeper::set_strict_time
+    #[error("timestamp before the last one of the client, {last}")]
+    OutOfOrderError { last: Timestamp },
 }
 
-#[derive(Serialize)]
+impl TxError {
+    /// code returns a stable, machine-readable reason for reports. Never change the existing ones.
+    pub fn code(&self) -> &'static str {
+        match self {
+            TxError::InvalidClientError => "invalid_client",
+            TxError::MissingAmountError => "missing_amount",
+            TxError::InvalidAmountError => "invalid_amount",
+            TxError::InvalidTxIdError => "invalid_tx_id",
+            TxError::InvaidFormatError => "invalid_format",
+            TxError::LockedAccountError => "locked_account",
+            TxError::FrozenAccountError => "frozen_account",
+            TxError::ClosedAccountError => "closed_account",
+            TxError::InvalidOperatioonError => "invalid_operation",
+            TxError::DuplicateTxIdError => "duplicate_tx_id",
+            TxError::ClientMismatchError { .. } => "client_mismatch",
+            TxError::CurrencyMismatchError => "currency_mismatch",
+            TxError::ExcessPrecisionError => "excess_precision",
+            TxError::AmountLimitError { .. } => "amount_limit",
+            TxError::TotalLimitError { .. } => "total_limit",
+            TxError::CountLimitError { .. } => "count_limit",
+            TxError::OutOfOrderError { .. } => "out_of_order",
+        }
+    }
+}
+
+/// AccountState is the lifecycle of an account:
+///
+/// active <-> frozen, by freeze/unlock
+/// active/frozen -> locked, by a chargeback
+/// locked -> active, by unlock after the chargeback investigation
+/// active/frozen/locked -> closed, by close when there is no fund left. It's final.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
+pub enum AccountState {
+    Active,
+    /// no deposit/withdrawal, but the disputes in flight can still be settled
+    Frozen,
+    /// nothing is allowed after a chargeback
+    Locked,
+    Closed,
+}
+
 pub struct Account {
-    #[serde(rename(serialize = "client"))]
     pub client_id: u16,
-    #[serde(rename(serialize = "available"))]
-    pub available_amount: Decimal,
-    #[serde(rename(serialize = "held"))]
-    pub held_amount: Decimal,
-    #[serde(rename(serialize = "total"))]
-    pub total_amount: Decimal,
-    pub locked: bool,
-
-    #[serde(skip_serializing)]
+    /// the balances in every currency the account has had funds in, see balances
+    pub buckets: BTreeMap<Currency, Balances>,
+    /// the fees charged in every currency, see fees
+    fees: BTreeMap<Currency, Decimal>,
+    pub state: AccountState,
+    /// the admin reason of the latest state change
+    pub state_reason: Option<String>,
+
     deposit_history: HashMap<u32, Deposit>,
 
-    #[serde(skip_serializing)]
-    withdrawal_history: HashMap<u32, Transaction>, // TODO: basically we should store deposit_history/withdrawal_history in database in Prod
+    withdrawal_history: HashMap<u32, Withdrawal>, // TODO: basically we should store deposit_history/withdrawal_history in database in Prod
+
+    /// every transaction applied, in order, see history
+    events: Vec<Event>,
+    /// the balances before the first event, not zero for an account loaded from a snapshot saved without its history
+    opening: BTreeMap<Currency, Balances>,
+    /// the number of events when the account was loaded from a snapshot, i.e., where the current period starts
+    period_start: usize,
 }
 
 impl Account {
     pub fn new(client_id: u16) -> Account {
         Account {
             client_id,
-            held_amount: Decimal::ZERO,
-            available_amount: Decimal::ZERO,
-            total_amount: Decimal::ZERO,
-            locked: false,
+            buckets: BTreeMap::new(),
+            fees: BTreeMap::new(),
+            state: AccountState::Active,
+            state_reason: None,
             deposit_history: HashMap::with_capacity(DEFAULT_COUNT),
             withdrawal_history: HashMap::with_capacity(DEFAULT_COUNT),
+            events: Vec::new(),
+            opening: BTreeMap::new(),
+            period_start: 0,
         }
     }
 
     pub fn on_tx(&mut self, tx: &Transaction) -> Result<(), TxError> {
-        match tx.r#type {
-            TxType::Deposit => self.on_deposit(tx)?,
-            TxType::Withdrawal => self.on_withdraw(tx)?,
-            TxType::Dispute => self.on_dispute(tx)?,
-            TxType::Resolve => self.on_resolve(tx)?,
-            TxType::ChargeBack => self.on_chargeback(tx)?,
-        }
+        let effect = self.prepare(tx)?;
+        self.apply(&effect);
 
         Ok(())
     }
 
-    fn on_deposit(&mut self, tx: &Transaction) -> Result<(), TxError> {
-        debug!("{:?}", tx);
+    /// prepare validates tx and computes its effect, without changing anything. See apply.
+    pub fn prepare(&self, tx: &Transaction) -> Result<Effect, TxError> {
+        self.prepare_with(tx, PrecisionRule::default(), &FeeSchedule::new())
+    }
+
+    /// prepare_with is prepare, with the amount of a deposit/withdrawal rounded by rule, and the fee of fees charged
+    pub fn prepare_with(&self, tx: &Transaction, rule: PrecisionRule, fees: &FeeSchedule) -> Result<Effect, TxError> {
+        let effect = match tx.r#type {
+            TxType::Deposit => self.prepare_deposit(tx, rule),
+            TxType::Withdrawal => self.prepare_withdraw(tx, rule),
+            TxType::Dispute => self.prepare_dispute(tx),
+            TxType::Resolve => self.prepare_resolve(tx),
+            TxType::ChargeBack => self.prepare_chargeback(tx),
+            TxType::Unlock => self.prepare_state_change(tx, &[AccountState::Frozen, AccountState::Locked], AccountState::Active),
+            TxType::Freeze => self.prepare_state_change(tx, &[AccountState::Active], AccountState::Frozen),
+            TxType::Close => self.prepare_close(tx),
+        }?;
+
+        self.charge_fee(effect, rule, fees)
+    }
+
+    /// apply applies an effect prepared on the same state, e.g., just now, or when replaying a journal
+    pub fn apply(&mut self, effect: &Effect) {
+        debug_assert!(effect.client == self.client_id);
+
+        // the balances are derived from the postings, so that they always agree with the general ledger.
+        // A currency gets a bucket with its first posting.
+        let mut postings = effect.postings().peekable();
+        if postings.peek().is_some() {
+            let balances = self.buckets.entry(effect.currency).or_default();
+            for posting in postings {
+                match posting.account {
+                    LedgerAccount::CustomerAvailable => balances.available += posting.amount,
+                    LedgerAccount::CustomerHeld => balances.held += posting.amount,
+                    LedgerAccount::FeeRevenue => *self.fees.entry(effect.currency).or_default() += posting.amount,
+                    LedgerAccount::Clearing | LedgerAccount::ChargebackLoss => {}
+                }
+            }
+            balances.total = balances.available + balances.held;
+        }
 
-        self.validate_account()?;
+        for change in &effect.changes {
+            match change {
+                Change::Deposit { amount } => {
+                    self.deposit_history.insert(
+                        effect.tx,
+                        Deposit {
+                            amount: *amount,
+                            currency: effect.currency,
+                            status: DepositStatus::None,
+                        },
+                    );
+                }
+                Change::Withdrawal { amount } => {
+                    self.withdrawal_history.insert(
+                        effect.tx,
+                        Withdrawal {
+                            amount: *amount,
+                            currency: effect.currency,
+                            status: WithdrawalStatus::None,
+                        },
+                    );
+                }
+                Change::DepositStatus { status } => {
+                    if let Some(deposit) = self.deposit_history.get_mut(&effect.tx) {
+                        deposit.status = *status;
+                    }
+                }
+                Change::WithdrawalStatus { status } => {
+                    if let Some(withdrawal) = self.withdrawal_history.get_mut(&effect.tx) {
+                        withdrawal.status = *status;
+                    }
+                }
+                Change::State { state, reason } => {
+                    info!("client {}: {:?} -> {:?}, reason: {:?}", self.client_id, self.state, state, reason);
+                    self.state = *state;
+                    self.state_reason = reason.clone();
+                }
+            }
+        }
 
-        let amount = Self::adjust_scale(&self.validate_deposit(tx)?);
+        let r#type = effect.tx_type();
+        let balances = self.balances(effect.currency);
+        self.events.push(Event {
+            tx: effect.tx,
+            amount: if r#type.is_admin() { None } else { self.tx_amount(effect.tx) },
+            r#type,
+            currency: effect.currency,
+            available: balances.available,
+            held: balances.held,
+            total: balances.total,
+            fee: effect.fee,
+            time: effect.time,
+            accepted: effect.accepted,
+        });
+    }
 
-        if let Some(new_available) = self.available_amount.checked_add(amount) {
-            if let Some(new_total) = self.total_amount.checked_add(amount) {
-                self.available_amount = new_available;
-                self.total_amount = new_total;
+    /// locked checks if the account is locked by a chargeback, e.g., for the report. A frozen or closed account is not.
+    pub fn locked(&self) -> bool {
+        self.state == AccountState::Locked
+    }
 
-                self.deposit_history.insert(
-                    tx.tx_id,
-                    Deposit {
-                        amount,
-                        status: DepositStatus::None,
-                    },
-                );
+    /// disputed_amount returns the sum of the deposits and the withdrawals in currency in dispute, which should be held
+    pub fn disputed_amount(&self, currency: Currency) -> Decimal {
+        let deposits = self.deposit_history.values().filter(|d| d.currency == currency && d.status == DepositStatus::Disputed).map(|d| d.amount);
+        let withdrawals = self.withdrawal_history.values().filter(|w| w.currency == currency && w.status == WithdrawalStatus::Disputed).map(|w| w.amount);
+        deposits.chain(withdrawals).sum()
+    }
 
-                return Ok(());
-            }
+    /// fees returns the fees charged in currency
+    pub fn fees(&self, currency: Currency) -> Decimal {
+        self.fees.get(&currency).copied().unwrap_or_default()
+    }
+
+    /// has_tx checks if tx_id is a deposit or withdrawal applied on this account
+    pub fn has_tx(&self, tx_id: u32) -> bool {
+        self.deposit_history.contains_key(&tx_id) || self.withdrawal_history.contains_key(&tx_id)
+    }
+
+    /// charge_fee adds the fee of the transaction of effect, on the amount it's about, which is taken from available after it.
+    /// A chargeback is forced on the account, so its fee is capped by the funds left, otherwise the transaction is rejected
+    /// if the fee doesn't fit.
+    fn charge_fee(&self, effect: Effect, rule: PrecisionRule, fees: &FeeSchedule) -> Result<Effect, TxError> {
+        let r#type = effect.tx_type();
+        if r#type.is_admin() {
+            return Ok(effect);
+        }
+
+        let amount = match effect.changes.first() {
+            Some(Change::Deposit { amount } | Change::Withdrawal { amount }) => *amount,
+            _ => self.tx_amount(effect.tx).unwrap_or_default(),
+        };
+        let fee = fees.fee(&r#type, effect.currency, amount, rule).ok_or(TxError::InvalidAmountError)?;
+        if fee.is_zero() {
+            return Ok(effect);
+        }
+
+        let available = self.balances(effect.currency).available + effect.available;
+        let fee = match r#type {
+            TxType::ChargeBack => fee.min(available),
+            _ if fee > available => return Err(TxError::InvalidAmountError),
+            _ => fee,
+        };
+        let total = self.balances(effect.currency).total + effect.total;
+        if exact_add(available, -fee).is_none() || exact_add(total, -fee).is_none() {
+            return Err(TxError::InvalidAmountError);
         }
+        Ok(Effect { fee, ..effect })
+    }
+
+    fn prepare_deposit(&self, tx: &Transaction, rule: PrecisionRule) -> Result<Effect, TxError> {
+        debug!("{:?}", tx);
+
+        self.validate_account(tx)?;
 
-        Err(TxError::InvalidAmountError)
+        let (amount, residue) = self.validate_deposit(tx, rule)?;
+
+        let effect = self.effect(tx, tx.currency.unwrap_or_default(), amount, Decimal::ZERO, amount, vec![Change::Deposit { amount }])?;
+        Ok(Effect { residue, ..effect })
     }
 
-    fn on_withdraw(&mut self, tx: &Transaction) -> Result<(), TxError> {
+    fn prepare_withdraw(&self, tx: &Transaction, rule: PrecisionRule) -> Result<Effect, TxError> {
         debug!("{:?}", tx);
 
-        self.validate_account()?;
+        self.validate_account(tx)?;
 
-        let amount = Self::adjust_scale(&self.validate_withdraw(tx)?);
+        let (amount, residue) = self.validate_withdraw(tx, rule)?;
 
-        if let Some(new_available) = self.available_amount.checked_sub(amount) {
-            if new_available >= Decimal::ZERO {
-                if let Some(new_total) = self.total_amount.checked_sub(amount) {
-                    if new_total >= Decimal::ZERO {
-                        self.available_amount = new_available;
-                        self.total_amount = new_total;
+        let effect = self.effect(tx, tx.currency.unwrap_or_default(), -amount, Decimal::ZERO, -amount, vec![Change::Withdrawal { amount }])?;
+        Ok(Effect { residue: -residue, ..effect })
+    }
 
-                        self.withdrawal_history.insert(tx.tx_id, tx.clone());
-                        return Ok(());
-                    }
-                }
-            }
+    fn prepare_dispute(&self, tx: &Transaction) -> Result<Effect, TxError> {
+        debug!("{:?}", tx);
+
+        self.validate_account(tx)?;
+        let currency = self.validate_currency(tx)?;
+
+        if self.withdrawal_history.contains_key(&tx.tx_id) {
+            return self.prepare_withdrawal_dispute(tx, currency);
         }
 
-        Err(TxError::InvalidAmountError)
+        let amount = Self::validate_dispute(&self.deposit_history, tx)?.amount;
+
+        let status = DepositStatus::Disputed;
+        self.effect(tx, currency, -amount, amount, Decimal::ZERO, vec![Change::DepositStatus { status }])
     }
 
-    fn on_dispute(&mut self, tx: &Transaction) -> Result<(), TxError> {
+    /// A disputed withdrawal is reversed into held, i.e., the funds are back in total but can't be used until it's settled
+    fn prepare_withdrawal_dispute(&self, tx: &Transaction, currency: Currency) -> Result<Effect, TxError> {
+        let amount = Self::validate_withdrawal_dispute(&self.withdrawal_history, tx)?.amount;
+
+        let status = WithdrawalStatus::Disputed;
+        self.effect(tx, currency, Decimal::ZERO, amount, amount, vec![Change::WithdrawalStatus { status }])
+    }
+
+    fn prepare_resolve(&self, tx: &Transaction) -> Result<Effect, TxError> {
         debug!("{:?}", tx);
 
-        self.validate_account()?;
+        self.validate_account(tx)?;
+        let currency = self.validate_currency(tx)?;
 
-        let deposit = Self::validate_dispute(&mut self.deposit_history, tx)?;
-        let amount = deposit.amount;
+        if self.withdrawal_history.contains_key(&tx.tx_id) {
+            return self.prepare_withdrawal_resolve(tx, currency);
+        }
 
-        if let Some(new_held) = self.held_amount.checked_add(amount) {
-            if let Some(new_available) = self.available_amount.checked_sub(amount) {
-                if new_available >= Decimal::ZERO {
-                    deposit.status = DepositStatus::Disputed;
-                    self.held_amount = new_held;
-                    self.available_amount = new_available;
-                    return Ok(());
-                }
-            }
+        let amount = Self::validate_resolve(&self.deposit_history, tx)?.amount;
+
+        let status = DepositStatus::None;
+        self.effect(tx, currency, amount, -amount, Decimal::ZERO, vec![Change::DepositStatus { status }])
+    }
+
+    /// A resolved withdrawal stands, so the held funds leave the account again
+    fn prepare_withdrawal_resolve(&self, tx: &Transaction, currency: Currency) -> Result<Effect, TxError> {
+        let amount = Self::validate_withdrawal_resolve(&self.withdrawal_history, tx)?.amount;
+
+        let status = WithdrawalStatus::None;
+        self.effect(tx, currency, Decimal::ZERO, -amount, -amount, vec![Change::WithdrawalStatus { status }])
+    }
+
+    fn prepare_chargeback(&self, tx: &Transaction) -> Result<Effect, TxError> {
+        debug!("{:?}", tx);
+        self.validate_account(tx)?;
+        let currency = self.validate_currency(tx)?;
+
+        if self.withdrawal_history.contains_key(&tx.tx_id) {
+            return self.prepare_withdrawal_chargeback(tx, currency);
         }
 
-        Err(TxError::InvalidAmountError)
+        let amount = Self::validate_chargeback(&self.deposit_history, tx)?.amount;
+
+        let status = DepositStatus::ChargedBack;
+        // locked until it's unlocked after the investigation
+        let state = Change::State {
+            state: AccountState::Locked,
+            reason: None,
+        };
+        self.effect(tx, currency, Decimal::ZERO, -amount, -amount, vec![Change::DepositStatus { status }, state])
+    }
+
+    /// A charged back withdrawal is reversed for good, so the held funds are available to the client again
+    fn prepare_withdrawal_chargeback(&self, tx: &Transaction, currency: Currency) -> Result<Effect, TxError> {
+        let amount = Self::validate_withdrawal_chargeback(&self.withdrawal_history, tx)?.amount;
+
+        let status = WithdrawalStatus::ChargedBack;
+        let state = Change::State {
+            state: AccountState::Locked,
+            reason: None,
+        };
+        self.effect(tx, currency, amount, -amount, Decimal::ZERO, vec![Change::WithdrawalStatus { status }, state])
     }
 
-    fn on_resolve(&mut self, tx: &Transaction) -> Result<(), TxError> {
+    fn prepare_state_change(&self, tx: &Transaction, from: &[AccountState], to: AccountState) -> Result<Effect, TxError> {
         debug!("{:?}", tx);
 
-        self.validate_account()?;
+        if self.state == AccountState::Closed {
+            return Err(TxError::ClosedAccountError);
+        }
+
+        if !from.contains(&self.state) {
+            return Err(TxError::InvalidOperatioonError);
+        }
 
-        let deposit = Self::validate_resolve(&mut self.deposit_history, tx)?;
-        let amount = deposit.amount;
+        let state = Change::State {
+            state: to,
+            reason: tx.reason.clone(),
+        };
+        self.effect(tx, tx.currency.unwrap_or_default(), Decimal::ZERO, Decimal::ZERO, Decimal::ZERO, vec![state])
+    }
 
-        if let Some(new_held) = self.held_amount.checked_sub(amount) {
-            if let Some(new_available) = self.available_amount.checked_add(amount) {
-                if new_held >= Decimal::ZERO {
-                    self.held_amount = new_held;
-                    self.available_amount = new_available;
-                    deposit.status = DepositStatus::None;
-                    return Ok(());
-                }
-            }
+    /// An account can only be closed when there is no fund left in any currency, including the held
+    fn prepare_close(&self, tx: &Transaction) -> Result<Effect, TxError> {
+        if self.buckets.values().any(|b| b.total != Decimal::ZERO || b.held != Decimal::ZERO) {
+            return Err(TxError::InvalidOperatioonError);
         }
 
-        Err(TxError::InvalidAmountError)
+        self.prepare_state_change(tx, &[AccountState::Active, AccountState::Frozen, AccountState::Locked], AccountState::Closed)
     }
 
-    fn on_chargeback(&mut self, tx: &Transaction) -> Result<(), TxError> {
-        debug!("{:?}", tx);
-        self.validate_account()?;
-
-        let deposit = Self::validate_chargeback(&mut self.deposit_history, tx)?;
-        let amount = deposit.amount;
-
-        if let Some(new_held) = self.held_amount.checked_sub(amount) {
-            if let Some(new_total) = self.total_amount.checked_sub(amount) {
-                if new_held >= Decimal::ZERO && new_total >= Decimal::ZERO {
-                    self.held_amount = new_held;
-                    self.total_amount = new_total;
-                    deposit.status = DepositStatus::ChargedBack;
-                    self.locked = true; // TODO, how to unlock?
-                    return Ok(());
-                }
+    /// effect builds the effect of tx from the changes of the balances in currency, making sure that no balance overflows,
+    /// is rounded or goes negative
+    fn effect(&self, tx: &Transaction, currency: Currency, available: Decimal, held: Decimal, total: Decimal, changes: Vec<Change>) -> Result<Effect, TxError> {
+        let balances = self.balances(currency);
+        for (balance, delta) in [(balances.available, available), (balances.held, held), (balances.total, total)] {
+            match exact_add(balance, delta) {
+                Some(new_balance) if new_balance >= Decimal::ZERO => {}
+                _ => return Err(TxError::InvalidAmountError),
             }
         }
 
-        Err(TxError::InvalidAmountError)
+        Ok(Effect {
+            client: self.client_id,
+            tx: tx.tx_id,
+            currency,
+            available,
+            held,
+            total,
+            residue: Decimal::ZERO,
+            fee: Decimal::ZERO,
+            time: None,
+            accepted: None,
+            changes,
+        })
     }
 
-    /// For simplicity, we dont check if it's duplciate or not. In prod, this could be done through a database.
-    fn validate_deposit(&self, tx: &Transaction) -> Result<Decimal, TxError> {
+    /// Duplicates are only checked within this account here, Bookkeeper checks them across all the clients.
+    /// It returns the amount rounded by rule, and the residue.
+    fn validate_deposit(&self, tx: &Transaction, rule: PrecisionRule) -> Result<(Decimal, Decimal), TxError> {
         debug_assert!(tx.r#type == TxType::Deposit);
 
-        let amount = Self::validate_amount(tx)?;
+        let amount = rule.round(Self::validate_amount(tx)?)?;
 
-        if self.deposit_history.contains_key(&tx.tx_id) {
+        if self.has_tx(tx.tx_id) {
             return Err(TxError::InvalidTxIdError);
         }
 
@@ -219,31 +492,47 @@ impl Account {
         Ok(amount)
     }
 
-    /// For simplicity, we dont check if it's duplciate or not. In prod, this could be done through a database.
-    fn validate_withdraw(&self, tx: &Transaction) -> Result<Decimal, TxError> {
+    /// Duplicates are only checked within this account here, Bookkeeper checks them across all the clients.
+    /// The rounded amount is checked against the available funds, as it's what is withdrawn.
+    fn validate_withdraw(&self, tx: &Transaction, rule: PrecisionRule) -> Result<(Decimal, Decimal), TxError> {
         debug_assert!(tx.r#type == TxType::Withdrawal);
 
-        let amount = Self::validate_amount(tx)?;
+        let (amount, residue) = rule.round(Self::validate_amount(tx)?)?;
 
-        if amount > self.available_amount {
+        if amount > self.balances(tx.currency.unwrap_or_default()).available {
             return Err(TxError::InvalidAmountError);
         }
 
         // available_amount is alwayas <= total_amount, so we don't need to check total
 
-        if self.withdrawal_history.contains_key(&tx.tx_id) {
+        if self.has_tx(tx.tx_id) {
             return Err(TxError::InvalidTxIdError);
         }
 
-        Ok(amount)
+        Ok((amount, residue))
     }
 
-    fn validate_account(&self) -> Result<(), TxError> {
-        if self.locked {
-            return Err(TxError::LockedAccountError);
+    /// validate_currency returns the currency of the deposit/withdrawal tx is about, which tx must give, if any
+    fn validate_currency(&self, tx: &Transaction) -> Result<Currency, TxError> {
+        let currency = match self.deposit_history.get(&tx.tx_id) {
+            Some(deposit) => deposit.currency,
+            None => self.withdrawal_history.get(&tx.tx_id).ok_or(TxError::InvalidTxIdError)?.currency,
+        };
+
+        match tx.currency {
+            Some(c) if c != currency => Err(TxError::CurrencyMismatchError),
+            _ => Ok(currency),
         }
+    }
 
-        Ok(())
+    fn validate_account(&self, tx: &Transaction) -> Result<(), TxError> {
+        match self.state {
+            AccountState::Active => Ok(()),
+            AccountState::Frozen if tx.r#type == TxType::Deposit || tx.r#type == TxType::Withdrawal => Err(TxError::FrozenAccountError),
+            AccountState::Frozen => Ok(()),
+            AccountState::Locked => Err(TxError::LockedAccountError),
+            AccountState::Closed => Err(TxError::ClosedAccountError),
+        }
     }
 
     fn validate_amount(tx: &Transaction) -> Result<Decimal, TxError> {
@@ -260,18 +549,11 @@ impl Account {
         Err(TxError::MissingAmountError)
     }
 
-    fn adjust_scale(amt: &Decimal) -> Decimal {
-        // for simplity, we adjust for all, without checking if its decimal palces are great than 4 or not
-        let mut ret = *amt;
-        ret.rescale(MAX_DECIMAL_PLACES);
-        ret
-    }
-
     /// For simplicity, we dont check if it's duplciate or not. In prod, this could be done through a database.
-    fn validate_dispute<'a>(history: &'a mut HashMap<u32, Deposit>, tx: &Transaction) -> Result<&'a mut Deposit, TxError> {
+    fn validate_dispute<'a>(history: &'a HashMap<u32, Deposit>, tx: &Transaction) -> Result<&'a Deposit, TxError> {
         debug_assert!(tx.r#type == TxType::Dispute);
 
-        if let Some(deposit) = history.get_mut(&tx.tx_id) {
+        if let Some(deposit) = history.get(&tx.tx_id) {
             if deposit.status != DepositStatus::None {
                 return Err(TxError::InvalidOperatioonError);
             }
@@ -283,10 +565,10 @@ impl Account {
     }
 
     /// For simplicity, we dont check if it's duplciate or not. In prod, this could be done through a database.
-    fn validate_resolve<'a>(history: &'a mut HashMap<u32, Deposit>, tx: &Transaction) -> Result<&'a mut Deposit, TxError> {
+    fn validate_resolve<'a>(history: &'a HashMap<u32, Deposit>, tx: &Transaction) -> Result<&'a Deposit, TxError> {
         debug_assert!(tx.r#type == TxType::Resolve);
 
-        if let Some(deposit) = history.get_mut(&tx.tx_id) {
+        if let Some(deposit) = history.get(&tx.tx_id) {
             if deposit.status != DepositStatus::Disputed {
                 return Err(TxError::InvalidOperatioonError);
             }
@@ -298,10 +580,10 @@ impl Account {
     }
 
     /// For simplicity, we dont check if it's duplciate or not. In prod, this could be done through a database.
-    fn validate_chargeback<'a>(history: &'a mut HashMap<u32, Deposit>, tx: &Transaction) -> Result<&'a mut Deposit, TxError> {
+    fn validate_chargeback<'a>(history: &'a HashMap<u32, Deposit>, tx: &Transaction) -> Result<&'a Deposit, TxError> {
         debug_assert!(tx.r#type == TxType::ChargeBack);
 
-        if let Some(deposit) = history.get_mut(&tx.tx_id) {
+        if let Some(deposit) = history.get(&tx.tx_id) {
             if deposit.status != DepositStatus::Disputed {
                 return Err(TxError::InvalidOperatioonError);
             }
@@ -311,26 +593,332 @@ impl Account {
 
         Err(TxError::InvalidTxIdError)
     }
+
+    fn validate_withdrawal_dispute<'a>(history: &'a HashMap<u32, Withdrawal>, tx: &Transaction) -> Result<&'a Withdrawal, TxError> {
+        debug_assert!(tx.r#type == TxType::Dispute);
+
+        if let Some(withdrawal) = history.get(&tx.tx_id) {
+            if withdrawal.status != WithdrawalStatus::None {
+                return Err(TxError::InvalidOperatioonError);
+            }
+
+            return Ok(withdrawal);
+        }
+
+        Err(TxError::InvalidTxIdError)
+    }
+
+    fn validate_withdrawal_resolve<'a>(history: &'a HashMap<u32, Withdrawal>, tx: &Transaction) -> Result<&'a Withdrawal, TxError> {
+        debug_assert!(tx.r#type == TxType::Resolve);
+
+        if let Some(withdrawal) = history.get(&tx.tx_id) {
+            if withdrawal.status != WithdrawalStatus::Disputed {
+                return Err(TxError::InvalidOperatioonError);
+            }
+
+            return Ok(withdrawal);
+        }
+
+        Err(TxError::InvalidTxIdError)
+    }
+
+    fn validate_withdrawal_chargeback<'a>(history: &'a HashMap<u32, Withdrawal>, tx: &Transaction) -> Result<&'a Withdrawal, TxError> {
+        debug_assert!(tx.r#type == TxType::ChargeBack);
+
+        if let Some(withdrawal) = history.get(&tx.tx_id) {
+            if withdrawal.status != WithdrawalStatus::Disputed {
+                return Err(TxError::InvalidOperatioonError);
+            }
+
+            return Ok(withdrawal);
+        }
+
+        Err(TxError::InvalidTxIdError)
+    }
+}
+
+/// AccountSnapshot is the full state of an account, including its history, see Bookkeeper::save_snapshot
+#[derive(Serialize, Deserialize)]
+pub struct AccountSnapshot<'a> {
+    client: u16,
+    buckets: Cow<'a, BTreeMap<Currency, Balances>>,
+    fees: Cow<'a, BTreeMap<Currency, Decimal>>,
+    state: AccountState,
+    state_reason: Option<Cow<'a, str>>,
+    deposits: Cow<'a, HashMap<u32, Deposit>>,
+    withdrawals: Cow<'a, HashMap<u32, Withdrawal>>,
+    events: Cow<'a, [Event]>,
+    /// the balances the history starts from
+    openings: Cow<'a, BTreeMap<Currency, Balances>>,
 }
 
-#[derive(PartialEq)]
-enum DepositStatus {
+impl Account {
+    pub fn snapshot(&self) -> AccountSnapshot<'_> {
+        AccountSnapshot {
+            client: self.client_id,
+            buckets: Cow::Borrowed(&self.buckets),
+            fees: Cow::Borrowed(&self.fees),
+            state: self.state,
+            state_reason: self.state_reason.as_deref().map(Cow::Borrowed),
+            deposits: Cow::Borrowed(&self.deposit_history),
+            withdrawals: Cow::Borrowed(&self.withdrawal_history),
+            events: Cow::Borrowed(&self.events),
+            openings: Cow::Borrowed(&self.opening),
+        }
+    }
+
+    /// tx_ids returns the ids of all the deposits/withdrawals applied on this account
+    pub fn tx_ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.deposit_history.keys().chain(self.withdrawal_history.keys()).copied()
+    }
+
+    /// events returns the transactions applied to the account, in order, with the balances right after each of them
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// history returns the transactions applied to the account, in order, with their running balances
+    /// and the current dispute status of the deposit/withdrawal they are about
+    pub fn history(&self) -> Vec<HistoryEntry> {
+        self.history_since(0)
+    }
+
+    /// history_since returns the history from the event at index from on
+    pub fn history_since(&self, from: usize) -> Vec<HistoryEntry> {
+        self.events[from.min(self.events.len())..]
+            .iter()
+            .map(|e| HistoryEntry {
+                tx: e.tx,
+                r#type: e.r#type.clone(),
+                currency: e.currency,
+                amount: e.amount,
+                status: if e.r#type.is_admin() { None } else { self.tx_status(e.tx) },
+                available: e.available,
+                held: e.held,
+                total: e.total,
+            })
+            .collect()
+    }
+
+    /// balances returns the balances in currency, zero if the account never had any
+    pub fn balances(&self, currency: Currency) -> Balances {
+        self.buckets.get(&currency).copied().unwrap_or_default()
+    }
+
+    /// currencies returns the currencies of the buckets, now or at the opening, or the default one if there is none
+    pub fn currencies(&self) -> BTreeSet<Currency> {
+        let mut currencies: BTreeSet<_> = self.buckets.keys().chain(self.opening.keys()).copied().collect();
+        if currencies.is_empty() {
+            currencies.insert(Currency::default());
+        }
+        currencies
+    }
+
+    /// balances_after returns the balances in currency right after the first n events, i.e., before the event at index n
+    pub fn balances_after(&self, n: usize, currency: Currency) -> Balances {
+        match self.events[..n.min(self.events.len())].iter().rev().find(|e| e.currency == currency) {
+            Some(e) => Balances {
+                available: e.available,
+                held: e.held,
+                total: e.total,
+            },
+            None => self.opening.get(&currency).copied().unwrap_or_default(),
+        }
+    }
+
+    /// last_time returns the time of the latest event which has one, see Effect::time
+    pub fn last_time(&self) -> Option<u64> {
+        self.events.iter().rev().find_map(|e| e.time)
+    }
+
+    /// at returns the account as it was at time, i.e., with the events up to it, whatever their order: its balances,
+    /// fees and state, but none of its history. It's None if the account had no event yet.
+    /// The events of the transactions without a timestamp have no time, so they are counted at any time.
+    pub fn at(&self, time: u64) -> Option<Account> {
+        let counted = |e: &Event| e.time.is_none_or(|t| t <= time);
+        if !self.events.iter().any(counted) && !self.events.is_empty() && self.opening.is_empty() {
+            return None;
+        }
+
+        // every event changes the balances of its currency from the ones of the previous event in it.
+        // There is no history, so none of the room Account::new makes for it.
+        let mut acct = Account {
+            client_id: self.client_id,
+            buckets: self.opening.clone(),
+            fees: BTreeMap::new(),
+            state: AccountState::Active,
+            state_reason: None,
+            deposit_history: HashMap::new(),
+            withdrawal_history: HashMap::new(),
+            events: Vec::new(),
+            opening: BTreeMap::new(),
+            period_start: 0,
+        };
+        let mut previous = self.opening.clone();
+        for e in &self.events {
+            let after = Balances {
+                available: e.available,
+                held: e.held,
+                total: e.total,
+            };
+            let before = previous.insert(e.currency, after).unwrap_or_default();
+            if counted(e) {
+                let balances = acct.buckets.entry(e.currency).or_default();
+                balances.available += after.available - before.available;
+                balances.held += after.held - before.held;
+                balances.total += after.total - before.total;
+                if !e.fee.is_zero() {
+                    *acct.fees.entry(e.currency).or_default() += e.fee;
+                }
+            }
+        }
+
+        // the state is the one set by the latest change, and if there is none, the one of an account which never changed
+        let changed = |e: &Event| match e.r#type {
+            TxType::ChargeBack => Some(AccountState::Locked),
+            TxType::Unlock => Some(AccountState::Active),
+            TxType::Freeze => Some(AccountState::Frozen),
+            TxType::Close => Some(AccountState::Closed),
+            _ => None,
+        };
+        acct.state = match self.events.iter().rev().filter(|e| counted(e)).find_map(changed) {
+            Some(state) => state,
+            None if self.events.iter().any(|e| changed(e).is_some()) => AccountState::Active,
+            None => self.state,
+        };
+        Some(acct)
+    }
+
+    /// period_start returns the index of the first event of the current period, i.e., since the account was loaded from a snapshot
+    pub fn period_start(&self) -> usize {
+        self.period_start
+    }
+
+    /// tx_amount returns the amount of the deposit/withdrawal tx_id
+    fn tx_amount(&self, tx_id: u32) -> Option<Decimal> {
+        match self.deposit_history.get(&tx_id) {
+            Some(deposit) => Some(deposit.amount),
+            None => self.withdrawal_history.get(&tx_id).map(|w| w.amount),
+        }
+    }
+
+    /// tx_status returns the current dispute status of the deposit/withdrawal tx_id
+    fn tx_status(&self, tx_id: u32) -> Option<TxStatus> {
+        match self.deposit_history.get(&tx_id) {
+            Some(deposit) => Some(TxStatus::Deposit(deposit.status)),
+            None => self.withdrawal_history.get(&tx_id).map(|w| TxStatus::Withdrawal(w.status)),
+        }
+    }
+}
+
+impl From<AccountSnapshot<'_>> for Account {
+    fn from(s: AccountSnapshot<'_>) -> Self {
+        let events = s.events.into_owned();
+        Account {
+            client_id: s.client,
+            buckets: s.buckets.into_owned(),
+            fees: s.fees.into_owned(),
+            state: s.state,
+            state_reason: s.state_reason.map(Cow::into_owned),
+            deposit_history: s.deposits.into_owned(),
+            withdrawal_history: s.withdrawals.into_owned(),
+            period_start: events.len(),
+            events,
+            opening: s.openings.into_owned(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum DepositStatus {
     None,
     Disputed,
     ChargedBack,
 }
 
+/// Balances are the balances of an account at some point of its history
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub struct Balances {
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+}
+
+/// Event is a transaction applied to an account, with the balances in its currency right after it
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Event {
+    pub tx: u32,
+    #[serde(rename = "type")]
+    pub r#type: TxType,
+    /// the currency of amount and of the balances
+    pub currency: Currency,
+    /// the amount of the deposit/withdrawal, or of the one disputed, resolved or charged back. None for an admin transaction.
+    pub amount: Option<Decimal>,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    /// the fee charged on it, see Effect::fee
+    #[serde(default, skip_serializing_if = "Decimal::is_zero")]
+    pub fee: Decimal,
+    /// when it happened, see Effect::time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<u64>,
+    /// when it was accepted, if it has no time, see Effect::accepted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accepted: Option<u64>,
+}
+
+/// TxStatus is the dispute status of a deposit or a withdrawal
+#[derive(Debug, Serialize, Clone, Copy, PartialEq)]
+#[serde(untagged)]
+pub enum TxStatus {
+    Deposit(DepositStatus),
+    Withdrawal(WithdrawalStatus),
+}
+
+/// HistoryEntry is an event of Account::history, with the current dispute status of the deposit/withdrawal it's about
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct HistoryEntry {
+    pub tx: u32,
+    #[serde(rename = "type")]
+    pub r#type: TxType,
+    pub currency: Currency,
+    pub amount: Option<Decimal>,
+    pub status: Option<TxStatus>,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 struct Deposit {
     amount: Decimal,
+    currency: Currency,
     status: DepositStatus,
 }
 
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum WithdrawalStatus {
+    None,
+    Disputed,
+    ChargedBack,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Withdrawal {
+    amount: Decimal,
+    currency: Currency,
+    status: WithdrawalStatus,
+}
+
 #[cfg(test)]
 mod test {
     use rust_decimal::Decimal;
     use std::str::FromStr;
 
-    use crate::model::{Account, Transaction, TxError, TxType};
+    use crate::model::{Account, AccountState, Currency, DepositStatus, Transaction, TxError, TxStatus, TxType, WithdrawalStatus};
 
     /// Check a flow: deposit(ok) -> withdraw(ok) -> withdraw (failed)
     #[test]
@@ -342,14 +930,17 @@ mod test {
             client_id,
             tx_id: 1,
             amount: Some(amount),
+            currency: None,
+            reason: None,
+            timestamp: None,
         };
 
         let mut acct = Account::new(client_id);
 
         assert!(acct.on_tx(&deposit).is_ok());
-        assert!(acct.held_amount == Decimal::ZERO);
-        assert!(acct.available_amount == amount);
-        assert!(acct.total_amount == amount);
+        assert!(acct.balances(Currency::USD).held == Decimal::ZERO);
+        assert!(acct.balances(Currency::USD).available == amount);
+        assert!(acct.balances(Currency::USD).total == amount);
 
         let amount2 = Decimal::new(9, 1);
         deposit.tx_id = 2;
@@ -357,9 +948,9 @@ mod test {
         assert!(acct.on_tx(&deposit).is_ok());
 
         let total_amount = amount + amount2;
-        assert!(acct.held_amount == Decimal::ZERO);
-        assert!(acct.available_amount == total_amount);
-        assert!(acct.total_amount == total_amount);
+        assert!(acct.balances(Currency::USD).held == Decimal::ZERO);
+        assert!(acct.balances(Currency::USD).available == total_amount);
+        assert!(acct.balances(Currency::USD).total == total_amount);
 
         let withdrawal_amount = Decimal::new(15, 1);
         let balance = total_amount - withdrawal_amount;
@@ -368,20 +959,23 @@ mod test {
             client_id,
             tx_id: 3,
             amount: Some(withdrawal_amount),
+            currency: None,
+            reason: None,
+            timestamp: None,
         };
 
         assert!(acct.on_tx(&withdrawal).is_ok());
-        assert!(acct.held_amount == Decimal::ZERO);
-        assert!(acct.available_amount == balance);
-        assert!(acct.total_amount == balance);
+        assert!(acct.balances(Currency::USD).held == Decimal::ZERO);
+        assert!(acct.balances(Currency::USD).available == balance);
+        assert!(acct.balances(Currency::USD).total == balance);
 
         withdrawal.tx_id = 3;
         assert!(acct.on_tx(&withdrawal).err().unwrap() == TxError::InvalidAmountError);
 
         // amounts are not changed after an insufficient withdrawal
-        assert!(acct.held_amount == Decimal::ZERO);
-        assert!(acct.available_amount == balance);
-        assert!(acct.total_amount == balance);
+        assert!(acct.balances(Currency::USD).held == Decimal::ZERO);
+        assert!(acct.balances(Currency::USD).available == balance);
+        assert!(acct.balances(Currency::USD).total == balance);
     }
 
     /// Check a normal flow: deposit(ok) -> dispute(ok) -> resolve (ok)
@@ -395,6 +989,9 @@ mod test {
             client_id,
             tx_id: 1,
             amount: Some(amount),
+            currency: None,
+            reason: None,
+            timestamp: None,
         };
 
         let mut acct = Account::new(client_id);
@@ -406,24 +1003,30 @@ mod test {
             client_id,
             tx_id: 1,
             amount: None,
+            currency: None,
+            reason: None,
+            timestamp: None,
         };
 
         assert!(acct.on_tx(&dispute).is_ok());
-        assert!(acct.held_amount == amount);
-        assert!(acct.available_amount == amount - amount);
-        assert!(acct.total_amount == amount);
+        assert!(acct.balances(Currency::USD).held == amount);
+        assert!(acct.balances(Currency::USD).available == amount - amount);
+        assert!(acct.balances(Currency::USD).total == amount);
 
         let resolve = Transaction {
             r#type: TxType::Resolve,
             client_id,
             tx_id: 1,
             amount: None,
+            currency: None,
+            reason: None,
+            timestamp: None,
         };
 
         assert!(acct.on_tx(&resolve).is_ok());
-        assert!(acct.held_amount == Decimal::ZERO);
-        assert!(acct.available_amount == amount);
-        assert!(acct.total_amount == amount);
+        assert!(acct.balances(Currency::USD).held == Decimal::ZERO);
+        assert!(acct.balances(Currency::USD).available == amount);
+        assert!(acct.balances(Currency::USD).total == amount);
     }
 
     /// Check a normal flow: deposit(ok) -> dispute(ok) -> chargeback (ok)
@@ -437,6 +1040,9 @@ mod test {
             client_id,
             tx_id: 1,
             amount: Some(amount),
+            currency: None,
+            reason: None,
+            timestamp: None,
         };
 
         let mut acct = Account::new(client_id);
@@ -448,6 +1054,9 @@ mod test {
             client_id,
             tx_id: 1,
             amount: None,
+            currency: None,
+            reason: None,
+            timestamp: None,
         };
 
         assert!(acct.on_tx(&dispute).is_ok());
@@ -457,12 +1066,15 @@ mod test {
             client_id,
             tx_id: 1,
             amount: None,
+            currency: None,
+            reason: None,
+            timestamp: None,
         };
 
         assert!(acct.on_tx(&chargeback).is_ok());
-        assert!(acct.held_amount == Decimal::ZERO);
-        assert!(acct.available_amount == Decimal::ZERO);
-        assert!(acct.total_amount == Decimal::ZERO);
+        assert!(acct.balances(Currency::USD).held == Decimal::ZERO);
+        assert!(acct.balances(Currency::USD).available == Decimal::ZERO);
+        assert!(acct.balances(Currency::USD).total == Decimal::ZERO);
     }
 
     /// Check a flow: deposit(failed)
@@ -475,6 +1087,9 @@ mod test {
             client_id,
             tx_id: 1,
             amount: Some(Decimal::from(0i16)),
+            currency: None,
+            reason: None,
+            timestamp: None,
         };
 
         let mut acct = Account::new(client_id);
@@ -495,6 +1110,9 @@ mod test {
             client_id,
             tx_id: 1,
             amount: Some(Decimal::from(1i16)),
+            currency: None,
+            reason: None,
+            timestamp: None,
         };
 
         let mut acct = Account::new(client_id);
@@ -513,6 +1131,9 @@ mod test {
             client_id,
             tx_id: 1,
             amount: Some(Decimal::from(10i16)),
+            currency: None,
+            reason: None,
+            timestamp: None,
         };
 
         let mut acct = Account::new(client_id);
@@ -524,6 +1145,9 @@ mod test {
             client_id,
             tx_id: 2,
             amount: Some(Decimal::from(1i16)),
+            currency: None,
+            reason: None,
+            timestamp: None,
         };
         assert!(acct.on_tx(&withdrawal).is_ok());
 
@@ -540,6 +1164,9 @@ mod test {
             client_id,
             tx_id: 1,
             amount: None,
+            currency: None,
+            reason: None,
+            timestamp: None,
         };
 
         let mut acct = Account::new(client_id);
@@ -551,6 +1178,9 @@ mod test {
             client_id,
             tx_id: 2,
             amount: None,
+            currency: None,
+            reason: None,
+            timestamp: None,
         };
 
         assert!(acct.on_tx(&withdrawal).err().unwrap() == TxError::MissingAmountError);
@@ -566,6 +1196,9 @@ mod test {
             client_id,
             tx_id: 1,
             amount: Some(Decimal::from(1i16)),
+            currency: None,
+            reason: None,
+            timestamp: None,
         };
 
         let mut acct = Account::new(client_id);
@@ -577,6 +1210,9 @@ mod test {
             client_id,
             tx_id: 1,
             amount: None,
+            currency: None,
+            reason: None,
+            timestamp: None,
         };
 
         assert!(acct.on_tx(&dispute).is_ok());
@@ -586,11 +1222,14 @@ mod test {
             client_id,
             tx_id: 1,
             amount: None,
+            currency: None,
+            reason: None,
+            timestamp: None,
         };
 
         assert!(acct.on_tx(&chargeback).is_ok());
 
-        assert!(acct.locked);
+        assert!(acct.state == AccountState::Locked);
 
         deposit.tx_id = 2;
         assert!(acct.on_tx(&deposit).err().unwrap() == TxError::LockedAccountError);
@@ -606,6 +1245,9 @@ mod test {
             client_id,
             tx_id: 1,
             amount: Some(Decimal::from(1i16)),
+            currency: None,
+            reason: None,
+            timestamp: None,
         };
 
         let mut acct = Account::new(client_id);
@@ -617,6 +1259,9 @@ mod test {
             client_id,
             tx_id: 1,
             amount: None,
+            currency: None,
+            reason: None,
+            timestamp: None,
         };
 
         assert!(acct.on_tx(&invalid_op).err().unwrap() == TxError::InvalidOperatioonError);
@@ -624,4 +1269,294 @@ mod test {
         invalid_op.r#type = TxType::ChargeBack;
         assert!(acct.on_tx(&invalid_op).err().unwrap() == TxError::InvalidOperatioonError);
     }
+
+    fn deposit_then_withdraw(acct: &mut Account) {
+        let deposit = Transaction {
+            r#type: TxType::Deposit,
+            client_id: acct.client_id,
+            tx_id: 1,
+            amount: Some(Decimal::from(10i16)),
+            currency: None,
+            reason: None,
+            timestamp: None,
+        };
+        assert!(acct.on_tx(&deposit).is_ok());
+
+        let withdrawal = Transaction {
+            r#type: TxType::Withdrawal,
+            client_id: acct.client_id,
+            tx_id: 2,
+            amount: Some(Decimal::from(4i16)),
+            currency: None,
+            reason: None,
+            timestamp: None,
+        };
+        assert!(acct.on_tx(&withdrawal).is_ok());
+    }
+
+    fn assert_amounts(acct: &Account, available: i16, held: i16, total: i16) {
+        assert!(acct.balances(Currency::USD).available == Decimal::from(available));
+        assert!(acct.balances(Currency::USD).held == Decimal::from(held));
+        assert!(acct.balances(Currency::USD).total == Decimal::from(total));
+    }
+
+    /// Check a flow: deposit -> withdrawal -> dispute withdrawal(ok) -> duplicate dispute(failed) -> resolve(ok) -> resolve(failed) -> dispute(ok)
+    #[test]
+    fn test_withdrawal_dispute_resolve() {
+        let client_id = 1;
+        let mut acct = Account::new(client_id);
+        deposit_then_withdraw(&mut acct);
+        assert_amounts(&acct, 6, 0, 6);
+
+        let mut tx = Transaction {
+            r#type: TxType::Dispute,
+            client_id,
+            tx_id: 2,
+            amount: None,
+            currency: None,
+            reason: None,
+            timestamp: None,
+        };
+
+        // the withdrawn funds come back into held, not into available
+        assert!(acct.on_tx(&tx).is_ok());
+        assert_amounts(&acct, 6, 4, 10);
+
+        assert!(acct.on_tx(&tx).err().unwrap() == TxError::InvalidOperatioonError);
+        assert_amounts(&acct, 6, 4, 10);
+
+        // the withdrawal stands
+        tx.r#type = TxType::Resolve;
+        assert!(acct.on_tx(&tx).is_ok());
+        assert_amounts(&acct, 6, 0, 6);
+
+        assert!(acct.on_tx(&tx).err().unwrap() == TxError::InvalidOperatioonError);
+        assert_amounts(&acct, 6, 0, 6);
+
+        // a resolved withdrawal can be disputed again
+        tx.r#type = TxType::Dispute;
+        assert!(acct.on_tx(&tx).is_ok());
+        assert_amounts(&acct, 6, 4, 10);
+        assert!(acct.state == AccountState::Active);
+    }
+
+    /// Check a flow: deposit -> withdrawal -> chargeback(failed) -> dispute withdrawal(ok) -> chargeback(ok) -> locked
+    #[test]
+    fn test_withdrawal_dispute_chargeback() {
+        let client_id = 1;
+        let mut acct = Account::new(client_id);
+        deposit_then_withdraw(&mut acct);
+
+        let mut tx = Transaction {
+            r#type: TxType::ChargeBack,
+            client_id,
+            tx_id: 2,
+            amount: None,
+            currency: None,
+            reason: None,
+            timestamp: None,
+        };
+
+        assert!(acct.on_tx(&tx).err().unwrap() == TxError::InvalidOperatioonError);
+        assert_amounts(&acct, 6, 0, 6);
+
+        tx.r#type = TxType::Dispute;
+        assert!(acct.on_tx(&tx).is_ok());
+
+        // the withdrawal is reversed, the client gets the funds back
+        tx.r#type = TxType::ChargeBack;
+        assert!(acct.on_tx(&tx).is_ok());
+        assert_amounts(&acct, 10, 0, 10);
+        assert!(acct.state == AccountState::Locked);
+
+        tx.r#type = TxType::Dispute;
+        assert!(acct.on_tx(&tx).err().unwrap() == TxError::LockedAccountError);
+    }
+
+    /// Check a flow: a deposit and a withdrawal are disputed at the same time, then both are settled
+    #[test]
+    fn test_deposit_and_withdrawal_disputes() {
+        let client_id = 1;
+        let mut acct = Account::new(client_id);
+        deposit_then_withdraw(&mut acct);
+
+        let mut deposit_tx = Transaction {
+            r#type: TxType::Dispute,
+            client_id,
+            tx_id: 1,
+            amount: None,
+            currency: None,
+            reason: None,
+            timestamp: None,
+        };
+        let mut withdrawal_tx = deposit_tx.clone();
+        withdrawal_tx.tx_id = 2;
+
+        // only 6 is available, so the deposit of 10 can't be disputed until the withdrawal is
+        assert!(acct.on_tx(&deposit_tx).err().unwrap() == TxError::InvalidAmountError);
+        assert!(acct.on_tx(&withdrawal_tx).is_ok());
+        assert_amounts(&acct, 6, 4, 10);
+
+        withdrawal_tx.r#type = TxType::Resolve;
+        assert!(acct.on_tx(&withdrawal_tx).is_ok());
+        assert_amounts(&acct, 6, 0, 6);
+
+        deposit_tx.tx_id = 3;
+        assert!(acct.on_tx(&deposit_tx).err().unwrap() == TxError::InvalidTxIdError);
+    }
+
+    fn admin(r#type: TxType, client_id: u16) -> Transaction {
+        Transaction {
+            r#type,
+            client_id,
+            tx_id: 0,
+            amount: None,
+            currency: None,
+            reason: Some("ops".to_string()),
+            timestamp: None,
+        }
+    }
+
+    /// Check a flow: freeze(ok) -> deposit/withdrawal(failed) -> dispute(ok) -> freeze(failed) -> unlock(ok) -> unlock(failed)
+    #[test]
+    fn test_freeze_unlock() {
+        let client_id = 1;
+        let mut acct = Account::new(client_id);
+        deposit_then_withdraw(&mut acct);
+
+        assert!(acct.on_tx(&admin(TxType::Freeze, client_id)).is_ok());
+        assert!(acct.state == AccountState::Frozen);
+        assert!(!acct.locked());
+        assert_eq!(acct.state_reason.as_deref(), Some("ops"));
+
+        let mut tx = Transaction {
+            r#type: TxType::Deposit,
+            client_id,
+            tx_id: 3,
+            amount: Some(Decimal::from(1i16)),
+            currency: None,
+            reason: None,
+            timestamp: None,
+        };
+        assert!(acct.on_tx(&tx).err().unwrap() == TxError::FrozenAccountError);
+        tx.r#type = TxType::Withdrawal;
+        assert!(acct.on_tx(&tx).err().unwrap() == TxError::FrozenAccountError);
+
+        // the disputes can still go on
+        tx.r#type = TxType::Dispute;
+        tx.tx_id = 2;
+        tx.amount = None;
+        assert!(acct.on_tx(&tx).is_ok());
+        assert_amounts(&acct, 6, 4, 10);
+
+        assert!(acct.on_tx(&admin(TxType::Freeze, client_id)).err().unwrap() == TxError::InvalidOperatioonError);
+
+        assert!(acct.on_tx(&admin(TxType::Unlock, client_id)).is_ok());
+        assert!(acct.state == AccountState::Active);
+        assert!(acct.on_tx(&admin(TxType::Unlock, client_id)).err().unwrap() == TxError::InvalidOperatioonError);
+    }
+
+    /// Check a flow: chargeback -> locked -> freeze(failed) -> unlock(ok) -> deposit(ok)
+    #[test]
+    fn test_unlock_locked() {
+        let client_id = 1;
+        let mut acct = Account::new(client_id);
+        deposit_then_withdraw(&mut acct);
+
+        let mut tx = Transaction {
+            r#type: TxType::Dispute,
+            client_id,
+            tx_id: 2,
+            amount: None,
+            currency: None,
+            reason: None,
+            timestamp: None,
+        };
+        assert!(acct.on_tx(&tx).is_ok());
+        tx.r#type = TxType::ChargeBack;
+        assert!(acct.on_tx(&tx).is_ok());
+        assert!(acct.state == AccountState::Locked);
+        assert!(acct.locked());
+
+        assert!(acct.on_tx(&admin(TxType::Freeze, client_id)).err().unwrap() == TxError::InvalidOperatioonError);
+        assert!(acct.on_tx(&admin(TxType::Unlock, client_id)).is_ok());
+
+        tx.r#type = TxType::Deposit;
+        tx.tx_id = 3;
+        tx.amount = Some(Decimal::from(1i16));
+        assert!(acct.on_tx(&tx).is_ok());
+        assert_amounts(&acct, 11, 0, 11);
+    }
+
+    /// Check a flow: close with funds(failed) -> withdraw all -> close(ok) -> anything(failed)
+    #[test]
+    fn test_close() {
+        let client_id = 1;
+        let mut acct = Account::new(client_id);
+        deposit_then_withdraw(&mut acct);
+
+        assert!(acct.on_tx(&admin(TxType::Close, client_id)).err().unwrap() == TxError::InvalidOperatioonError);
+
+        let mut tx = Transaction {
+            r#type: TxType::Withdrawal,
+            client_id,
+            tx_id: 3,
+            amount: Some(Decimal::from(6i16)),
+            currency: None,
+            reason: None,
+            timestamp: None,
+        };
+        assert!(acct.on_tx(&tx).is_ok());
+        assert!(acct.on_tx(&admin(TxType::Close, client_id)).is_ok());
+        assert!(acct.state == AccountState::Closed);
+        assert!(!acct.locked());
+
+        tx.r#type = TxType::Deposit;
+        tx.tx_id = 4;
+        assert!(acct.on_tx(&tx).err().unwrap() == TxError::ClosedAccountError);
+        assert!(acct.on_tx(&admin(TxType::Unlock, client_id)).err().unwrap() == TxError::ClosedAccountError);
+        assert!(acct.on_tx(&admin(TxType::Close, client_id)).err().unwrap() == TxError::ClosedAccountError);
+    }
+
+    /// Check the history of: deposit -> withdrawal -> dispute -> failed deposit -> chargeback -> unlock
+    #[test]
+    fn test_history() {
+        let client_id = 1;
+        let mut acct = Account::new(client_id);
+        deposit_then_withdraw(&mut acct);
+
+        let mut tx = Transaction {
+            r#type: TxType::Dispute,
+            client_id,
+            tx_id: 2,
+            amount: None,
+            currency: None,
+            reason: None,
+            timestamp: None,
+        };
+        assert!(acct.on_tx(&tx).is_ok());
+        tx.r#type = TxType::ChargeBack;
+        assert!(acct.on_tx(&tx).is_ok());
+        assert!(acct.on_tx(&admin(TxType::Deposit, client_id)).is_err());
+        assert!(acct.on_tx(&admin(TxType::Unlock, client_id)).is_ok());
+
+        let history = acct.history();
+        let got: Vec<_> = history.iter().map(|h| (h.tx, h.r#type.clone(), h.amount, h.status)).collect();
+        let (four, charged_back) = (Some(Decimal::from(4i16)), Some(TxStatus::Withdrawal(WithdrawalStatus::ChargedBack)));
+        assert_eq!(
+            got,
+            vec![
+                (1, TxType::Deposit, Some(Decimal::from(10i16)), Some(TxStatus::Deposit(DepositStatus::None))),
+                (2, TxType::Withdrawal, four, charged_back),
+                (2, TxType::Dispute, four, charged_back),
+                (2, TxType::ChargeBack, four, charged_back),
+                (0, TxType::Unlock, None, None),
+            ]
+        );
+
+        // the running balances, i.e., available/held/total after each of them
+        let balances: Vec<_> = history.iter().map(|h| (h.available, h.held, h.total)).collect();
+        let d = |n: i16| Decimal::from(n);
+        assert_eq!(balances, vec![(d(10), d(0), d(10)), (d(6), d(0), d(6)), (d(6), d(4), d(10)), (d(10), d(0), d(10)), (d(10), d(0), d(10))]);
+    }
 }
@@ -0,0 +1,243 @@
+use std::{collections::BTreeMap, str::FromStr};
+
+use rust_decimal::Decimal;
+use serde::{Serialize, Serializer};
+
+use super::{Account, Currency, GeneralLedger, LedgerAccount, TxType};
+
+/// AuditMode is when the invariants are checked, see Bookkeeper::set_audit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditMode {
+    /// the account of every transaction applied, right after it, so that a violation is found at the offending tx
+    EachTx,
+    /// all the accounts, once everything is processed
+    End,
+}
+
+impl FromStr for AuditMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "each" | "each-tx" => Ok(AuditMode::EachTx),
+            "end" => Ok(AuditMode::End),
+            _ => Err(format!("unknown audit mode: {}", s)),
+        }
+    }
+}
+
+/// Invariant is a rule the ledger must always follow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Invariant {
+    /// total == available + held, in every currency
+    Total,
+    /// held == the deposits and withdrawals in dispute, in every currency
+    Held,
+    /// no balance is negative
+    Negative,
+    /// a locked account is only unlocked or closed
+    Locked,
+    /// the trial balance nets to zero, in every currency
+    TrialBalance,
+    /// the customer ledger accounts and the fee revenue are the sum of the accounts, in every currency
+    CustomerLedger,
+}
+
+impl Invariant {
+    /// code returns a stable, machine-readable name for reports
+    pub fn code(&self) -> &'static str {
+        match self {
+            Invariant::Total => "total_mismatch",
+            Invariant::Held => "held_mismatch",
+            Invariant::Negative => "negative_balance",
+            Invariant::Locked => "locked_mutation",
+            Invariant::TrialBalance => "unbalanced_ledger",
+            Invariant::CustomerLedger => "customer_ledger_mismatch",
+        }
+    }
+}
+
+/// Violation is a broken invariant, with the client and the tx it was found at, if any
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Violation {
+    pub client: Option<u16>,
+    pub tx: Option<u32>,
+    #[serde(serialize_with = "serialize_invariant")]
+    pub invariant: Invariant,
+    pub detail: String,
+}
+
+fn serialize_invariant<S>(invariant: &Invariant, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    s.serialize_str(invariant.code())
+}
+
+impl Account {
+    /// audit checks the balances of the account, and that it wasn't changed while locked from the event at index from on.
+    /// The balance violations are reported at the latest tx.
+    pub fn audit(&self, from: usize) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        let tx = self.events().last().map(|e| e.tx);
+        let mut violation = |tx, invariant, detail| {
+            violations.push(Violation {
+                client: Some(self.client_id),
+                tx,
+                invariant,
+                detail,
+            })
+        };
+
+        for currency in self.currencies() {
+            let b = self.balances(currency);
+            if b.total != b.available + b.held {
+                violation(tx, Invariant::Total, format!("{} total {} != available {} + held {}", currency, b.total, b.available, b.held));
+            }
+
+            let disputed = self.disputed_amount(currency);
+            if b.held != disputed {
+                violation(tx, Invariant::Held, format!("{} held {} != disputed {}", currency, b.held, disputed));
+            }
+
+            for (name, balance) in [("available", b.available), ("held", b.held), ("total", b.total)] {
+                if balance < Decimal::ZERO {
+                    violation(tx, Invariant::Negative, format!("{} {} {}", currency, name, balance));
+                }
+            }
+        }
+
+        // a chargeback locks the account, so the next event can only be an unlock or a close
+        let events = self.events();
+        for i in from.max(1)..events.len() {
+            if events[i - 1].r#type == TxType::ChargeBack && events[i].r#type != TxType::Unlock && events[i].r#type != TxType::Close {
+                violation(Some(events[i].tx), Invariant::Locked, format!("{:?} after the chargeback of tx {}", events[i].r#type, events[i - 1].tx).to_lowercase());
+            }
+        }
+
+        violations
+    }
+}
+
+impl GeneralLedger {
+    /// audit checks that the ledger balances, and that its customer accounts and fee revenue are the sum of accounts
+    pub fn audit<'a, I>(&self, accounts: I) -> Vec<Violation>
+    where
+        I: IntoIterator<Item = &'a Account>,
+    {
+        let mut violations = self.audit_balance();
+
+        let mut sums: BTreeMap<Currency, [Decimal; 3]> = self.currencies().into_iter().map(|c| (c, [Decimal::ZERO; 3])).collect();
+        for acct in accounts {
+            for (&currency, balances) in &acct.buckets {
+                let sum = sums.entry(currency).or_default();
+                sum[0] += balances.available;
+                sum[1] += balances.held;
+                sum[2] += acct.fees(currency);
+            }
+        }
+        for (currency, sum) in sums {
+            for (account, sum) in [LedgerAccount::CustomerAvailable, LedgerAccount::CustomerHeld, LedgerAccount::FeeRevenue].into_iter().zip(sum) {
+                if self.balance(currency, account) != sum {
+                    violations.push(Violation {
+                        client: None,
+                        tx: None,
+                        invariant: Invariant::CustomerLedger,
+                        detail: format!("{} {:?} {} != {} in the accounts", currency, account, self.balance(currency, account), sum),
+                    });
+                }
+            }
+        }
+
+        violations
+    }
+
+    /// audit_balance checks that the trial balance nets to zero, it's cheap enough for every transaction
+    pub fn audit_balance(&self) -> Vec<Violation> {
+        self.trial_balance()
+            .nets()
+            .into_iter()
+            .filter(|(_, net)| !net.is_zero())
+            .map(|(currency, net)| Violation {
+                client: None,
+                tx: None,
+                invariant: Invariant::TrialBalance,
+                detail: format!("the {} trial balance nets to {}", currency, net),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::Decimal;
+
+    use crate::model::{AuditMode, Bookkeeper, Change, Currency, DepositStatus, Effect, Invariant};
+
+    const INPUT: &str = "type, client, tx, amount
+deposit, 1, 1, 10.0
+withdrawal, 1, 2, 4.0
+dispute, 1, 2,
+deposit, 2, 3, 5.0
+dispute, 2, 3,
+chargeback, 2, 3,
+unlock, 2, 4,
+deposit, 2, 5, 1.0";
+
+    #[test]
+    fn test_audit_clean() {
+        for mode in [AuditMode::EachTx, AuditMode::End] {
+            let mut bkeeper = Bookkeeper::new();
+            bkeeper.set_audit(mode);
+            bkeeper.process_reader(INPUT.as_bytes()).unwrap();
+            assert_eq!(bkeeper.finish_audit(), vec![]);
+        }
+    }
+
+    #[test]
+    fn test_audit_violations() {
+        let mut bkeeper = Bookkeeper::new();
+        bkeeper.process_reader(INPUT.as_bytes()).unwrap();
+
+        // held by hand, as a broken on_resolve would
+        bkeeper.accounts.get_mut(&1).unwrap().buckets.get_mut(&Currency::USD).unwrap().held = Decimal::ZERO;
+        // a deposit applied on a locked account, as a broken on_chargeback would
+        let acct = bkeeper.accounts.get_mut(&2).unwrap();
+        let chargeback = Effect {
+            client: 2,
+            tx: 3,
+            currency: Currency::USD,
+            available: Decimal::ZERO,
+            held: Decimal::ZERO,
+            total: Decimal::ZERO,
+            residue: Decimal::ZERO,
+            fee: Decimal::ZERO,
+            time: None,
+            accepted: None,
+            changes: vec![Change::DepositStatus {
+                status: DepositStatus::ChargedBack,
+            }],
+        };
+        acct.apply(&chargeback);
+        let deposit = Effect {
+            tx: 6,
+            available: Decimal::ONE,
+            total: Decimal::ONE,
+            changes: vec![Change::Deposit { amount: Decimal::ONE }],
+            ..chargeback
+        };
+        acct.apply(&deposit);
+
+        let got: Vec<_> = bkeeper.audit().into_iter().map(|v| (v.client, v.tx, v.invariant)).collect();
+        assert_eq!(
+            got,
+            vec![
+                (Some(1), Some(2), Invariant::Total),
+                (Some(1), Some(2), Invariant::Held),
+                (Some(2), Some(6), Invariant::Locked),
+                (None, None, Invariant::CustomerLedger),
+                (None, None, Invariant::CustomerLedger),
+            ]
+        );
+    }
+}
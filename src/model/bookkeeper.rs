@@ -1,65 +1,562 @@
 use std::{
-    collections::HashMap,
-    io::{self, Read},
+    borrow::Cow,
+    fs,
+    io::{self, BufRead, Read, Write},
+    mem,
+    path::Path,
+    str::FromStr,
+    sync::Arc,
 };
 
 use log::*;
+use serde::Deserialize;
 
-use super::{Account, Transaction, TxError, TxType};
+use super::{
+    Account, AccountStore, AuditMode, Clock, CsvSource, Effect, FeeSchedule, GeneralLedger, HistoryEntry, InputFormat, InputRecord, Journal, JournalRecord, JsonLinesSource, LedgerBound, Limits, LogRejections, Precision, Rejection, RejectionSink, ReportFormat,
+    ReportOrder, ReportSink, ReportWriter, Snapshot, SnapshotError, Statement, StatementFormat, SyncPolicy, SystemClock, Timestamp, Transaction, TrialBalance, TxError, TxIndex, TxSource, TxType, Violation, SNAPSHOT_VERSION,
+};
+
+/// AccountPolicy decides when a client gets an account
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AccountPolicy {
+    /// an account is created by the first successful deposit of the client
+    #[default]
+    OnDeposit,
+    /// an account is created by any row of the client, even a rejected one
+    AnyRow,
+    /// only registered clients have accounts, see Bookkeeper::register_client
+    Registered,
+}
 
-const DEFAULT_ACCOUNT_COUNT: usize = 4086;
+impl FromStr for AccountPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "on-deposit" => Ok(AccountPolicy::OnDeposit),
+            "any-row" => Ok(AccountPolicy::AnyRow),
+            "registered" => Ok(AccountPolicy::Registered),
+            _ => Err(format!("unknown account policy: {}", s)),
+        }
+    }
+}
+
+/// ClientRecord is a row of the client master file, other columns are ignored
+#[derive(Deserialize)]
+struct ClientRecord {
+    client: u16,
+}
 
 pub struct Bookkeeper {
-    pub accounts: HashMap<u16, Account>,
+    pub accounts: AccountStore,
+
+    policy: AccountPolicy,
+
+    /// how the amounts of every currency are rounded
+    precision: Precision,
+
+    /// the fees charged on the accepted transactions
+    fees: Arc<FeeSchedule>,
+
+    /// the limits checked before a deposit/withdrawal is accepted
+    limits: Arc<Limits>,
+
+    /// the time every transaction without a timestamp is accepted at, see Effect::accepted
+    clock: Arc<dyn Clock>,
+
+    /// if a transaction older than the last one of its client is rejected
+    strict_time: bool,
+
+    /// ids of all the applied deposits/withdrawals, a tx id is unique across the whole ledger.
+    /// The shards of the parallel engine share it.
+    tx_index: Arc<TxIndex>,
+
+    journal: Option<Journal>,
+    /// the rejections replayed from the journal, reported again before the rest of the input, see process_source
+    replayed_rejections: Vec<Rejection>,
+
+    /// every applied transaction is posted to it, see Effect::postings
+    general_ledger: GeneralLedger,
+
+    audit: Option<AuditMode>,
+    /// the violations found after each transaction, see AuditMode::EachTx
+    violations: Vec<Violation>,
 }
 
 impl Bookkeeper {
     pub fn new() -> Bookkeeper {
+        Self::with_policy(AccountPolicy::default())
+    }
+
+    pub fn with_policy(policy: AccountPolicy) -> Bookkeeper {
         Bookkeeper {
-            accounts: HashMap::with_capacity(DEFAULT_ACCOUNT_COUNT),
+            accounts: AccountStore::new(),
+            policy,
+            precision: Precision::new(),
+            fees: Arc::new(FeeSchedule::new()),
+            limits: Arc::new(Limits::new()),
+            clock: Arc::new(SystemClock),
+            strict_time: false,
+            tx_index: Arc::new(TxIndex::new()),
+            journal: None,
+            replayed_rejections: Vec::new(),
+            general_ledger: GeneralLedger::new(),
+            audit: None,
+            violations: Vec::new(),
+        }
+    }
+
+    /// set_precision sets the decimal places and the rounding mode of the amounts of every currency.
+    /// The residues rounded away are kept by the general ledger, see GeneralLedger::residue.
+    pub fn set_precision(&mut self, precision: Precision) {
+        self.precision = precision;
+    }
+
+    /// set_fees sets the fees charged on every transaction accepted from now on.
+    /// They are posted to the fee revenue of the general ledger, and summed per account, see Account::fees.
+    pub fn set_fees(&mut self, fees: FeeSchedule) {
+        self.fees = Arc::new(fees);
+    }
+
+    /// set_limits sets the limits checked before every deposit/withdrawal from now on.
+    /// Their windows are over the times of the transactions, in whatever order they come, see Limits::check.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = Arc::new(limits);
+    }
+
+    /// set_clock sets the clock telling the time the transactions without a timestamp are accepted at, the system one by default
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// set_strict_time makes a transaction whose timestamp is before the time of the last transaction of its client rejected
+    /// with out_of_order, so that the history of every account is in time order. The same time is fine, and a transaction
+    /// without a timestamp has no time to check.
+    pub fn set_strict_time(&mut self, strict: bool) {
+        self.strict_time = strict;
+    }
+
+    /// set_audit makes the invariants checked, see finish_audit
+    pub fn set_audit(&mut self, mode: AuditMode) {
+        self.audit = Some(mode);
+    }
+
+    /// finish_audit returns the violations of the invariants: the ones found after each transaction so far,
+    /// or, auditing at the end, the ones of the whole ledger. Every violation is logged.
+    pub fn finish_audit(&mut self) -> Vec<Violation> {
+        let violations = match self.audit {
+            Some(AuditMode::EachTx) => {
+                let mut violations = mem::take(&mut self.violations);
+                violations.extend(self.general_ledger.audit(self.accounts.values()));
+                violations
+            }
+            Some(AuditMode::End) => self.audit(),
+            None => Vec::new(),
+        };
+
+        for v in &violations {
+            error!("audit: client {:?}, tx {:?}: {} {}", v.client, v.tx, v.invariant.code(), v.detail);
+        }
+        violations
+    }
+
+    /// audit checks the invariants of every account and of the general ledger
+    pub fn audit(&self) -> Vec<Violation> {
+        let mut violations: Vec<_> = self.accounts.values().flat_map(|acct| acct.audit(0)).collect();
+        violations.extend(self.general_ledger.audit(self.accounts.values()));
+        violations
+    }
+
+    /// register_client opens an empty account for client_id, it returns false if the client has one already
+    pub fn register_client(&mut self, client_id: u16) -> bool {
+        if self.accounts.contains_key(&client_id) {
+            return false;
         }
+
+        self.accounts.open(client_id);
+        true
     }
 
-    pub fn process_reader<R>(&mut self, r: R) -> Result<(), csv::Error>
+    /// load_clients registers all the clients in the client master file, a csv with a client column.
+    /// It returns the count of newly registered clients.
+    pub fn load_clients<R>(&mut self, r: R) -> Result<usize, csv::Error>
     where
         R: Read,
     {
         let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(r);
-        let mut raw_record = csv::StringRecord::new();
-        let headers = reader.headers()?.clone();
-        let trimed_headers = trim_string_record(&headers);
-
-        while reader.read_record(&mut raw_record)? {
-            let trimed_raw_record = trim_string_record(&raw_record);
-            match trimed_raw_record.deserialize(Some(&trimed_headers)) {
-                Ok(tx) => {
-                    if let Some(e) = self.on_tx(&tx).err() {
-                        error!("failed to process transaction({:?}): {:?}", tx, e);
-                    }
+        let mut count = 0;
+        for record in reader.deserialize() {
+            let record: ClientRecord = record?;
+            if self.register_client(record.client) {
+                count += 1;
+            }
+        }
+
+        info!("registered {} client(s)", count);
+        Ok(count)
+    }
+
+    /// process_reader applies all the transactions in the csv r, logging the rejected ones
+    pub fn process_reader<R>(&mut self, r: R) -> io::Result<()>
+    where
+        R: Read,
+    {
+        self.process_reader_with_rejections(r, &mut LogRejections)
+    }
+
+    /// process_reader_with_rejections applies all the transactions in the csv r, and reports every rejected row to rejections
+    pub fn process_reader_with_rejections<R, S>(&mut self, r: R, rejections: &mut S) -> io::Result<()>
+    where
+        R: Read,
+        S: RejectionSink + ?Sized,
+    {
+        self.process_source(&mut CsvSource::new(r)?, rejections)
+    }
+
+    /// process_input applies all the transactions in r, read as format, and reports every rejected row to rejections
+    pub fn process_input<R, S>(&mut self, r: R, format: InputFormat, rejections: &mut S) -> io::Result<()>
+    where
+        R: BufRead,
+        S: RejectionSink + ?Sized,
+    {
+        match format {
+            InputFormat::Csv => self.process_source(&mut CsvSource::new(r)?, rejections),
+            InputFormat::JsonLines => self.process_source(&mut JsonLinesSource::new(r), rejections),
+        }
+    }
+
+    /// process_source applies all the transactions of source, and reports every rejected record to rejections.
+    /// The validation is the same whatever the format, only a malformed record is up to the source.
+    pub fn process_source<T, S>(&mut self, source: &mut T, rejections: &mut S) -> io::Result<()>
+    where
+        T: TxSource + ?Sized,
+        S: RejectionSink + ?Sized,
+    {
+        // after a recovery, the rows already journaled are skipped, and their rejections are reported again
+        let resume_after = self.journal.as_ref().map_or(0, |j| j.last_line());
+        for rejection in mem::take(&mut self.replayed_rejections) {
+            rejections.on_rejection(&rejection)?;
+        }
+
+        while let Some(record) = source.next_record()? {
+            if record.line <= resume_after {
+                continue;
+            }
+
+            if let Some(rejection) = self.process_record(record)? {
+                if let Some(journal) = &mut self.journal {
+                    journal.append(rejection.line, JournalRecord::reject(&rejection))?;
+                }
+                rejections.on_rejection(&rejection)?;
+            }
+        }
+
+        rejections.flush()?;
+
+        Ok(())
+    }
+
+    /// save_snapshot writes the full state, including all the histories, so that a later batch can go on from it
+    pub fn save_snapshot<W>(&self, w: W) -> Result<(), SnapshotError>
+    where
+        W: Write,
+    {
+        let snapshot = Snapshot {
+            version: SNAPSHOT_VERSION,
+            accounts: self.accounts.ordered(ReportOrder::Insertion).into_iter().map(|acct| acct.snapshot()).collect(),
+            general_ledger: Cow::Borrowed(&self.general_ledger),
+        };
+
+        let mut w = io::BufWriter::new(w);
+        serde_json::to_writer(&mut w, &snapshot)?;
+        w.flush()?;
+
+        info!("saved {} account(s)", snapshot.accounts.len());
+        Ok(())
+    }
+
+    /// load_snapshot replaces all the accounts with the ones saved by save_snapshot, the account policy is kept
+    pub fn load_snapshot<R>(&mut self, r: R) -> Result<(), SnapshotError>
+    where
+        R: Read,
+    {
+        // the version is checked first, a snapshot of another version has another layout
+        let value: serde_json::Value = serde_json::from_reader(io::BufReader::new(r))?;
+        let version = value.get("version").and_then(serde_json::Value::as_u64).unwrap_or_default() as u32;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::VersionError(version));
+        }
+        let snapshot = Snapshot::deserialize(value)?;
+
+        self.accounts.clear();
+        self.tx_index = Arc::new(TxIndex::new());
+        for acct in snapshot.accounts {
+            let acct = Account::from(acct);
+            for tx_id in acct.tx_ids() {
+                if !self.tx_index.insert(tx_id, acct.client_id) {
+                    warn!("tx {} of client {} is already owned by client {:?}", tx_id, acct.client_id, self.tx_index.owner(tx_id));
                 }
-                Err(e) => error!("failed to deserialize transaction({:?}): {:?}", trimed_raw_record, e),
             }
+            self.accounts.insert(acct);
         }
+        self.general_ledger = snapshot.general_ledger.into_owned();
 
+        info!("loaded {} account(s)", self.accounts.len());
         Ok(())
     }
 
-    pub fn report_balance(&self) -> Result<(), csv::Error> {
+    /// report_balance writes the balance report to stdout as CSV, by client id
+    pub fn report_balance(&self) -> io::Result<()> {
+        self.write_report(&mut ReportWriter::new(io::stdout().lock(), ReportFormat::Csv), ReportOrder::ClientId)
+    }
+
+    /// write_report sends every account to report in order, and finishes it.
+    /// The same ledger always gives the same report, whatever the order of the transactions of different clients.
+    pub fn write_report<S>(&self, report: &mut S, order: ReportOrder) -> io::Result<()>
+    where
+        S: ReportSink + ?Sized,
+    {
         info!("{} account(s)", self.accounts.len());
 
-        let mut writer = csv::Writer::from_writer(io::stdout());
+        for acct in self.accounts.ordered(order) {
+            report.on_account(acct)?;
+        }
+
+        report.finish()
+    }
+
+    /// write_report_at is write_report with every account as it was at time, see Account::at.
+    /// An account which had no event yet is left out, and the order is the one of the accounts at time.
+    pub fn write_report_at<S>(&self, report: &mut S, order: ReportOrder, time: Timestamp) -> io::Result<()>
+    where
+        S: ReportSink + ?Sized,
+    {
+        info!("{} account(s) as of {}", self.accounts.len(), time);
+
+        for acct in self.accounts.ordered_at(order, time.0) {
+            report.on_account(&acct)?;
+        }
+
+        report.finish()
+    }
+
+    /// history returns the transactions applied to the account of client_id, in order, with their running balances, see Account::history
+    pub fn history(&self, client_id: u16) -> Option<Vec<HistoryEntry>> {
+        self.accounts.get(&client_id).map(Account::history)
+    }
+
+    /// statement returns the statement of the whole history of the account of client_id, see Statement::new
+    pub fn statement(&self, client_id: u16) -> Option<Statement> {
+        self.accounts.get(&client_id).map(|acct| Statement::new(acct, 0, &self.precision))
+    }
+
+    pub fn general_ledger(&self) -> &GeneralLedger {
+        &self.general_ledger
+    }
+
+    /// trial_balance returns the balances of the general ledger, which always net to zero
+    pub fn trial_balance(&self) -> TrialBalance {
+        self.general_ledger.trial_balance()
+    }
+
+    /// write_statements writes the statement of every account over the current period, i.e., since the snapshot it was loaded from,
+    /// to a file per client in dir, e.g., 7.csv. It returns the number of statements.
+    pub fn write_statements(&self, dir: &Path, format: StatementFormat) -> io::Result<usize> {
+        fs::create_dir_all(dir)?;
 
         for acct in self.accounts.values() {
-            writer.serialize(acct)?;
+            let path = dir.join(format!("{}.{}", acct.client_id, format.extension()));
+            Statement::new(acct, acct.period_start(), &self.precision).write(io::BufWriter::new(fs::File::create(path)?), format)?;
+        }
+
+        info!("wrote {} statement(s) to {}", self.accounts.len(), dir.display());
+        Ok(self.accounts.len())
+    }
+
+    /// process_record applies the transaction of record, if it's valid, or returns why it's rejected
+    pub(crate) fn process_record(&mut self, record: InputRecord) -> io::Result<Option<Rejection>> {
+        let result = match &record.tx {
+            Ok(tx) => self.process_tx(record.line, tx)?.map_err(|e| {
+                let detail = e.to_string();
+                (e, detail)
+            }),
+            Err(e) => Err((TxError::InvaidFormatError, e.clone())),
+        };
+
+        Ok(result.err().map(|(reason, detail)| Rejection {
+            line: record.line,
+            client_id: record.client_id,
+            tx_id: record.tx_id,
+            reason,
+            detail,
+            record: record.raw,
+        }))
+    }
+
+    /// process_tx applies tx read at line, journaling it first if there is a journal.
+    /// The outer error is a journal failure, after which nothing should be applied any more.
+    pub fn process_tx(&mut self, line: u64, tx: &Transaction) -> io::Result<Result<(), TxError>> {
+        if self.policy == AccountPolicy::AnyRow && !self.accounts.contains_key(&tx.client_id) {
+            if let Some(journal) = &mut self.journal {
+                journal.append(line, JournalRecord::Open { client: tx.client_id })?;
+            }
+            self.register_client(tx.client_id);
+        }
+
+        let effect = match self.prepare(tx) {
+            Ok(effect) => effect,
+            Err(e) => return Ok(Err(e)),
+        };
+
+        if let Some(journal) = &mut self.journal {
+            journal.append(
+                line,
+                JournalRecord::Apply {
+                    tx: Box::new(Cow::Borrowed(tx)),
+                    effect: Cow::Borrowed(&effect),
+                },
+            )?;
+        }
+
+        self.commit(&effect);
+        Ok(Ok(()))
+    }
+
+    /// shard returns a bookkeeper without any account, with the same policy, precision, fees, limits, clock and time order, sharing the tx index
+    pub(crate) fn shard(&self) -> Bookkeeper {
+        Bookkeeper {
+            accounts: AccountStore::new(),
+            policy: self.policy,
+            precision: self.precision.clone(),
+            fees: Arc::clone(&self.fees),
+            limits: Arc::clone(&self.limits),
+            clock: Arc::clone(&self.clock),
+            strict_time: self.strict_time,
+            tx_index: Arc::clone(&self.tx_index),
+            journal: None,
+            replayed_rejections: Vec::new(),
+            general_ledger: GeneralLedger::new(),
+            audit: self.audit,
+            violations: Vec::new(),
         }
+    }
+
+    /// ledger_bound returns the bound of the general ledger and of the histories, see LedgerBound
+    pub(crate) fn ledger_bound(&self) -> LedgerBound {
+        LedgerBound::new(&self.general_ledger, self.accounts.values(), self.precision.places())
+    }
+
+    /// merge_shard adds the postings and the violations of a shard
+    pub(crate) fn merge_shard(&mut self, shard: &mut Bookkeeper) -> io::Result<()> {
+        self.general_ledger.merge(&shard.general_ledger)?;
+        self.violations.append(&mut shard.violations);
+
+        Ok(())
+    }
 
-        writer.flush()?;
+    pub(crate) fn journaled(&self) -> bool {
+        self.journal.is_some()
+    }
+
+    /// attach_journal makes every change journaled from now on
+    pub fn attach_journal(&mut self, journal: Journal) {
+        self.journal = Some(journal);
+    }
+
+    /// recover_journal replays the journal at path on top of the current state, which must be the same
+    /// as when the journal was created, and then keeps journaling to it. Processing an input goes on after
+    /// the last journaled line, and the rejections journaled before it are reported again first.
+    pub fn recover_journal<P: AsRef<Path>>(&mut self, path: P, sync: SyncPolicy) -> io::Result<()> {
+        let journal = Journal::recover(path, sync, |entry| self.replay(entry.line, entry.record))?;
+        self.attach_journal(journal);
 
         Ok(())
     }
 
-    fn on_tx(&mut self, tx: &Transaction) -> Result<(), TxError> {
-        self.accounts.entry(tx.client_id).or_insert(Account::new(tx.client_id)).on_tx(tx)
+    fn replay(&mut self, line: u64, record: JournalRecord) {
+        match record {
+            JournalRecord::Open { client } => {
+                self.register_client(client);
+            }
+            JournalRecord::Apply { effect, .. } => self.commit(&effect),
+            JournalRecord::Reject { client, tx, reason, detail, raw } => self.replayed_rejections.push(Rejection {
+                line,
+                client_id: client,
+                tx_id: tx,
+                reason: reason.into_owned(),
+                detail: detail.into_owned(),
+                record: raw.into_owned(),
+            }),
+        }
+    }
+
+    /// prepare validates tx against the whole ledger and computes its effect, without changing anything
+    fn prepare(&self, tx: &Transaction) -> Result<Effect, TxError> {
+        let is_new_tx = tx.r#type == TxType::Deposit || tx.r#type == TxType::Withdrawal;
+
+        // rows referring to a tx id owned by another client are rejected before touching any account.
+        // Admin rows are about the account itself, so their tx ids are not checked.
+        match self.tx_index.owner(tx.tx_id) {
+            _ if tx.r#type.is_admin() => {}
+            Some(owner) if owner != tx.client_id => {
+                return Err(if is_new_tx {
+                    TxError::DuplicateTxIdError
+                } else {
+                    TxError::ClientMismatchError { owner }
+                })
+            }
+            None if !is_new_tx => return Err(TxError::InvalidTxIdError),
+            _ => {}
+        }
+
+        // the clock is only the now of the limits for a transaction without a timestamp, it doesn't give it a time
+        let now = tx.timestamp.map_or_else(|| self.clock.now(), |t| t.0);
+        let (time, accepted) = if tx.timestamp.is_some() { (Some(now), None) } else { (None, Some(now)) };
+        let last = self.accounts.get(&tx.client_id).and_then(Account::last_time);
+        if let (Some(time), Some(last)) = (time, last) {
+            if self.strict_time && time < last {
+                return Err(TxError::OutOfOrderError { last: Timestamp(last) });
+            }
+        }
+
+        let rule = self.precision.rule(tx.currency.unwrap_or_default());
+        let effect = match self.accounts.get(&tx.client_id) {
+            Some(acct) => acct.prepare_with(tx, rule, &self.fees)?,
+            None => {
+                if self.policy != AccountPolicy::OnDeposit || tx.r#type != TxType::Deposit {
+                    return Err(TxError::InvalidClientError);
+                }
+
+                // the account is opened by commit, only if its first deposit is applied
+                Account::new(tx.client_id).prepare_with(tx, rule, &self.fees)?
+            }
+        };
+
+        let events = self.accounts.get(&tx.client_id).map_or(&[][..], |acct| acct.events());
+        self.limits.check(&effect, events, now)?;
+
+        // the amount fits in the account, but may be too big for the general ledger, summed over all the clients
+        if !self.general_ledger.can_post(&effect) {
+            return Err(TxError::InvalidAmountError);
+        }
+
+        Ok(Effect { time, accepted, ..effect })
+    }
+
+    fn commit(&mut self, effect: &Effect) {
+        self.accounts.open(effect.client).apply(effect);
+        self.general_ledger.post(effect);
+
+        if self.audit == Some(AuditMode::EachTx) {
+            if let Some(acct) = self.accounts.get(&effect.client) {
+                self.violations.extend(acct.audit(acct.events().len() - 1));
+            }
+            self.violations.extend(self.general_ledger.audit_balance());
+        }
+
+        if effect.opens_tx() {
+            self.tx_index.insert(effect.tx, effect.client);
+        }
     }
 }
 
@@ -69,20 +566,34 @@ impl Default for Bookkeeper {
     }
 }
 
-/// trim_string_record removes all the spaces in the input
-fn trim_string_record(s: &csv::StringRecord) -> csv::StringRecord {
-    let mut trimed_string_record = csv::StringRecord::new();
-    for field in s {
-        let mut f = field.to_string();
-        f.retain(|c| !c.is_whitespace());
-        trimed_string_record.push_field(&f[..]);
-    }
-    trimed_string_record
-}
-
 #[cfg(test)]
 mod test {
-    use crate::model::{Bookkeeper, Transaction, TxError, TxType};
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+
+    use rust_decimal::Decimal;
+
+    use crate::model::{
+        AccountPolicy, AccountState, Bookkeeper, Clock, Currency, FeeSchedule, InputFormat, LedgerAccount, Limits, Precision, RejectionLog, ReportFormat, ReportOrder, ReportWriter, SnapshotError,
+        Timestamp, Transaction, TxError, TxType,
+    };
+
+    /// ManualClock is a clock set by the test
+    struct ManualClock(AtomicU64);
+
+    impl Clock for ManualClock {
+        fn now(&self) -> u64 {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    impl Bookkeeper {
+        fn on_tx(&mut self, tx: &Transaction) -> Result<(), TxError> {
+            self.process_tx(0, tx).unwrap()
+        }
+    }
 
     #[test]
     fn test_client_invalid() {
@@ -93,11 +604,734 @@ mod test {
             client_id,
             tx_id: 1,
             amount: None,
+            currency: None,
+            reason: None,
+            timestamp: None,
         };
 
         let mut bkeeper = Bookkeeper::new();
         assert!(bkeeper.on_tx(&dispute).err().unwrap() == TxError::InvalidTxIdError);
+        assert!(bkeeper.accounts.is_empty());
+
+        let mut bkeeper = Bookkeeper::with_policy(AccountPolicy::AnyRow);
+        assert!(bkeeper.on_tx(&dispute).err().unwrap() == TxError::InvalidTxIdError);
+        assert!(bkeeper.accounts.len() == 1);
+        assert!(bkeeper.accounts.contains_key(&client_id));
+    }
+
+    #[test]
+    fn test_account_on_deposit() {
+        let mut tx = Transaction {
+            r#type: TxType::Withdrawal,
+            client_id: 1,
+            tx_id: 1,
+            amount: Some(Decimal::from(2i16)),
+            currency: None,
+            reason: None,
+            timestamp: None,
+        };
+
+        let mut bkeeper = Bookkeeper::new();
+        assert!(bkeeper.on_tx(&tx).err().unwrap() == TxError::InvalidClientError);
+
+        tx.r#type = TxType::Deposit;
+        tx.amount = None;
+        assert!(bkeeper.on_tx(&tx).err().unwrap() == TxError::MissingAmountError);
+        assert!(bkeeper.accounts.is_empty());
+
+        tx.amount = Some(Decimal::from(2i16));
+        assert!(bkeeper.on_tx(&tx).is_ok());
+        assert!(bkeeper.accounts.len() == 1);
+    }
+
+    #[test]
+    fn test_account_registered() {
+        let clients = "client, name\n1, alice\n2, bob\n1, alice again\n";
+
+        let mut bkeeper = Bookkeeper::with_policy(AccountPolicy::Registered);
+        assert_eq!(bkeeper.load_clients(clients.as_bytes()).unwrap(), 2);
+
+        let mut tx = Transaction {
+            r#type: TxType::Deposit,
+            client_id: 3,
+            tx_id: 1,
+            amount: Some(Decimal::from(2i16)),
+            currency: None,
+            reason: None,
+            timestamp: None,
+        };
+        assert!(bkeeper.on_tx(&tx).err().unwrap() == TxError::InvalidClientError);
+
+        tx.client_id = 2;
+        assert!(bkeeper.on_tx(&tx).is_ok());
+
+        let mut ids: Vec<_> = bkeeper.accounts.keys().copied().collect();
+        ids.sort_unstable();
+        assert_eq!(ids, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_rejections() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 1.0
+dispute, 1, 2,
+deposit, x, 3, 1.0
+withdrawal, 1, 4, 5.0
+deposit, 2, 5";
+
+        let mut bkeeper = Bookkeeper::new();
+        let mut rejections = RejectionLog::default();
+        bkeeper.process_reader_with_rejections(input.as_bytes(), &mut rejections).unwrap();
+
+        let got: Vec<_> = rejections
+            .rejections
+            .iter()
+            .map(|r| (r.line, r.client_id, r.tx_id, r.reason.code()))
+            .collect();
+        assert_eq!(
+            got,
+            vec![
+                (3, Some(1), Some(2), "invalid_tx_id"),
+                (4, None, Some(3), "invalid_format"),
+                (5, Some(1), Some(4), "invalid_amount"),
+                (6, Some(2), Some(5), "missing_amount"),
+            ]
+        );
+        assert_eq!(rejections.rejections[1].record, "deposit, x, 3, 1.0");
+    }
+
+    #[test]
+    fn test_input_formats() {
+        let csv = "type, client, tx, amount, reason
+deposit, 1, 1, 1.5,
+dispute, 1, 2,,
+deposit, x, 3, 1.0,
+withdrawal, 1, 4, 5.0,
+deposit, 2, 5,,
+deposit, 2, 1, 1.0,
+freeze, 2, 6,, kyc review
+withdrawal, 1, 7, 0.5,";
+        let ndjson = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 1.5}
+{"type": "dispute", "client": 1, "tx": 2}
+{"type": "deposit", "client": "x", "tx": 3, "amount": "1.0"}
+{"type": "withdrawal", "client": 1, "tx": 4, "amount": "5.0"}
+{"type": "deposit", "client": 2, "tx": 5, "amount": null}
+{"type": "deposit", "client": 2, "tx": 1, "amount": 1.0}
+{"type": "freeze", "client": 2, "tx": 6, "reason": "kyc review"}
+{"type": "withdrawal", "client": 1, "tx": 7, "amount": "0.5"}"#;
+
+        let mut results = Vec::new();
+        for (input, format) in [(csv, InputFormat::Csv), (ndjson, InputFormat::JsonLines)] {
+            let mut bkeeper = Bookkeeper::new();
+            let mut rejections = RejectionLog::default();
+            bkeeper.process_input(input.as_bytes(), format, &mut rejections).unwrap();
+
+            let got: Vec<_> = rejections
+                .rejections
+                .iter()
+                .map(|r| (r.line, r.client_id, r.tx_id, r.reason.code()))
+                .collect();
+            let acct = bkeeper.accounts.get(&1).unwrap();
+            results.push((got, bkeeper.accounts.len(), acct.balances(Currency::USD).available, acct.balances(Currency::USD).total));
+        }
+
+        // the csv lines start from 2, after the header
+        let (csv, ndjson) = (&results[0], &results[1]);
+        let csv_rejections: Vec<_> = csv.0.iter().map(|&(line, client, tx, code)| (line - 1, client, tx, code)).collect();
+        assert_eq!(csv_rejections, ndjson.0);
+        assert!((csv.1, csv.2, csv.3) == (ndjson.1, ndjson.2, ndjson.3));
+        assert_eq!(
+            ndjson.0,
+            vec![
+                (2, Some(1), Some(2), "invalid_tx_id"),
+                (3, None, Some(3), "invalid_format"),
+                (4, Some(1), Some(4), "invalid_amount"),
+                (5, Some(2), Some(5), "missing_amount"),
+                (6, Some(2), Some(1), "duplicate_tx_id"),
+                (7, Some(2), Some(6), "invalid_client"),
+            ]
+        );
+        assert!(ndjson.3 == Decimal::new(10, 1));
+    }
+
+    #[test]
+    fn test_tx_id_across_clients() {
+        let deposit = Transaction {
+            r#type: TxType::Deposit,
+            client_id: 1,
+            tx_id: 1,
+            amount: Some(Decimal::from(2i16)),
+            currency: None,
+            reason: None,
+            timestamp: None,
+        };
+
+        let mut bkeeper = Bookkeeper::new();
+        assert!(bkeeper.on_tx(&deposit).is_ok());
+
+        // the same client reuses it
+        assert!(bkeeper.on_tx(&deposit).err().unwrap() == TxError::InvalidTxIdError);
+
+        let withdrawal = Transaction {
+            r#type: TxType::Withdrawal,
+            client_id: 1,
+            tx_id: 1,
+            amount: Some(Decimal::from(1i16)),
+            currency: None,
+            reason: None,
+            timestamp: None,
+        };
+        assert!(bkeeper.on_tx(&withdrawal).err().unwrap() == TxError::InvalidTxIdError);
+
+        // another client reuses it
+        let mut other = deposit.clone();
+        other.client_id = 2;
+        assert!(bkeeper.on_tx(&other).err().unwrap() == TxError::DuplicateTxIdError);
+        assert!(!bkeeper.accounts.contains_key(&2));
+
+        // a rejected deposit does not take the id
+        let mut invalid = deposit.clone();
+        invalid.tx_id = 2;
+        invalid.amount = None;
+        assert!(bkeeper.on_tx(&invalid).err().unwrap() == TxError::MissingAmountError);
+        other.tx_id = 2;
+        assert!(bkeeper.on_tx(&other).is_ok());
+    }
+
+    #[test]
+    fn test_dispute_client_mismatch() {
+        let deposit = Transaction {
+            r#type: TxType::Deposit,
+            client_id: 1,
+            tx_id: 1,
+            amount: Some(Decimal::from(2i16)),
+            currency: None,
+            reason: None,
+            timestamp: None,
+        };
+
+        let mut bkeeper = Bookkeeper::new();
+        assert!(bkeeper.on_tx(&deposit).is_ok());
+
+        for r#type in [TxType::Dispute, TxType::Resolve, TxType::ChargeBack] {
+            let tx = Transaction {
+                r#type,
+                client_id: 2,
+                tx_id: 1,
+                amount: None,
+                currency: None,
+                reason: None,
+                timestamp: None,
+            };
+            let err = bkeeper.on_tx(&tx).err().unwrap();
+            assert!(err == TxError::ClientMismatchError { owner: 1 });
+            assert_eq!(err.to_string(), "Tx ID belongs to client 1");
+        }
+
         assert!(bkeeper.accounts.len() == 1);
-        assert!(bkeeper.accounts.get(&client_id).is_some());
+        let acct = bkeeper.accounts.get(&1).unwrap();
+        assert!(acct.balances(Currency::USD).held == Decimal::ZERO);
+        assert!(acct.balances(Currency::USD).available == Decimal::from(2i16));
+    }
+
+    /// Check that amounts which fit in every account, but not summed in the general ledger, are rejected
+    #[test]
+    fn test_general_ledger_overflow() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 79228162514264337593543950335
+deposit, 2, 2, 1.0
+withdrawal, 1, 3, 1.0
+deposit, 2, 4, 1.0";
+
+        let mut bkeeper = Bookkeeper::new();
+        let mut rejections = RejectionLog::default();
+        bkeeper.process_input(input.as_bytes(), InputFormat::Csv, &mut rejections).unwrap();
+        let rejected: Vec<_> = rejections.rejections.iter().map(|r| (r.line, r.reason.clone())).collect();
+        assert_eq!(rejected, vec![(3, TxError::InvalidAmountError)]);
+        assert!(bkeeper.accounts.get(&2).unwrap().balances(Currency::USD).total == Decimal::ONE);
+        assert!(bkeeper.trial_balance().is_balanced());
+        assert_eq!(bkeeper.audit(), vec![]);
+
+        // the parallel engine rejects the same rows, the huge deposit and the next one being in different shards
+        let mut parallel = Bookkeeper::new();
+        let mut rejections = RejectionLog::default();
+        parallel.process_input_parallel(input.as_bytes(), InputFormat::Csv, &mut rejections, 2).unwrap();
+        assert_eq!(rejections.rejections.iter().map(|r| (r.line, r.reason.clone())).collect::<Vec<_>>(), rejected);
+        for client_id in [1, 2] {
+            assert_eq!(parallel.accounts.get(&client_id).unwrap().balances(Currency::USD), bkeeper.accounts.get(&client_id).unwrap().balances(Currency::USD));
+        }
+        assert!(parallel.trial_balance() == bkeeper.trial_balance());
+    }
+
+    #[test]
+    fn test_unlock_after_chargeback() {
+        let input = "type, client, tx, amount, reason
+deposit, 1, 1, 3.0,
+dispute, 1, 1,,
+chargeback, 1, 1,,
+deposit, 1, 2, 1.0,
+unlock, 1, 3,, investigation cleared
+deposit, 1, 4, 1.0,
+unlock, 2, 5,, no such client";
+
+        let mut bkeeper = Bookkeeper::new();
+        let mut rejections = RejectionLog::default();
+        bkeeper.process_reader_with_rejections(input.as_bytes(), &mut rejections).unwrap();
+
+        let got: Vec<_> = rejections.rejections.iter().map(|r| (r.line, r.reason.code())).collect();
+        assert_eq!(got, vec![(5, "locked_account"), (8, "invalid_client")]);
+
+        let acct = bkeeper.accounts.get(&1).unwrap();
+        assert!(acct.state == AccountState::Active);
+        assert_eq!(acct.state_reason.as_deref(), Some("investigation cleared"));
+        assert!(acct.balances(Currency::USD).total == Decimal::from(1i16));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let today = "type, client, tx, amount
+deposit, 1, 1, 3.0
+deposit, 2, 2, 5.0
+withdrawal, 2, 3, 1.0
+dispute, 2, 3,";
+
+        let mut bkeeper = Bookkeeper::new();
+        bkeeper.process_reader(today.as_bytes()).unwrap();
+
+        let mut buf = Vec::new();
+        bkeeper.save_snapshot(&mut buf).unwrap();
+
+        let tomorrow = "type, client, tx, amount
+dispute, 1, 1,
+resolve, 2, 3,
+deposit, 3, 1, 1.0
+deposit, 3, 4, 1.0";
+
+        let mut bkeeper = Bookkeeper::new();
+        bkeeper.load_snapshot(&buf[..]).unwrap();
+        let mut rejections = RejectionLog::default();
+        bkeeper.process_reader_with_rejections(tomorrow.as_bytes(), &mut rejections).unwrap();
+
+        // the tx ids of yesterday are still taken
+        let got: Vec<_> = rejections.rejections.iter().map(|r| (r.line, r.reason.code())).collect();
+        assert_eq!(got, vec![(4, "duplicate_tx_id")]);
+
+        let acct = bkeeper.accounts.get(&1).unwrap();
+        assert!(acct.balances(Currency::USD).held == Decimal::from(3i16));
+        assert!(acct.balances(Currency::USD).available == Decimal::ZERO);
+
+        let acct = bkeeper.accounts.get(&2).unwrap();
+        assert!(acct.balances(Currency::USD).held == Decimal::ZERO);
+        assert!(acct.balances(Currency::USD).total == Decimal::from(4i16));
+
+        assert!(bkeeper.accounts.get(&3).unwrap().balances(Currency::USD).total == Decimal::from(1i16));
+
+        // the general ledger goes on from yesterday's too: 9 deposited and 1 withdrawn
+        assert_eq!(bkeeper.general_ledger().balance(Currency::USD, LedgerAccount::Clearing), -Decimal::from(8i16));
+        assert!(bkeeper.general_ledger().balance(Currency::USD, LedgerAccount::ChargebackLoss).is_zero());
+        assert!(bkeeper.trial_balance().is_balanced());
+
+        // the history goes on from yesterday's
+        let types: Vec<_> = bkeeper.history(2).unwrap().into_iter().map(|h| h.r#type).collect();
+        assert_eq!(types, vec![TxType::Deposit, TxType::Withdrawal, TxType::Dispute, TxType::Resolve]);
+
+        // a snapshot is of one layout, another version or a missing field is rejected
+        let snapshot = String::from_utf8(buf).unwrap();
+        let other = snapshot.replacen("\"version\":1", "\"version\":2", 1);
+        assert!(matches!(Bookkeeper::new().load_snapshot(other.as_bytes()), Err(SnapshotError::VersionError(2))));
+        let partial = snapshot.replacen("\"events\":", "\"history\":", 1);
+        assert!(matches!(Bookkeeper::new().load_snapshot(partial.as_bytes()), Err(SnapshotError::FormatError(_))));
+    }
+
+    /// Check that a snapshot keeps all that the features add to the ledger: the currency of every bucket and tx, the
+    /// rounding residues, the fees, the insertion order, and the times of the events, so that the limit windows go on
+    #[test]
+    fn test_snapshot_round_trip() {
+        let input = "type, client, tx, amount, currency, timestamp
+deposit, 3, 1, 10.0, EUR, 2026-09-30T10:00:00Z
+deposit, 1, 2, 1.005, EUR, 2026-09-30T11:00:00Z
+deposit, 2, 3, 100.0,,
+withdrawal, 2, 4, 50.0,,
+withdrawal, 3, 5, 6.0, EUR, 2026-10-01T00:00:00Z
+dispute, 3, 5,, EUR, 2026-10-01T01:00:00Z
+deposit, 4, 6, 3.0,,
+dispute, 4, 6,,,
+chargeback, 4, 6,,,";
+        let clock = Arc::new(ManualClock(AtomicU64::new(86400 * 10)));
+        let keeper = || {
+            let mut bkeeper = Bookkeeper::new();
+            let mut precision = Precision::new();
+            precision.set("EUR=2:bankers".parse().unwrap());
+            bkeeper.set_precision(precision);
+            bkeeper.set_fees(FeeSchedule::from_reader(r#"{"fees": [{"type": "withdrawal", "flat": "1"}]}"#.as_bytes()).unwrap());
+            bkeeper.set_limits(Limits::from_reader(r#"{"limits": [{"type": "withdrawal", "window": "day", "max_total": "60"}]}"#.as_bytes()).unwrap());
+            bkeeper.set_clock(clock.clone());
+            bkeeper
+        };
+
+        let mut bkeeper = keeper();
+        let mut rejections = RejectionLog::default();
+        bkeeper.process_reader_with_rejections(input.as_bytes(), &mut rejections).unwrap();
+        assert!(rejections.rejections.is_empty());
+
+        let mut buf = Vec::new();
+        bkeeper.save_snapshot(&mut buf).unwrap();
+        let mut restored = keeper();
+        restored.load_snapshot(&buf[..]).unwrap();
+
+        for order in [ReportOrder::ClientId, ReportOrder::Insertion, ReportOrder::TotalDesc(Currency::EUR)] {
+            assert_eq!(report(&restored, order), report(&bkeeper, order));
+        }
+        assert_eq!(restored.trial_balance(), bkeeper.trial_balance());
+        assert_eq!(restored.general_ledger().residue(Currency::EUR), Decimal::new(5, 3));
+        assert_eq!(restored.audit(), vec![]);
+        let report_at = |bkeeper: &Bookkeeper| {
+            let mut buf = Vec::new();
+            bkeeper.write_report_at(&mut ReportWriter::new(&mut buf, ReportFormat::Csv), ReportOrder::ClientId, "2026-09-30T23:59:59Z".parse().unwrap()).unwrap();
+            String::from_utf8(buf).unwrap()
+        };
+        assert_eq!(report_at(&restored), report_at(&bkeeper));
+
+        // the disputed withdrawal is still in EUR, and the one of client 2 still in today's window
+        let tx = |r#type, client_id, tx_id, amount: Option<i64>| Transaction {
+            r#type,
+            client_id,
+            tx_id,
+            amount: amount.map(Decimal::from),
+            currency: Some(Currency::USD),
+            reason: None,
+            timestamp: None,
+        };
+        assert_eq!(restored.on_tx(&tx(TxType::Resolve, 3, 5, None)), Err(TxError::CurrencyMismatchError));
+        assert_eq!(restored.on_tx(&tx(TxType::Withdrawal, 2, 7, Some(20))), Err(TxError::TotalLimitError { limit: Decimal::from(60), window: "day".parse().unwrap() }));
+    }
+
+    fn report(bkeeper: &Bookkeeper, order: ReportOrder) -> String {
+        let mut buf = Vec::new();
+        bkeeper.write_report(&mut ReportWriter::new(&mut buf, ReportFormat::Csv), order).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn test_report_stable() {
+        let input = "type, client, tx, amount
+deposit, 3, 1, 1.0
+deposit, 1, 2, 5.0
+deposit, 2, 3, 1.0
+deposit, 4, 4, 7.0
+withdrawal, 1, 5, 1.0";
+        // the same transactions of each client, interleaved differently
+        let shuffled = "type, client, tx, amount
+deposit, 4, 4, 7.0
+deposit, 1, 2, 5.0
+deposit, 2, 3, 1.0
+withdrawal, 1, 5, 1.0
+deposit, 3, 1, 1.0";
+
+        let mut bkeeper = Bookkeeper::new();
+        bkeeper.process_reader(input.as_bytes()).unwrap();
+        let mut other = Bookkeeper::new();
+        other.process_reader(shuffled.as_bytes()).unwrap();
+
+        let by_client = "client,currency,available,held,total,fees,locked,state
+1,USD,4.0000,0,4.0000,0,false,active
+2,USD,1.0000,0,1.0000,0,false,active
+3,USD,1.0000,0,1.0000,0,false,active
+4,USD,7.0000,0,7.0000,0,false,active
+";
+        assert_eq!(report(&bkeeper, ReportOrder::ClientId), by_client);
+        assert_eq!(report(&other, ReportOrder::ClientId), by_client);
+        assert_eq!(report(&bkeeper, ReportOrder::TotalDesc(Currency::USD)), report(&other, ReportOrder::TotalDesc(Currency::USD)));
+
+        let ids = |bkeeper: &Bookkeeper, order| report(bkeeper, order).lines().skip(1).map(|l| l[..1].to_string()).collect::<Vec<_>>();
+        assert_eq!(ids(&bkeeper, ReportOrder::TotalDesc(Currency::USD)), vec!["4", "1", "2", "3"]);
+        assert_eq!(ids(&bkeeper, ReportOrder::Insertion), vec!["3", "1", "2", "4"]);
+        assert_eq!(ids(&other, ReportOrder::Insertion), vec!["4", "1", "2", "3"]);
+    }
+
+    #[test]
+    fn test_snapshot_version() {
+        let mut bkeeper = Bookkeeper::new();
+        let err = bkeeper.load_snapshot(&b"{\"version\":0,\"accounts\":[]}"[..]).err().unwrap();
+        assert!(matches!(err, SnapshotError::VersionError(0)));
+    }
+
+    /// Check that every currency is a bucket of its own, and that a dispute can't name another currency than its tx
+    #[test]
+    fn test_currencies() {
+        let input = "type, client, tx, amount, currency
+deposit, 1, 1, 10.0, EUR
+deposit, 1, 2, 5.0,
+withdrawal, 1, 3, 6.0, USD
+withdrawal, 1, 4, 6.0, EUR
+dispute, 1, 4,, GBP
+dispute, 1, 4,, EUR
+deposit, 1, 5, 1.0, EURO
+close, 1, 6,,";
+
+        let mut bkeeper = Bookkeeper::new();
+        let mut rejections = RejectionLog::default();
+        bkeeper.process_reader_with_rejections(input.as_bytes(), &mut rejections).unwrap();
+
+        let got: Vec<_> = rejections.rejections.iter().map(|r| (r.line, r.reason.code())).collect();
+        assert_eq!(got, vec![(4, "invalid_amount"), (6, "currency_mismatch"), (8, "invalid_format"), (9, "invalid_operation")]);
+
+        let acct = bkeeper.accounts.get(&1).unwrap();
+        let d = |n: i16| Decimal::from(n);
+        let eur = acct.balances(Currency::EUR);
+        assert_eq!((eur.available, eur.held, eur.total), (d(4), d(6), d(10)));
+        assert_eq!(acct.balances(Currency::USD).total, d(5));
+        assert_eq!(acct.balances(Currency::GBP).total, Decimal::ZERO);
+
+        assert_eq!(
+            report(&bkeeper, ReportOrder::ClientId),
+            "client,currency,available,held,total,fees,locked,state
+1,EUR,4.0000,6.0000,10.0000,0,false,active
+1,USD,5.0000,0,5.0000,0,false,active
+"
+        );
+        assert_eq!(bkeeper.audit(), vec![]);
+    }
+
+    /// Check that the amounts are rounded by the rule of their currency, and that the residues are totalled
+    #[test]
+    fn test_precision() {
+        let input = "type, client, tx, amount, currency
+deposit, 1, 1, 1.005, EUR
+deposit, 1, 2, 1.015, EUR
+deposit, 2, 3, 10.00009, USD
+withdrawal, 2, 4, 10.00009,
+deposit, 3, 5, 100.5, JPY
+deposit, 3, 6, 100, JPY
+withdrawal, 1, 7, 2.024, EUR";
+
+        let mut precision = Precision::new();
+        for setting in ["EUR=2:bankers", "JPY=0:reject", "4:truncate"] {
+            precision.set(setting.parse().unwrap());
+        }
+
+        let process = |threads| {
+            let mut bkeeper = Bookkeeper::new();
+            bkeeper.set_precision(precision.clone());
+            let mut rejections = RejectionLog::default();
+            bkeeper.process_input_parallel(input.as_bytes(), InputFormat::Csv, &mut rejections, threads).unwrap();
+            let got: Vec<_> = rejections.rejections.iter().map(|r| (r.line, r.reason.code())).collect();
+            assert_eq!(got, vec![(6, "excess_precision")]);
+            bkeeper
+        };
+
+        let bkeeper = process(1);
+        // 1.005 -> 1.00 and 1.015 -> 1.02, which leaves 0.02 from a withdrawal of 2.024 rounded to 2.02.
+        // The withdrawal of 10.00009 is truncated to 10.0000, which is all the funds.
+        assert_eq!(
+            report(&bkeeper, ReportOrder::ClientId),
+            "client,currency,available,held,total,fees,locked,state
+1,EUR,0.00,0,0,0,false,active
+2,USD,0.0000,0,0,0,false,active
+3,JPY,100,0,100,0,false,active
+"
+        );
+
+        let ledger = bkeeper.general_ledger();
+        let d = |n: i64, scale| Decimal::new(n, scale);
+        assert_eq!(ledger.residue(Currency::EUR), d(5, 3) - d(5, 3) - d(4, 3));
+        assert_eq!(ledger.residue(Currency::USD), Decimal::ZERO);
+        assert_eq!(ledger.residue("JPY".parse().unwrap()), Decimal::ZERO);
+
+        let mut buf = Vec::new();
+        bkeeper.trial_balance().write(&mut buf).unwrap();
+        assert!(String::from_utf8(buf).unwrap().contains("EUR,net,0\nEUR,rounding_residue,-0.004\nJPY,"));
+        assert_eq!(bkeeper.audit(), vec![]);
+
+        // the shards keep their residues
+        assert_eq!(process(2).trial_balance(), bkeeper.trial_balance());
+    }
+
+    /// Check that the fees are taken from available, posted to the fee revenue and broken out in the report
+    #[test]
+    fn test_fees() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 100.0
+withdrawal, 1, 2, 50.0
+withdrawal, 1, 3, 48.5
+deposit, 2, 4, 10.0
+deposit, 2, 5, 3.0
+dispute, 2, 5,
+chargeback, 2, 5,";
+        let fees = r#"{"fees": [{"type": "withdrawal", "flat": "0.5", "percent": "1", "min": "1", "max": "20"}, {"type": "chargeback", "flat": "15"}]}"#;
+
+        let process = |threads| {
+            let mut bkeeper = Bookkeeper::new();
+            bkeeper.set_fees(FeeSchedule::from_reader(fees.as_bytes()).unwrap());
+            let mut rejections = RejectionLog::default();
+            bkeeper.process_input_parallel(input.as_bytes(), InputFormat::Csv, &mut rejections, threads).unwrap();
+            // 48.5 and its fee of 1 don't fit in 49
+            let got: Vec<_> = rejections.rejections.iter().map(|r| (r.line, r.reason.code())).collect();
+            assert_eq!(got, vec![(4, "invalid_amount")]);
+            bkeeper
+        };
+
+        let bkeeper = process(1);
+        // the fee of the chargeback is capped by the 10 left
+        assert_eq!(
+            report(&bkeeper, ReportOrder::ClientId),
+            "client,currency,available,held,total,fees,locked,state
+1,USD,49.0000,0,49.0000,1.0000,false,active
+2,USD,0.0000,0.0000,0.0000,10.0000,true,locked
+"
+        );
+        assert_eq!(bkeeper.general_ledger().balance(Currency::USD, LedgerAccount::FeeRevenue), Decimal::from(11));
+        assert!(bkeeper.trial_balance().is_balanced());
+        assert_eq!(bkeeper.audit(), vec![]);
+        assert_eq!(process(2).trial_balance(), bkeeper.trial_balance());
+    }
+
+    /// Check that a deposit/withdrawal over a limit is rejected with the rule it breaks, and that a client can have its own limits
+    #[test]
+    fn test_limits() {
+        let limits = r#"{"limits": [
+            {"type": "withdrawal", "max_amount": "100"},
+            {"type": "withdrawal", "window": "1m", "max_count": 2},
+            {"type": "withdrawal", "window": "day", "max_total": "150"},
+            {"type": "withdrawal", "client": 2, "max_amount": "1000"}
+        ]}"#;
+        let clock = Arc::new(ManualClock(AtomicU64::new(86400 * 10)));
+        let mut bkeeper = Bookkeeper::new();
+        bkeeper.set_limits(Limits::from_reader(limits.as_bytes()).unwrap());
+        bkeeper.set_clock(clock.clone());
+
+        let mut rejections = RejectionLog::default();
+        let mut process = |bkeeper: &mut Bookkeeper, input: &str| bkeeper.process_reader_with_rejections(input.as_bytes(), &mut rejections).unwrap();
+        process(
+            &mut bkeeper,
+            "type, client, tx, amount
+deposit, 1, 1, 1000.0
+deposit, 2, 2, 2000.0
+withdrawal, 1, 3, 100.5
+withdrawal, 1, 4, 50.0
+withdrawal, 1, 5, 50.0
+withdrawal, 1, 6, 10.0
+withdrawal, 2, 7, 500.0
+withdrawal, 2, 8, 500.0
+withdrawal, 2, 9, 1.0",
+        );
+        // a minute later, the count is fine but not the total of the day
+        clock.0.fetch_add(60, Ordering::Relaxed);
+        process(
+            &mut bkeeper,
+            "type, client, tx, amount
+withdrawal, 1, 10, 50.5
+withdrawal, 1, 11, 50.0",
+        );
+        // and the next day it's all fine again
+        clock.0.fetch_add(86400, Ordering::Relaxed);
+        process(
+            &mut bkeeper,
+            "type, client, tx, amount
+withdrawal, 1, 12, 100.0",
+        );
+
+        let got: Vec<_> = rejections.rejections.iter().map(|r| (r.tx_id.unwrap(), r.reason.code(), r.detail.as_str())).collect();
+        assert_eq!(
+            got,
+            vec![
+                (3, "amount_limit", "amount over the limit of 100"),
+                (6, "count_limit", "over the limit of 2 transactions per 60s"),
+                (10, "total_limit", "total over the limit of 150 per day"),
+            ]
+        );
+        assert_eq!(bkeeper.accounts.get(&1).unwrap().balances(Currency::USD).available, Decimal::from(750));
+        assert_eq!(bkeeper.accounts.get(&2).unwrap().balances(Currency::USD).available, Decimal::from(999));
+
+        let withdrawal = Transaction {
+            r#type: TxType::Withdrawal,
+            client_id: 1,
+            tx_id: 13,
+            amount: Some(Decimal::from(60)),
+            currency: None,
+            reason: None,
+            timestamp: None,
+        };
+        assert_eq!(bkeeper.on_tx(&withdrawal), Err(TxError::TotalLimitError { limit: Decimal::from(150), window: "day".parse().unwrap() }));
+
+        // a backdated withdrawal is checked in the window of its time, and doesn't hide the ones of today
+        let backdated = Transaction {
+            tx_id: 14,
+            amount: Some(Decimal::from(10)),
+            timestamp: Some(Timestamp(86400 * 5)),
+            ..withdrawal.clone()
+        };
+        assert_eq!(bkeeper.on_tx(&backdated), Ok(()));
+        assert_eq!(bkeeper.on_tx(&withdrawal), Err(TxError::TotalLimitError { limit: Decimal::from(150), window: "day".parse().unwrap() }));
+    }
+
+    /// Check that a transaction older than the last one of its client is rejected in strict mode, and that one without a
+    /// timestamp gets no time from the clock, so it's never out of order nor makes the later ones so
+    #[test]
+    fn test_strict_time() {
+        let mut bkeeper = Bookkeeper::new();
+        bkeeper.set_strict_time(true);
+        bkeeper.set_clock(Arc::new(ManualClock(AtomicU64::new(1790899200))));
+
+        let csv = "type, client, tx, amount, timestamp
+deposit, 1, 1, 10.0, 2026-09-30T10:00:00Z
+deposit, 2, 2, 5.0, 2026-10-01T09:00:00+02:00
+withdrawal, 1, 3, 4.0, 2026-10-01T00:00:00Z
+deposit, 1, 4, 1.0, 2026-09-30T12:00:00Z
+deposit, 3, 5, 1.0, 2026-09-01T00:00:00Z
+deposit, 1, 6, 1.0,";
+        let ndjson = r#"{"type": "deposit", "client": 1, "tx": 7, "amount": "1", "timestamp": 1790812800}
+{"type": "deposit", "client": 2, "tx": 8, "amount": "1", "timestamp": "2026-10-01T06:59:59Z"}"#;
+        let mut rejections = RejectionLog::default();
+        bkeeper.process_input(csv.as_bytes(), InputFormat::Csv, &mut rejections).unwrap();
+        bkeeper.process_input(ndjson.as_bytes(), InputFormat::JsonLines, &mut rejections).unwrap();
+
+        let got: Vec<_> = rejections.rejections.iter().map(|r| (r.tx_id.unwrap(), r.reason.code(), r.detail.as_str())).collect();
+        assert_eq!(
+            got,
+            vec![
+                (4, "out_of_order", "timestamp before the last one of the client, 2026-10-01T00:00:00Z"),
+                (8, "out_of_order", "timestamp before the last one of the client, 2026-10-01T07:00:00Z"),
+            ]
+        );
+        let times = |client| bkeeper.accounts.get(&client).unwrap().events().iter().map(|e| (e.time, e.accepted)).collect::<Vec<_>>();
+        assert_eq!(times(1), vec![(Some(1790762400), None), (Some(1790812800), None), (None, Some(1790899200)), (Some(1790812800), None)]);
+        assert_eq!(times(3), vec![(Some(1788220800), None)]);
+    }
+
+    /// Check that the report as of a time counts the transactions up to it, even the ones which came in late,
+    /// and that it's ordered by the totals at the time
+    #[test]
+    fn test_report_at() {
+        let input = "type, client, tx, amount, timestamp
+deposit, 1, 1, 10.0, 2026-09-30T10:00:00Z
+deposit, 2, 2, 5.0, 2026-10-01T07:00:00Z
+withdrawal, 1, 3, 4.0, 2026-10-01T00:00:00Z
+deposit, 1, 4, 1.0, 2026-09-30T12:00:00Z
+dispute, 1, 4,, 2026-09-30T20:00:00Z
+freeze, 1, 5,, 2026-10-01T01:00:00Z";
+        let mut bkeeper = Bookkeeper::new();
+        bkeeper.process_reader(input.as_bytes()).unwrap();
+
+        let report_by = |bkeeper: &Bookkeeper, order, time: &str| {
+            let mut buf = Vec::new();
+            bkeeper.write_report_at(&mut ReportWriter::new(&mut buf, ReportFormat::Csv), order, time.parse().unwrap()).unwrap();
+            String::from_utf8(buf).unwrap()
+        };
+        let report_at = |bkeeper: &Bookkeeper, time: &str| report_by(bkeeper, ReportOrder::ClientId, time);
+        let september = report_at(&bkeeper, "2026-09-30T23:59:59Z");
+        assert_eq!(september, "client,currency,available,held,total,fees,locked,state\n1,USD,10.0000,1.0000,11.0000,0,false,active\n");
+        assert_eq!(report_at(&bkeeper, "2026-10-01T00:30:00Z"), "client,currency,available,held,total,fees,locked,state\n1,USD,6.0000,1.0000,7.0000,0,false,active\n");
+        assert_eq!(report_at(&bkeeper, "2026-10-02T00:00:00Z"), report(&bkeeper, ReportOrder::ClientId));
+
+        let input = "type, client, tx, amount, timestamp
+deposit, 1, 1, 10.0, 2026-09-30T10:00:00Z
+deposit, 2, 2, 20.0, 2026-09-30T10:00:00Z
+withdrawal, 2, 3, 15.0, 2026-10-01T00:00:00Z";
+        let mut bkeeper = Bookkeeper::new();
+        bkeeper.process_reader(input.as_bytes()).unwrap();
+        let ids = |time| report_by(&bkeeper, ReportOrder::TotalDesc(Currency::USD), time).lines().skip(1).map(|l| l[..1].to_string()).collect::<Vec<_>>();
+        assert_eq!(ids("2026-09-30T23:59:59Z"), vec!["2", "1"]);
+        assert_eq!(ids("2026-10-01T00:00:00Z"), vec!["1", "2"]);
     }
 }
@@ -0,0 +1,15 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Clock tells the time a transaction is accepted at, in seconds since the unix epoch, see Bookkeeper::set_clock
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+}
+
+/// SystemClock is the time of the system
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+    }
+}
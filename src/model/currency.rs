@@ -0,0 +1,86 @@
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Currency is an ISO 4217 code, e.g., USD. The amounts of a row without a currency are in the default one, USD.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Currency([u8; 3]);
+
+impl Currency {
+    pub const USD: Currency = Currency(*b"USD");
+    pub const EUR: Currency = Currency(*b"EUR");
+    pub const GBP: Currency = Currency(*b"GBP");
+
+    pub fn as_str(&self) -> &str {
+        // only ascii letters get in, see from_str
+        std::str::from_utf8(&self.0).unwrap_or_default()
+    }
+}
+
+impl Default for Currency {
+    fn default() -> Self {
+        Currency::USD
+    }
+}
+
+impl FromStr for Currency {
+    type Err = String;
+
+    /// from_str takes any three letters, in any case
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.as_bytes() {
+            &[a, b, c] if s.bytes().all(|b| b.is_ascii_alphabetic()) => Ok(Currency([a, b, c].map(|b| b.to_ascii_uppercase()))),
+            _ => Err(format!("invalid currency: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+impl fmt::Debug for Currency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S>(&self, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        s.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D>(d: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(d)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::model::Currency;
+
+    #[test]
+    fn test_currency() {
+        assert_eq!("eur".parse::<Currency>().unwrap(), Currency::EUR);
+        assert_eq!(Currency::GBP.to_string(), "GBP");
+        assert_eq!(Currency::default(), Currency::USD);
+        for invalid in ["", "US", "USDT", "U$D", "ÉUR"] {
+            assert!(invalid.parse::<Currency>().is_err(), "{}", invalid);
+        }
+
+        assert_eq!(serde_json::to_string(&Currency::EUR).unwrap(), "\"EUR\"");
+        assert_eq!(serde_json::from_str::<Currency>("\"gbp\"").unwrap(), Currency::GBP);
+        assert!(serde_json::from_str::<Currency>("\"pound\"").is_err());
+    }
+}
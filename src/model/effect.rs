@@ -0,0 +1,66 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use super::{AccountState, Currency, DepositStatus, TxType, WithdrawalStatus};
+
+/// Effect is what an accepted transaction does to an account.
+/// It's computed by Account::prepare without changing anything, and then applied by Account::apply,
+/// so that it can be journaled in between and replayed as is.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Effect {
+    pub client: u16,
+    pub tx: u32,
+    /// the currency of the balances changed
+    pub currency: Currency,
+    /// the changes of the balances
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    /// what was rounded away from the amount of a deposit/withdrawal, signed as the change of the balances:
+    /// a deposit rounded down has a positive residue, a withdrawal rounded down a negative one
+    #[serde(default, skip_serializing_if = "Decimal::is_zero")]
+    pub residue: Decimal,
+    /// the fee charged on the transaction, taken from available, see FeeSchedule
+    #[serde(default, skip_serializing_if = "Decimal::is_zero")]
+    pub fee: Decimal,
+    /// when it happened, in seconds since the unix epoch: the timestamp of the transaction, None if it has none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub time: Option<u64>,
+    /// the time a transaction without a timestamp was accepted at, see Clock. It's only what the windows of the limits
+    /// count it by, it doesn't tell when it happened.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accepted: Option<u64>,
+    pub changes: Vec<Change>,
+}
+
+impl Effect {
+    /// opens_tx checks if it records a new deposit/withdrawal, whose tx id is then taken
+    pub fn opens_tx(&self) -> bool {
+        self.changes.iter().any(|c| matches!(c, Change::Deposit { .. } | Change::Withdrawal { .. }))
+    }
+
+    /// tx_type returns the type of the transaction it's the effect of, which is told by its first change
+    pub fn tx_type(&self) -> TxType {
+        match self.changes.first().expect("an effect always changes something") {
+            Change::Deposit { .. } => TxType::Deposit,
+            Change::Withdrawal { .. } => TxType::Withdrawal,
+            Change::DepositStatus { status: DepositStatus::Disputed } | Change::WithdrawalStatus { status: WithdrawalStatus::Disputed } => TxType::Dispute,
+            Change::DepositStatus { status: DepositStatus::None } | Change::WithdrawalStatus { status: WithdrawalStatus::None } => TxType::Resolve,
+            Change::DepositStatus { status: DepositStatus::ChargedBack } | Change::WithdrawalStatus { status: WithdrawalStatus::ChargedBack } => TxType::ChargeBack,
+            Change::State { state: AccountState::Active, .. } => TxType::Unlock,
+            Change::State { state: AccountState::Frozen, .. } => TxType::Freeze,
+            Change::State { state: AccountState::Closed, .. } => TxType::Close,
+            Change::State { state: AccountState::Locked, .. } => TxType::ChargeBack,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Change {
+    Deposit { amount: Decimal },
+    Withdrawal { amount: Decimal },
+    DepositStatus { status: DepositStatus },
+    WithdrawalStatus { status: WithdrawalStatus },
+    State { state: AccountState, reason: Option<String> },
+}
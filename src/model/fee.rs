@@ -0,0 +1,142 @@
+use std::io;
+
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use super::{Currency, PrecisionRule, RoundingMode, TxType};
+
+/// FeeTier is the fee of the amounts from a threshold on, up to the threshold of the next tier: its flat and percent apply
+/// to the whole amount, not only to the part above the threshold
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeeTier {
+    pub from: Decimal,
+    #[serde(default)]
+    pub flat: Decimal,
+    #[serde(default)]
+    pub percent: Decimal,
+}
+
+/// FeeRule is the fee of a type of transaction, in a currency or in any of them: flat + percent of the amount,
+/// or the ones of the tier of the amount if there are tiers, then capped by min and max
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeeRule {
+    pub r#type: TxType,
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    #[serde(default)]
+    pub flat: Decimal,
+    #[serde(default)]
+    pub percent: Decimal,
+    #[serde(default)]
+    pub tiers: Vec<FeeTier>,
+    #[serde(default)]
+    pub min: Option<Decimal>,
+    #[serde(default)]
+    pub max: Option<Decimal>,
+}
+
+impl FeeRule {
+    /// fee returns the fee on amount, or None if it overflows
+    fn fee(&self, amount: Decimal) -> Option<Decimal> {
+        let (flat, percent) = match self.tiers.iter().filter(|tier| tier.from <= amount).max_by_key(|tier| tier.from) {
+            Some(tier) => (tier.flat, tier.percent),
+            None if self.tiers.is_empty() => (self.flat, self.percent),
+            None => (Decimal::ZERO, Decimal::ZERO),
+        };
+
+        let mut fee = flat.checked_add(amount.checked_mul(percent)? / Decimal::ONE_HUNDRED)?;
+        if let Some(min) = self.min {
+            fee = fee.max(min);
+        }
+        if let Some(max) = self.max {
+            fee = fee.min(max);
+        }
+        Some(fee)
+    }
+}
+
+/// FeeSchedule is the fees charged on the accepted transactions, see Bookkeeper::set_fees
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeeSchedule {
+    pub fees: Vec<FeeRule>,
+}
+
+impl FeeSchedule {
+    pub fn new() -> FeeSchedule {
+        FeeSchedule::default()
+    }
+
+    /// from_reader reads a schedule saved as JSON, e.g., {"fees": [{"type": "withdrawal", "flat": "0.5", "percent": "1", "max": "20"}]}.
+    /// A fee is only charged on funds, so an admin transaction can't have one.
+    pub fn from_reader<R: io::Read>(r: R) -> io::Result<FeeSchedule> {
+        let schedule: FeeSchedule = serde_json::from_reader(r)?;
+        for rule in &schedule.fees {
+            let mut amounts = [rule.flat, rule.percent].into_iter().chain(rule.min).chain(rule.max).chain(rule.tiers.iter().flat_map(|t| [t.flat, t.percent]));
+            if rule.r#type.is_admin() || amounts.any(|d| d < Decimal::ZERO) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid fee: {:?}", rule)));
+            }
+        }
+        Ok(schedule)
+    }
+
+    /// rule returns the rule of r#type in currency, the one of the currency over the one of any currency
+    pub fn rule(&self, r#type: &TxType, currency: Currency) -> Option<&FeeRule> {
+        let rules = || self.fees.iter().filter(|rule| rule.r#type == *r#type);
+        rules().find(|rule| rule.currency == Some(currency)).or_else(|| rules().find(|rule| rule.currency.is_none()))
+    }
+
+    /// fee returns the fee of a transaction of r#type on amount in currency, rounded half-up to the places of precision,
+    /// or None if it overflows
+    pub fn fee(&self, r#type: &TxType, currency: Currency, amount: Decimal, precision: PrecisionRule) -> Option<Decimal> {
+        let Some(rule) = self.rule(r#type, currency) else {
+            return Some(Decimal::ZERO);
+        };
+
+        let precision = match precision.mode {
+            RoundingMode::Reject => PrecisionRule { mode: RoundingMode::HalfUp, ..precision },
+            _ => precision,
+        };
+        precision.round(rule.fee(amount)?).ok().map(|(fee, _)| fee)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::Decimal;
+
+    use crate::model::{Currency, FeeSchedule, PrecisionRule, TxType};
+
+    const SCHEDULE: &str = r#"{"fees": [
+        {"type": "withdrawal", "flat": "0.5", "percent": "1", "min": "1", "max": "20"},
+        {"type": "withdrawal", "currency": "EUR", "tiers": [{"from": "0", "percent": "2"}, {"from": "1000", "flat": "5", "percent": "1"}]},
+        {"type": "chargeback", "flat": "15"}
+    ]}"#;
+
+    #[test]
+    fn test_fees() {
+        let schedule = FeeSchedule::from_reader(SCHEDULE.as_bytes()).unwrap();
+        let d = |n: i64, scale| Decimal::new(n, scale);
+        let fee = |r#type, currency, amount| schedule.fee(&r#type, currency, amount, PrecisionRule::default()).unwrap().to_string();
+
+        // 0.5 + 1% capped by min and max
+        assert_eq!(fee(TxType::Withdrawal, Currency::USD, d(10, 0)), "1.0000");
+        assert_eq!(fee(TxType::Withdrawal, Currency::USD, d(100, 0)), "1.5000");
+        assert_eq!(fee(TxType::Withdrawal, Currency::USD, d(5000, 0)), "20.0000");
+        // the tier of the amount
+        assert_eq!(fee(TxType::Withdrawal, Currency::EUR, d(999, 0)), "19.9800");
+        // the rate of the tier is on the whole amount, so the fee drops at the boundary
+        assert_eq!(fee(TxType::Withdrawal, Currency::EUR, d(9999999, 4)), "20.0000");
+        assert_eq!(fee(TxType::Withdrawal, Currency::EUR, d(1000, 0)), "15.0000");
+        assert_eq!(fee(TxType::Withdrawal, Currency::EUR, d(2000, 0)), "25.0000");
+        assert_eq!(fee(TxType::Withdrawal, Currency::EUR, d(12345, 5)), "0.0025");
+        assert_eq!(fee(TxType::ChargeBack, Currency::GBP, d(1, 0)), "15.0000");
+        assert_eq!(fee(TxType::Deposit, Currency::USD, d(1, 0)), "0");
+
+        for invalid in [r#"{"fees": [{"type": "close", "flat": "1"}]}"#, r#"{"fees": [{"type": "deposit", "flat": "-1"}]}"#, r#"{"fees": [{"type": "deposit", "fee": "1"}]}"#] {
+            assert!(FeeSchedule::from_reader(invalid.as_bytes()).is_err(), "{}", invalid);
+        }
+    }
+}
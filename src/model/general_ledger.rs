@@ -0,0 +1,356 @@
+use std::{collections::BTreeMap, io};
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use super::{Account, Change, Currency, Effect};
+
+/// LedgerAccount is an account of the general ledger. The customer accounts are what is owed to the clients,
+/// clearing is the money in transit with the outside world, chargeback loss is what the disputed withdrawals cost,
+/// and fee revenue is what the house earned from the fees.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LedgerAccount {
+    CustomerAvailable,
+    CustomerHeld,
+    Clearing,
+    ChargebackLoss,
+    FeeRevenue,
+}
+
+const ACCOUNTS: [LedgerAccount; 5] = [
+    LedgerAccount::CustomerAvailable,
+    LedgerAccount::CustomerHeld,
+    LedgerAccount::Clearing,
+    LedgerAccount::ChargebackLoss,
+    LedgerAccount::FeeRevenue,
+];
+
+/// Posting is an entry of a transaction on a ledger account: a positive amount is a credit, a negative one a debit.
+/// The postings of a transaction always net to zero, in its currency.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Posting {
+    pub client: u16,
+    pub tx: u32,
+    pub currency: Currency,
+    pub account: LedgerAccount,
+    pub amount: Decimal,
+}
+
+/// exact_add returns a + b, or None if it overflows or may be rounded, which a Decimal does beyond 28 or 29 significant digits.
+/// A sum is rounded by lowering its scale, it's exact if it still has the digits of the operands, without their trailing zeros.
+pub(crate) fn exact_add(a: Decimal, b: Decimal) -> Option<Decimal> {
+    let sum = a.checked_add(b)?;
+    (sum.scale() >= a.normalize().scale().max(b.normalize().scale())).then_some(sum)
+}
+
+impl Effect {
+    /// postings returns the balanced entries of the effect. The customer ones move available and held,
+    /// and the change of the total goes to clearing, or to chargeback loss for a disputed withdrawal.
+    /// The fee is a separate posting from customer available to fee revenue. An admin transaction posts nothing.
+    pub fn postings(&self) -> impl Iterator<Item = Posting> + '_ {
+        let counter = if self.changes.iter().any(|c| matches!(c, Change::WithdrawalStatus { .. })) {
+            LedgerAccount::ChargebackLoss
+        } else {
+            LedgerAccount::Clearing
+        };
+
+        let fee = [(LedgerAccount::CustomerAvailable, -self.fee), (LedgerAccount::FeeRevenue, self.fee)];
+        [(LedgerAccount::CustomerAvailable, self.available), (LedgerAccount::CustomerHeld, self.held), (counter, -self.total)]
+            .into_iter()
+            .chain(fee)
+            .filter(|(_, amount)| !amount.is_zero())
+            .map(|(account, amount)| Posting {
+                client: self.client,
+                tx: self.tx,
+                currency: self.currency,
+                account,
+                amount,
+            })
+    }
+}
+
+/// GeneralLedger keeps the balances of the ledger accounts in every currency, summed over all the clients,
+/// and the total rounding residue of every currency, which is not posted, see Effect::residue
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GeneralLedger {
+    currencies: BTreeMap<Currency, BTreeMap<LedgerAccount, Decimal>>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    residues: BTreeMap<Currency, Decimal>,
+}
+
+impl GeneralLedger {
+    pub fn new() -> GeneralLedger {
+        GeneralLedger::default()
+    }
+
+    /// post books the postings of effect, and adds its rounding residue
+    pub fn post(&mut self, effect: &Effect) {
+        for posting in effect.postings() {
+            self.add(posting.currency, posting.account, posting.amount);
+        }
+        if !effect.residue.is_zero() {
+            *self.residues.entry(effect.currency).or_default() += effect.residue;
+        }
+    }
+
+    /// can_post tells if effect can be posted. The balances sum all the clients, so they may overflow even if the account doesn't.
+    pub fn can_post(&self, effect: &Effect) -> bool {
+        self.checked_balances(effect.currency, effect.postings().map(|p| (p.account, p.amount))).is_some()
+    }
+
+    /// balance returns the balance of account in currency, a credit balance is positive
+    pub fn balance(&self, currency: Currency, account: LedgerAccount) -> Decimal {
+        self.currencies.get(&currency).and_then(|balances| balances.get(&account)).copied().unwrap_or_default()
+    }
+
+    /// residue returns the total rounding residue of currency, i.e., what the transactions asked for less what was booked
+    pub fn residue(&self, currency: Currency) -> Decimal {
+        self.residues.get(&currency).copied().unwrap_or_default()
+    }
+
+    /// currencies returns the currencies posted to, or the default one if there is none
+    pub fn currencies(&self) -> Vec<Currency> {
+        match self.currencies.is_empty() {
+            true => vec![Currency::default()],
+            false => self.currencies.keys().copied().collect(),
+        }
+    }
+
+    pub fn trial_balance(&self) -> TrialBalance {
+        TrialBalance {
+            balances: self
+                .currencies()
+                .into_iter()
+                .flat_map(|currency| ACCOUNTS.into_iter().map(move |account| (currency, account)))
+                .map(|(currency, account)| (currency, account, self.balance(currency, account)))
+                .collect(),
+            residues: self.residues.iter().map(|(&currency, &residue)| (currency, residue)).filter(|(_, residue)| !residue.is_zero()).collect(),
+        }
+    }
+
+    /// merge adds the balances of other, e.g., of a shard of the parallel engine. It changes nothing if a balance would overflow or be rounded.
+    pub(crate) fn merge(&mut self, other: &GeneralLedger) -> io::Result<()> {
+        let mut merged = Vec::new();
+        for (&currency, balances) in &other.currencies {
+            let sums = self
+                .checked_balances(currency, ACCOUNTS.map(|account| (account, other.balance(currency, account))))
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("the {} general ledger overflows", currency)))?;
+            let known = self.currencies.get(&currency);
+            for (account, balance) in ACCOUNTS.into_iter().zip(sums) {
+                if balances.contains_key(&account) || known.is_some_and(|known| known.contains_key(&account)) {
+                    merged.push((currency, account, balance));
+                }
+            }
+        }
+
+        for (currency, account, balance) in merged {
+            self.currencies.entry(currency).or_default().insert(account, balance);
+        }
+        for (&currency, &residue) in &other.residues {
+            *self.residues.entry(currency).or_default() += residue;
+        }
+        Ok(())
+    }
+
+    /// checked_balances returns the balances in currency after postings, added one by one as post does, or None if one overflows
+    /// or is rounded. The trial balance must be netted exactly too, so every partial sum of the net is checked as well.
+    fn checked_balances<I>(&self, currency: Currency, postings: I) -> Option<[Decimal; 5]>
+    where
+        I: IntoIterator<Item = (LedgerAccount, Decimal)>,
+    {
+        let mut balances = ACCOUNTS.map(|account| self.balance(currency, account));
+        for (account, amount) in postings {
+            let i = ACCOUNTS.iter().position(|&a| a == account)?;
+            balances[i] = exact_add(balances[i], amount)?;
+        }
+        balances.iter().try_fold(Decimal::ZERO, |net, &balance| exact_add(net, balance))?;
+
+        Some(balances)
+    }
+
+    fn add(&mut self, currency: Currency, account: LedgerAccount, amount: Decimal) {
+        *self.currencies.entry(currency).or_default().entry(account).or_default() += amount;
+    }
+}
+
+/// LedgerBound bounds the balances of a general ledger by everything which can be posted to it: its balances, the amounts
+/// of the histories, which may be disputed, and the deposits/withdrawals to come. While the bound is far enough from
+/// Decimal::MAX, at the scale of the amounts, no balance can overflow or be rounded, whatever the order of the postings.
+pub(crate) struct LedgerBound {
+    volume: Decimal,
+    limit: Decimal,
+}
+
+impl LedgerBound {
+    /// new returns the bound of ledger and of the histories of accounts, whose amounts are rounded to at most places
+    pub(crate) fn new<'a, I>(ledger: &GeneralLedger, accounts: I, places: u32) -> LedgerBound
+    where
+        I: IntoIterator<Item = &'a Account>,
+    {
+        let balances = ledger.currencies.values().flat_map(|balances| balances.values().copied());
+        let amounts = accounts.into_iter().flat_map(|acct| acct.events()).filter_map(|e| e.amount);
+
+        let mut volume = Some(Decimal::ZERO);
+        let mut places = places;
+        for amount in balances.chain(amounts) {
+            volume = volume.and_then(|volume| volume.checked_add(amount.abs()));
+            places = places.max(amount.scale());
+        }
+
+        // a balance is at most a few times the volume, and the net of the trial balance at most the sum of the balances
+        LedgerBound {
+            volume: volume.unwrap_or(Decimal::MAX),
+            limit: Decimal::MAX / Decimal::from(32) / Decimal::from_i128_with_scale(10i128.pow(places.min(28)), 0),
+        }
+    }
+
+    /// add adds the amount of a deposit/withdrawal, plus what its rounding may add, and tells if the bound still holds
+    pub(crate) fn add(&mut self, amount: Decimal) -> bool {
+        self.volume = amount.abs().checked_add(Decimal::ONE).and_then(|amount| self.volume.checked_add(amount)).unwrap_or(Decimal::MAX);
+        self.volume <= self.limit
+    }
+}
+
+/// TrialBalance lists the balance of every ledger account in every currency, which always net to zero in each currency
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrialBalance {
+    pub balances: Vec<(Currency, LedgerAccount, Decimal)>,
+    /// the nonzero rounding residues, which are not part of the balances
+    pub residues: Vec<(Currency, Decimal)>,
+}
+
+impl TrialBalance {
+    /// nets returns what the balances of every currency net to, in order.
+    /// The customer accounts are summed first: they are checked to add up, and the rest has the opposite sign.
+    pub fn nets(&self) -> Vec<(Currency, Decimal)> {
+        let mut nets: Vec<(Currency, Decimal)> = Vec::new();
+        for &(currency, _, amount) in &self.balances {
+            match nets.last_mut() {
+                Some((last, net)) if *last == currency => *net += amount,
+                _ => nets.push((currency, amount)),
+            }
+        }
+        nets
+    }
+
+    /// is_balanced checks that every currency nets to zero
+    pub fn is_balanced(&self) -> bool {
+        self.nets().iter().all(|(_, net)| net.is_zero())
+    }
+
+    /// write writes the trial balance to w as csv, with a net row after the accounts of every currency,
+    /// and then a rounding_residue row if it has a residue
+    pub fn write<W: io::Write>(&self, w: W) -> io::Result<()> {
+        let mut w = csv::Writer::from_writer(w);
+        w.write_record(["currency", "account", "balance"])?;
+        let nets = self.nets();
+        for (currency, net) in &nets {
+            for (_, account, amount) in self.balances.iter().filter(|(c, _, _)| c == currency) {
+                w.serialize((currency, account, amount))?;
+            }
+            w.serialize((currency, "net", net))?;
+            for (_, residue) in self.residues.iter().filter(|(c, _)| c == currency) {
+                w.serialize((currency, "rounding_residue", residue))?;
+            }
+        }
+        w.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::Decimal;
+
+    use super::exact_add;
+    use crate::model::{Bookkeeper, Currency, LedgerAccount};
+
+    #[test]
+    fn test_exact_add() {
+        let d = |n: i64, scale| Decimal::new(n, scale);
+        assert_eq!(exact_add(d(15, 1), d(25, 2)), Some(d(175, 2)));
+        assert_eq!(exact_add(d(15, 1), Decimal::ZERO), Some(d(15, 1)));
+        assert_eq!(exact_add(Decimal::MAX, Decimal::ONE), None);
+        assert_eq!(exact_add(Decimal::MAX, d(-10000, 4)), Some(Decimal::MAX - Decimal::ONE));
+        // 29 digits can't be kept, whatever the order
+        let big = Decimal::from_i128_with_scale(45_939_181_145_354_817_711_963_917, 0);
+        assert_eq!(exact_add(big, d(-35_668_509_049, 4)), None);
+        assert_eq!(exact_add(d(-35_668_509_049, 4), big), None);
+    }
+
+    #[test]
+    fn test_trial_balance() {
+        let input = "type, client, tx, amount
+deposit, 1, 1, 10.0
+withdrawal, 1, 2, 4.0
+dispute, 1, 2,
+chargeback, 1, 2,
+deposit, 2, 3, 5.0
+dispute, 2, 3,
+deposit, 3, 4, 2.0
+dispute, 3, 4,
+chargeback, 3, 4,
+deposit, 4, 5, 1.0
+withdrawal, 4, 6, 0.5
+dispute, 4, 6,
+resolve, 4, 6,";
+
+        let mut bkeeper = Bookkeeper::new();
+        bkeeper.process_reader(input.as_bytes()).unwrap();
+
+        let ledger = bkeeper.general_ledger();
+        let d = |n: i64, scale| Decimal::new(n, scale);
+        assert_eq!(ledger.balance(Currency::USD, LedgerAccount::CustomerAvailable), d(105, 1));
+        assert_eq!(ledger.balance(Currency::USD, LedgerAccount::CustomerHeld), d(5, 0));
+        // 18 deposited, 2 charged back, 4.5 withdrawn of which 4 came back as a chargeback loss
+        assert_eq!(ledger.balance(Currency::USD, LedgerAccount::Clearing), d(-115, 1));
+        assert_eq!(ledger.balance(Currency::USD, LedgerAccount::ChargebackLoss), d(-4, 0));
+
+        let trial_balance = bkeeper.trial_balance();
+        assert!(trial_balance.is_balanced());
+
+        // the customer ledger accounts are the sum of the accounts
+        let available: Decimal = bkeeper.accounts.values().map(|acct| acct.balances(Currency::USD).available).sum();
+        assert_eq!(available, ledger.balance(Currency::USD, LedgerAccount::CustomerAvailable));
+        for b in bkeeper.accounts.values().map(|acct| acct.balances(Currency::USD)) {
+            assert_eq!(b.total, b.available + b.held);
+        }
+
+        let mut buf = Vec::new();
+        trial_balance.write(&mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "currency,account,balance
+USD,customer_available,10.5000
+USD,customer_held,5.0000
+USD,clearing,-11.5000
+USD,chargeback_loss,-4.0000
+USD,fee_revenue,0
+USD,net,0
+"
+        );
+    }
+
+    /// Check that every currency has its own ledger accounts, which net to zero on their own
+    #[test]
+    fn test_trial_balance_currencies() {
+        let input = "type, client, tx, amount, currency
+deposit, 1, 1, 10.0, EUR
+deposit, 1, 2, 3.0,
+withdrawal, 1, 3, 4.0, eur
+deposit, 2, 4, 2.0, GBP
+dispute, 2, 4,,";
+
+        let mut bkeeper = Bookkeeper::new();
+        bkeeper.process_reader(input.as_bytes()).unwrap();
+
+        let trial_balance = bkeeper.trial_balance();
+        let nets: Vec<_> = trial_balance.nets().into_iter().map(|(currency, net)| (currency, net.is_zero())).collect();
+        assert_eq!(nets, vec![(Currency::EUR, true), (Currency::GBP, true), (Currency::USD, true)]);
+
+        let ledger = bkeeper.general_ledger();
+        assert_eq!(ledger.balance(Currency::EUR, LedgerAccount::CustomerAvailable), Decimal::from(6));
+        assert_eq!(ledger.balance(Currency::USD, LedgerAccount::Clearing), Decimal::from(-3));
+        assert_eq!(ledger.balance(Currency::GBP, LedgerAccount::CustomerHeld), Decimal::from(2));
+    }
+}
@@ -0,0 +1,449 @@
+use std::{
+    io::{self, BufRead, Read},
+    mem,
+    path::Path,
+    str::{self, FromStr},
+};
+
+use serde_json::Value;
+
+use super::Transaction;
+
+/// InputFormat is the format of a transaction feed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    Csv,
+    /// newline-delimited JSON, one transaction object per line with the same fields as the csv
+    JsonLines,
+}
+
+impl InputFormat {
+    /// from_path guesses the format from the file extension, defaulting to csv
+    pub fn from_path(path: &Path) -> InputFormat {
+        match path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase()).as_deref() {
+            Some("ndjson" | "jsonl" | "json") => InputFormat::JsonLines,
+            _ => InputFormat::Csv,
+        }
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "csv" => Ok(InputFormat::Csv),
+            "ndjson" | "jsonl" | "json" => Ok(InputFormat::JsonLines),
+            _ => Err(format!("unknown input format: {}", s)),
+        }
+    }
+}
+
+/// InputRecord is a record read from a feed, which may or may not be a valid transaction
+pub struct InputRecord {
+    pub line: u64,
+    /// the record as it was read
+    pub raw: String,
+    /// best effort, for reporting a malformed record
+    pub client_id: Option<u16>,
+    /// best effort, for reporting a malformed record
+    pub tx_id: Option<u32>,
+    /// the transaction, or why the record is not one
+    pub tx: Result<Transaction, String>,
+}
+
+/// TxSource reads the records of a feed in order
+pub trait TxSource {
+    /// next_record returns None at the end of the feed. An error means the feed can't be read any more.
+    fn next_record(&mut self) -> io::Result<Option<InputRecord>>;
+}
+
+/// CsvHeader is the header row of a csv feed, which says where the fields are
+pub struct CsvHeader {
+    headers: csv::StringRecord,
+    client_idx: Option<usize>,
+    tx_idx: Option<usize>,
+    reason_idx: Option<usize>,
+}
+
+impl CsvHeader {
+    pub fn new(headers: &csv::StringRecord) -> CsvHeader {
+        let headers = trim_string_record(headers, None);
+        let position = |name| headers.iter().position(|h| h == name);
+
+        CsvHeader {
+            client_idx: position("client"),
+            tx_idx: position("tx"),
+            reason_idx: position("reason"),
+            headers,
+        }
+    }
+
+    /// record decodes raw_record, read at line from the text raw
+    pub fn record(&self, line: u64, raw: String, raw_record: &csv::StringRecord) -> InputRecord {
+        let trimed_raw_record = trim_string_record(raw_record, self.reason_idx);
+        InputRecord {
+            line,
+            raw,
+            client_id: parse_field(&trimed_raw_record, self.client_idx),
+            tx_id: parse_field(&trimed_raw_record, self.tx_idx),
+            tx: trimed_raw_record.deserialize(Some(&self.headers)).map_err(|e| e.to_string()),
+        }
+    }
+
+    /// invalid returns the record of raw_record, which is not valid UTF-8, read at line from the text raw
+    fn invalid(&self, line: u64, raw: String, raw_record: &csv::ByteRecord, e: String) -> InputRecord {
+        InputRecord {
+            line,
+            raw,
+            client_id: parse_byte_field(raw_record, self.client_idx),
+            tx_id: parse_byte_field(raw_record, self.tx_idx),
+            tx: Err(e),
+        }
+    }
+}
+
+/// Recorder keeps the bytes read from r from offset on, so that the text of a record can be taken back as it was
+struct Recorder<R: Read> {
+    r: R,
+    buf: Vec<u8>,
+    offset: u64,
+    /// the line at offset
+    line: u64,
+}
+
+impl<R: Read> Read for Recorder<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let n = self.r.read(out)?;
+        self.buf.extend_from_slice(&out[..n]);
+        Ok(n)
+    }
+}
+
+impl<R: Read> Recorder<R> {
+    /// take returns the line and the text of the record between the byte positions start and end, without its line breaks,
+    /// and forgets what is before end. The line is counted here, as the csv reader counts a \r\n break as the start of the next record.
+    fn take(&mut self, start: u64, end: u64) -> (u64, String) {
+        let (start, end) = ((start - self.offset) as usize, (end - self.offset) as usize);
+        let breaks = |bytes: &[u8]| bytes.iter().filter(|&&b| b == b'\n').count() as u64;
+        let text = &self.buf[start..end];
+        let first = text.iter().position(|&b| b != b'\r' && b != b'\n').unwrap_or(text.len());
+        let line = self.line + breaks(&self.buf[..start]) + breaks(&text[..first]);
+        let raw = String::from_utf8_lossy(&text[first..]).trim_end_matches(['\r', '\n']).to_string();
+
+        self.line += breaks(&self.buf[..end]);
+        self.buf.drain(..end);
+        self.offset += end as u64;
+        (line, raw)
+    }
+}
+
+/// CsvSource reads a csv with a header row, spaces in the fields are ignored. A row which is not valid UTF-8 is malformed.
+pub struct CsvSource<R: Read> {
+    reader: csv::Reader<Recorder<R>>,
+    header: CsvHeader,
+    raw_record: csv::ByteRecord,
+}
+
+impl<R: Read> CsvSource<R> {
+    pub fn new(r: R) -> io::Result<CsvSource<R>> {
+        let recorder = Recorder {
+            r,
+            buf: Vec::new(),
+            offset: 0,
+            line: 1,
+        };
+        let mut reader = csv_reader_builder().from_reader(recorder);
+        let header = CsvHeader::new(reader.headers()?);
+
+        Ok(CsvSource {
+            reader,
+            header,
+            raw_record: csv::ByteRecord::new(),
+        })
+    }
+}
+
+impl<R: Read> TxSource for CsvSource<R> {
+    fn next_record(&mut self) -> io::Result<Option<InputRecord>> {
+        if !self.reader.read_byte_record(&mut self.raw_record)? {
+            return Ok(None);
+        }
+
+        let start = self.raw_record.position().map_or(0, |p| p.byte());
+        let end = self.reader.position().byte();
+        let (line, raw) = self.reader.get_mut().take(start, end);
+
+        // the record is moved back and forth, so that its buffer is reused
+        let (record, raw_record) = match csv::StringRecord::from_byte_record(mem::take(&mut self.raw_record)) {
+            Ok(raw_record) => (self.header.record(line, raw, &raw_record), raw_record.into_byte_record()),
+            Err(e) => {
+                let detail = e.utf8_error().to_string();
+                let raw_record = e.into_byte_record();
+                (self.header.invalid(line, raw, &raw_record, detail), raw_record)
+            }
+        };
+        self.raw_record = raw_record;
+        Ok(Some(record))
+    }
+}
+
+/// LineDecoder decodes a feed line by line, for a feed which is not read from a file, e.g., a socket
+pub enum LineDecoder {
+    Csv(CsvHeader),
+    JsonLines,
+}
+
+impl LineDecoder {
+    /// detect guesses the format from the first line of a feed: a JSON object, which is a record to decode,
+    /// or the header row of a csv
+    pub fn detect(first: &str) -> io::Result<LineDecoder> {
+        if first.trim_start().starts_with('{') {
+            return Ok(LineDecoder::JsonLines);
+        }
+
+        let mut reader = csv_reader_builder().has_headers(false).from_reader(first.as_bytes());
+        let mut headers = csv::StringRecord::new();
+        reader.read_record(&mut headers)?;
+        Ok(LineDecoder::Csv(CsvHeader::new(&headers)))
+    }
+
+    /// decode decodes raw, the content of line without its line break
+    pub fn decode(&self, line: u64, raw: &str) -> InputRecord {
+        match self {
+            LineDecoder::Csv(header) => {
+                let mut reader = csv_reader_builder().has_headers(false).from_reader(raw.as_bytes());
+                let mut raw_record = csv::StringRecord::new();
+                match reader.read_record(&mut raw_record) {
+                    Ok(_) => header.record(line, raw.to_string(), &raw_record),
+                    Err(e) => InputRecord {
+                        line,
+                        raw: raw.to_string(),
+                        client_id: None,
+                        tx_id: None,
+                        tx: Err(e.to_string()),
+                    },
+                }
+            }
+            LineDecoder::JsonLines => parse_json_record(line, raw.trim()),
+        }
+    }
+}
+
+/// csv_reader_builder is flexible, so that a row with a wrong number of fields is rejected rather than aborting the whole feed
+fn csv_reader_builder() -> csv::ReaderBuilder {
+    let mut builder = csv::ReaderBuilder::new();
+    builder.trim(csv::Trim::All).flexible(true);
+    builder
+}
+
+/// JsonLinesSource reads one transaction object per line, blank lines are skipped. A line which is not valid UTF-8 is malformed.
+pub struct JsonLinesSource<R: BufRead> {
+    reader: R,
+    line: u64,
+    buf: Vec<u8>,
+}
+
+impl<R: BufRead> JsonLinesSource<R> {
+    pub fn new(reader: R) -> JsonLinesSource<R> {
+        JsonLinesSource {
+            reader,
+            line: 0,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl<R: BufRead> TxSource for JsonLinesSource<R> {
+    fn next_record(&mut self) -> io::Result<Option<InputRecord>> {
+        loop {
+            self.buf.clear();
+            if self.reader.read_until(b'\n', &mut self.buf)? == 0 {
+                return Ok(None);
+            }
+
+            self.line += 1;
+            let raw = match str::from_utf8(&self.buf) {
+                Ok(raw) => raw.trim(),
+                Err(e) => {
+                    return Ok(Some(InputRecord {
+                        line: self.line,
+                        raw: String::from_utf8_lossy(&self.buf).trim().to_string(),
+                        client_id: None,
+                        tx_id: None,
+                        tx: Err(e.to_string()),
+                    }))
+                }
+            };
+            if !raw.is_empty() {
+                return Ok(Some(parse_json_record(self.line, raw)));
+            }
+        }
+    }
+}
+
+fn parse_json_record(line: u64, raw: &str) -> InputRecord {
+    match serde_json::from_str(raw) {
+        Ok(value) => InputRecord::from_json(line, raw.to_string(), value),
+        Err(e) => InputRecord {
+            line,
+            raw: raw.to_string(),
+            client_id: None,
+            tx_id: None,
+            tx: Err(e.to_string()),
+        },
+    }
+}
+
+impl InputRecord {
+    /// from_json decodes a transaction object, raw is the text it was parsed from
+    pub fn from_json(line: u64, raw: String, mut value: Value) -> InputRecord {
+        let client_id = value.get("client").and_then(Value::as_u64).and_then(|id| id.try_into().ok());
+        let tx_id = value.get("tx").and_then(Value::as_u64).and_then(|id| id.try_into().ok());
+
+        // amounts are decimal strings in the csv, a JSON number is taken by its text, e.g., 0.1 is exactly 0.1
+        if let Some(amount) = value.get_mut("amount") {
+            if let Value::Number(n) = amount {
+                *amount = Value::String(n.to_string());
+            }
+        }
+
+        InputRecord {
+            line,
+            raw,
+            client_id,
+            tx_id,
+            tx: serde_json::from_value(value).map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// parse_field parses the field at idx, if any, for reporting a row that may be malformed
+fn parse_field<T: FromStr>(s: &csv::StringRecord, idx: Option<usize>) -> Option<T> {
+    s.get(idx?)?.parse().ok()
+}
+
+/// parse_byte_field is parse_field on a record which may not be valid UTF-8
+fn parse_byte_field<T: FromStr>(s: &csv::ByteRecord, idx: Option<usize>) -> Option<T> {
+    str::from_utf8(s.get(idx?)?).ok()?.trim().parse().ok()
+}
+
+/// trim_string_record removes all the spaces in the input, except in the free text field at keep_idx, e.g., the admin reason
+fn trim_string_record(s: &csv::StringRecord, keep_idx: Option<usize>) -> csv::StringRecord {
+    let mut trimed_string_record = csv::StringRecord::new();
+    for (i, field) in s.iter().enumerate() {
+        let mut f = field.to_string();
+        if Some(i) != keep_idx {
+            f.retain(|c| !c.is_whitespace());
+        }
+        trimed_string_record.push_field(&f[..]);
+    }
+    trimed_string_record
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use rust_decimal::Decimal;
+    use std::str::FromStr;
+
+    use crate::model::{CsvSource, InputFormat, JsonLinesSource, LineDecoder, TxSource, TxType};
+
+    #[test]
+    fn test_format_from_path() {
+        assert_eq!(InputFormat::from_path(Path::new("a.csv")), InputFormat::Csv);
+        assert_eq!(InputFormat::from_path(Path::new("a")), InputFormat::Csv);
+        assert_eq!(InputFormat::from_path(Path::new("a.NDJSON")), InputFormat::JsonLines);
+        assert_eq!(InputFormat::from_path(Path::new("a.jsonl")), InputFormat::JsonLines);
+    }
+
+    #[test]
+    fn test_line_decoder() {
+        let csv = LineDecoder::detect("type, client, tx, amount, reason").unwrap();
+        let tx = csv.decode(2, "freeze, 1, 3,, kyc review").tx.unwrap();
+        assert!(tx.r#type == TxType::Freeze);
+        assert_eq!(tx.reason.as_deref(), Some("kyc review"));
+
+        let record = csv.decode(3, "deposit, x, 4, 1.0");
+        assert!(record.tx.is_err());
+        assert_eq!((record.client_id, record.tx_id), (None, Some(4)));
+
+        let first = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 1.5}"#;
+        let json = LineDecoder::detect(first).unwrap();
+        assert!(matches!(json, LineDecoder::JsonLines));
+        assert_eq!(json.decode(1, first).tx.unwrap().amount, Some(Decimal::new(15, 1)));
+    }
+
+    /// Check that a csv record is kept as it was read, with its spaces and quotes, without its line break
+    #[test]
+    fn test_csv_raw() {
+        let input = "type, client, tx, amount, reason\r\nfreeze, 1, 3,,\"kyc, review\"\r\n\r\ndeposit,  x , 4, 1.0\ndeposit, 1, 5, 2.0";
+        let mut source = CsvSource::new(input.as_bytes()).unwrap();
+
+        let record = source.next_record().unwrap().unwrap();
+        assert_eq!((record.line, record.raw.as_str()), (2, "freeze, 1, 3,,\"kyc, review\""));
+        assert_eq!(record.tx.unwrap().reason.as_deref(), Some("kyc, review"));
+
+        let record = source.next_record().unwrap().unwrap();
+        assert_eq!((record.line, record.raw.as_str()), (4, "deposit,  x , 4, 1.0"));
+        assert!(record.tx.is_err());
+
+        let record = source.next_record().unwrap().unwrap();
+        assert_eq!(record.raw, "deposit, 1, 5, 2.0");
+        assert!(source.next_record().unwrap().is_none());
+    }
+
+    /// Check that a row which is not valid UTF-8 is malformed, and the rows after it are read
+    #[test]
+    fn test_invalid_utf8() {
+        let input = b"type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, 2, \xff\xfe\ndeposit, 1, 3, 1.0\n";
+        let mut source = CsvSource::new(&input[..]).unwrap();
+        assert!(source.next_record().unwrap().unwrap().tx.is_ok());
+        let record = source.next_record().unwrap().unwrap();
+        assert_eq!((record.line, record.client_id, record.tx_id), (3, Some(1), Some(2)));
+        assert_eq!(record.raw, "deposit, 1, 2, \u{fffd}\u{fffd}");
+        assert!(record.tx.is_err());
+        assert!(source.next_record().unwrap().unwrap().tx.is_ok());
+        assert!(source.next_record().unwrap().is_none());
+
+        let input = b"{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": 1}\n{\"type\": \"deposit\", \"client\": 1, \"tx\": 2, \"amount\": \"\xff\xfe\"}\n{\"type\": \"deposit\", \"client\": 1, \"tx\": 3, \"amount\": 1}\n";
+        let mut source = JsonLinesSource::new(&input[..]);
+        assert!(source.next_record().unwrap().unwrap().tx.is_ok());
+        let record = source.next_record().unwrap().unwrap();
+        assert_eq!(record.line, 2);
+        assert!(record.tx.is_err());
+        assert!(source.next_record().unwrap().unwrap().tx.is_ok());
+        assert!(source.next_record().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_json_lines() {
+        let input = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 0.1}
+
+{"type": "deposit", "client": 1, "tx": 2, "amount": "1.2345"}
+{"type": "dispute", "client": 1, "tx": 2}
+{"type": "deposit", "client": 1, "tx": 3
+"#;
+        let mut source = JsonLinesSource::new(input.as_bytes());
+
+        let record = source.next_record().unwrap().unwrap();
+        assert_eq!(record.line, 1);
+        let tx = record.tx.unwrap();
+        assert!(tx.r#type == TxType::Deposit);
+        assert_eq!(tx.amount, Some(Decimal::from_str("0.1").unwrap()));
+
+        let record = source.next_record().unwrap().unwrap();
+        assert_eq!(record.line, 3);
+        assert_eq!(record.tx.unwrap().amount, Some(Decimal::from_str("1.2345").unwrap()));
+
+        let record = source.next_record().unwrap().unwrap();
+        assert_eq!(record.tx.unwrap().amount, None);
+
+        let record = source.next_record().unwrap().unwrap();
+        assert_eq!(record.line, 5);
+        assert!(record.tx.is_err());
+
+        assert!(source.next_record().unwrap().is_none());
+    }
+}
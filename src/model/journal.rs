@@ -0,0 +1,341 @@
+use std::{
+    borrow::Cow,
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Seek, SeekFrom, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use log::*;
+use serde::{Deserialize, Serialize};
+
+use super::{Effect, Rejection, Transaction, TxError};
+
+/// JOURNAL_VERSION is bumped on any incompatible change of the journal format
+pub const JOURNAL_VERSION: u32 = 1;
+
+/// SyncPolicy decides how often the journal is fsynced. Every entry is written to the OS before its
+/// transaction is applied, so a crash of the process never loses one, but a crash of the OS may lose
+/// the entries which are not synced yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// sync every entry, the slowest and the safest
+    Always,
+    /// sync every n entries
+    Batch(u32),
+    /// leave it to the OS
+    Never,
+}
+
+impl FromStr for SyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "always" => Ok(SyncPolicy::Always),
+            "never" => Ok(SyncPolicy::Never),
+            other => match other.strip_prefix("batch=").map(str::parse) {
+                Some(Ok(n)) if n > 0 => Ok(SyncPolicy::Batch(n)),
+                _ => Err(format!("unknown sync policy: {}, expecting always, never or batch=<n>", s)),
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct JournalHeader {
+    version: u32,
+}
+
+/// JournalRecord is what happened to the ledger
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "lowercase")]
+pub enum JournalRecord<'a> {
+    /// an empty account is opened, see AccountPolicy::AnyRow
+    Open { client: u16 },
+    /// an accepted transaction and its effect
+    Apply { tx: Box<Cow<'a, Transaction>>, effect: Cow<'a, Effect> },
+    /// a rejected row, so that it's reported again after a recovery
+    Reject {
+        client: Option<u16>,
+        tx: Option<u32>,
+        reason: Cow<'a, TxError>,
+        detail: Cow<'a, str>,
+        /// the record as it was read, see Rejection::record
+        raw: Cow<'a, str>,
+    },
+}
+
+impl<'a> JournalRecord<'a> {
+    pub fn reject(rejection: &'a Rejection) -> JournalRecord<'a> {
+        JournalRecord::Reject {
+            client: rejection.client_id,
+            tx: rejection.tx_id,
+            reason: Cow::Borrowed(&rejection.reason),
+            detail: Cow::Borrowed(&rejection.detail),
+            raw: Cow::Borrowed(&rejection.record),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct JournalEntry<'a> {
+    pub seq: u64,
+    /// the line of the input the record comes from
+    pub line: u64,
+    #[serde(flatten)]
+    pub record: JournalRecord<'a>,
+}
+
+/// Journal is an append-only write-ahead log of everything changing the ledger, one JSON entry per line.
+/// Replaying it on top of the same starting state reconstructs the ledger exactly.
+pub struct Journal {
+    file: File,
+    sync: SyncPolicy,
+    unsynced: u32,
+    seq: u64,
+    last_line: u64,
+}
+
+impl Journal {
+    /// create starts a new journal at path, truncating any existing one
+    pub fn create<P: AsRef<Path>>(path: P, sync: SyncPolicy) -> io::Result<Journal> {
+        let mut file = File::create(path)?;
+        let mut header = serde_json::to_vec(&JournalHeader { version: JOURNAL_VERSION })?;
+        header.push(b'\n');
+        file.write_all(&header)?;
+        file.sync_all()?;
+
+        Ok(Journal {
+            file,
+            sync,
+            unsynced: 0,
+            seq: 0,
+            last_line: 0,
+        })
+    }
+
+    /// recover reads the journal at path, calling replay on every entry in order, and reopens it for appending.
+    /// A torn entry at the end, from a crash in the middle of a write, is dropped.
+    pub fn recover<P, F>(path: P, sync: SyncPolicy, mut replay: F) -> io::Result<Journal>
+    where
+        P: AsRef<Path>,
+        F: FnMut(JournalEntry<'static>),
+    {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut reader = BufReader::new(&file);
+
+        let mut buf = String::new();
+        reader.read_line(&mut buf)?;
+        let header: JournalHeader = serde_json::from_str(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if header.version != JOURNAL_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported journal version {}, expecting {}", header.version, JOURNAL_VERSION),
+            ));
+        }
+
+        let mut valid_len = buf.len() as u64;
+        let mut seq = 0;
+        let mut last_line = 0;
+        loop {
+            buf.clear();
+            if reader.read_line(&mut buf)? == 0 {
+                break;
+            }
+
+            let entry: JournalEntry = match serde_json::from_str(&buf) {
+                Ok(entry) if buf.ends_with('\n') => entry,
+                _ => {
+                    // only the very last entry may be torn
+                    if reader.read_line(&mut String::new())? != 0 {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("corrupted journal entry after seq {}", seq)));
+                    }
+
+                    warn!("dropping a torn journal entry after seq {}", seq);
+                    break;
+                }
+            };
+
+            if entry.seq != seq + 1 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("journal entry seq {} after {}", entry.seq, seq),
+                ));
+            }
+
+            seq = entry.seq;
+            last_line = entry.line;
+            valid_len += buf.len() as u64;
+            replay(entry);
+        }
+
+        drop(reader);
+        file.set_len(valid_len)?;
+        file.seek(SeekFrom::End(0))?;
+
+        info!("recovered {} journal entries, up to line {}", seq, last_line);
+        Ok(Journal {
+            file,
+            sync,
+            unsynced: 0,
+            seq,
+            last_line,
+        })
+    }
+
+    /// append writes record in a single write, and syncs it according to the sync policy
+    pub fn append(&mut self, line: u64, record: JournalRecord<'_>) -> io::Result<()> {
+        let entry = JournalEntry {
+            seq: self.seq + 1,
+            line,
+            record,
+        };
+
+        let mut buf = serde_json::to_vec(&entry)?;
+        buf.push(b'\n');
+        self.file.write_all(&buf)?;
+
+        self.seq = entry.seq;
+        self.last_line = line;
+        self.unsynced += 1;
+
+        match self.sync {
+            SyncPolicy::Always => self.sync()?,
+            SyncPolicy::Batch(n) if self.unsynced >= n => self.sync()?,
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced > 0 {
+            self.file.sync_data()?;
+            self.unsynced = 0;
+        }
+
+        Ok(())
+    }
+
+    /// last_line is the input line of the latest entry, so that processing can go on from the next one after a recovery
+    pub fn last_line(&self) -> u64 {
+        self.last_line
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        if let Err(e) = self.sync() {
+            error!("failed to sync the journal: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, io::Write};
+
+    use crate::model::{Bookkeeper, Currency, Journal, RejectionLog, SyncPolicy};
+
+    const INPUT: &str = "type, client, tx, amount
+deposit, 1, 1, 3.0
+deposit, 2, 2, 5.0
+withdrawal, 2, 3, 1.0
+dispute, 2, 3,
+deposit, 2, 1, 1.0
+chargeback, 2, 3,";
+
+    fn balances(bkeeper: &Bookkeeper) -> Vec<String> {
+        let mut balances: Vec<_> = bkeeper
+            .accounts
+            .values()
+            .map(|a| format!("{} {} {} {} {:?}", a.client_id, a.balances(Currency::USD).available, a.balances(Currency::USD).held, a.balances(Currency::USD).total, a.state))
+            .collect();
+        balances.sort();
+        balances
+    }
+
+    #[test]
+    fn test_recover() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+
+        let mut bkeeper = Bookkeeper::new();
+        bkeeper.attach_journal(Journal::create(&path, SyncPolicy::Batch(2)).unwrap());
+        bkeeper.process_reader(INPUT.as_bytes()).unwrap();
+        let expected = balances(&bkeeper);
+        drop(bkeeper);
+
+        // a torn entry at the end is dropped
+        let mut f = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        f.write_all(b"{\"seq\":6,\"line\":8,\"rec").unwrap();
+        drop(f);
+
+        let mut recovered = Bookkeeper::new();
+        recovered.recover_journal(&path, SyncPolicy::Always).unwrap();
+        assert_eq!(balances(&recovered), expected);
+
+        // nothing is applied twice, and the tx ids are taken
+        recovered.process_reader(INPUT.as_bytes()).unwrap();
+        assert_eq!(balances(&recovered), expected);
+        recovered.process_reader(format!("{}\ndeposit, 3, 1, 1.0", INPUT).as_bytes()).unwrap();
+        assert_eq!(balances(&recovered), expected);
+
+        // and the journal goes on from where it stopped
+        recovered.process_reader(format!("{}\ndeposit, 3, 1, 1.0\ndeposit, 3, 9, 1.0", INPUT).as_bytes()).unwrap();
+        drop(recovered);
+
+        let mut again = Bookkeeper::new();
+        again.recover_journal(&path, SyncPolicy::Never).unwrap();
+        assert_eq!(again.accounts.len(), 3);
+    }
+
+    #[test]
+    fn test_recover_rejections() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+        let rejected = |log: RejectionLog| log.rejections.into_iter().map(|r| format!("{} {} {} {}", r.line, r.reason.code(), r.detail, r.record)).collect::<Vec<_>>();
+
+        let mut expected = RejectionLog::default();
+        Bookkeeper::new().process_reader_with_rejections(INPUT.as_bytes(), &mut expected).unwrap();
+        let expected = rejected(expected);
+        assert_eq!(expected.len(), 1);
+
+        // the crash is after the row following the rejected one, so the rejected one is not read again
+        let mut bkeeper = Bookkeeper::new();
+        bkeeper.attach_journal(Journal::create(&path, SyncPolicy::Never).unwrap());
+        bkeeper.process_reader(INPUT.as_bytes()).unwrap();
+        drop(bkeeper);
+
+        let mut recovered = Bookkeeper::new();
+        recovered.recover_journal(&path, SyncPolicy::Never).unwrap();
+        let mut rejections = RejectionLog::default();
+        recovered.process_reader_with_rejections(INPUT.as_bytes(), &mut rejections).unwrap();
+        assert_eq!(rejected(rejections), expected);
+    }
+
+    #[test]
+    fn test_corrupted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal");
+
+        let mut bkeeper = Bookkeeper::new();
+        bkeeper.attach_journal(Journal::create(&path, SyncPolicy::Never).unwrap());
+        bkeeper.process_reader(INPUT.as_bytes()).unwrap();
+        drop(bkeeper);
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::write(&path, content.replacen("\"seq\":2", "\"seq\":3", 1)).unwrap();
+        assert!(Bookkeeper::new().recover_journal(&path, SyncPolicy::Never).is_err());
+    }
+
+    #[test]
+    fn test_sync_policy() {
+        assert_eq!("always".parse(), Ok(SyncPolicy::Always));
+        assert_eq!("never".parse(), Ok(SyncPolicy::Never));
+        assert_eq!("batch=100".parse(), Ok(SyncPolicy::Batch(100)));
+        assert!("batch=0".parse::<SyncPolicy>().is_err());
+        assert!("sometimes".parse::<SyncPolicy>().is_err());
+    }
+}
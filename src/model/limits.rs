@@ -0,0 +1,215 @@
+use std::{fmt, io, str::FromStr};
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize, Serializer};
+
+use super::{Change, Currency, Effect, Event, TxError, TxType};
+
+const DAY: u64 = 24 * 60 * 60;
+
+/// Window is the period the transactions are counted and summed over, up to now
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Window {
+    /// the last seconds
+    Rolling(u64),
+    /// since midnight UTC
+    Day,
+}
+
+impl Window {
+    /// since returns the first second of the window ending at now
+    pub fn since(&self, now: u64) -> u64 {
+        match self {
+            Window::Rolling(seconds) => now.saturating_sub(*seconds) + 1,
+            Window::Day => now - now % DAY,
+        }
+    }
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    /// from_str takes day, or a number of seconds, minutes or hours, e.g., 30s, 10m or 24h
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid window: {}, expecting day, or a duration such as 30s, 10m or 24h", s);
+        if s.eq_ignore_ascii_case("day") {
+            return Ok(Window::Day);
+        }
+
+        let unit = match s.chars().last() {
+            Some('s') => 1,
+            Some('m') => 60,
+            Some('h') => 60 * 60,
+            _ => return Err(invalid()),
+        };
+        match s[..s.len() - 1].parse::<u64>().ok().and_then(|n| n.checked_mul(unit)) {
+            Some(seconds) if seconds > 0 => Ok(Window::Rolling(seconds)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl TryFrom<String> for Window {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Window {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Window::Rolling(seconds) => write!(f, "{}s", seconds),
+            Window::Day => f.write_str("day"),
+        }
+    }
+}
+
+impl Serialize for Window {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// LimitRule limits the deposits or the withdrawals of every client, or of one client, in a currency or in any of them:
+/// the amount of each, and the total and the count over a window
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LimitRule {
+    pub r#type: TxType,
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    /// the client whose own rules override the ones of every client, for this type
+    #[serde(default)]
+    pub client: Option<u16>,
+    #[serde(default)]
+    pub max_amount: Option<Decimal>,
+    #[serde(default)]
+    pub window: Option<Window>,
+    #[serde(default)]
+    pub max_total: Option<Decimal>,
+    #[serde(default)]
+    pub max_count: Option<u32>,
+}
+
+/// Limits are the rules checked before a deposit/withdrawal is accepted, see Bookkeeper::set_limits
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    pub limits: Vec<LimitRule>,
+}
+
+impl Limits {
+    pub fn new() -> Limits {
+        Limits::default()
+    }
+
+    /// from_reader reads limits saved as JSON, e.g., {"limits": [{"type": "withdrawal", "window": "1m", "max_count": 10}]}.
+    /// A total or a count needs a window, and a window needs one of them.
+    pub fn from_reader<R: io::Read>(r: R) -> io::Result<Limits> {
+        let limits: Limits = serde_json::from_reader(r)?;
+        for rule in &limits.limits {
+            let funds = rule.r#type == TxType::Deposit || rule.r#type == TxType::Withdrawal;
+            let windowed = rule.max_total.is_some() || rule.max_count.is_some();
+            if !funds || rule.window.is_some() != windowed {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid limit: {:?}", rule)));
+            }
+        }
+        Ok(limits)
+    }
+
+    /// rules returns the rules of a deposit/withdrawal of client in currency: the ones of the client, if it has any for r#type,
+    /// otherwise the ones of every client
+    pub fn rules<'a>(&'a self, r#type: &'a TxType, client: u16, currency: Currency) -> impl Iterator<Item = &'a LimitRule> + 'a {
+        let overridden = self.limits.iter().any(|rule| rule.r#type == *r#type && rule.client == Some(client));
+        let owner = if overridden { Some(client) } else { None };
+        self.limits
+            .iter()
+            .filter(move |rule| rule.r#type == *r#type && rule.client == owner && rule.currency.is_none_or(|c| c == currency))
+    }
+
+    /// check checks the deposit/withdrawal of effect, at now, against the rules, given the events of its account so far.
+    /// The window of a rule counts the deposits/withdrawals of the same type and currency whose time is in it, in any order,
+    /// so that a backdated one doesn't hide the others.
+    pub fn check(&self, effect: &Effect, events: &[Event], now: u64) -> Result<(), TxError> {
+        let (r#type, amount) = match effect.changes.first() {
+            Some(Change::Deposit { amount }) => (TxType::Deposit, *amount),
+            Some(Change::Withdrawal { amount }) => (TxType::Withdrawal, *amount),
+            _ => return Ok(()),
+        };
+
+        for rule in self.rules(&r#type, effect.client, effect.currency) {
+            if let Some(limit) = rule.max_amount.filter(|&limit| amount > limit) {
+                return Err(TxError::AmountLimitError { limit });
+            }
+
+            let Some(window) = rule.window else {
+                continue;
+            };
+            let since = window.since(now);
+            // an event without a timestamp is counted by the time it was accepted at
+            let applied = events
+                .iter()
+                .filter(|e| e.time.or(e.accepted).is_some_and(|time| (since..=now).contains(&time)))
+                .filter(|e| e.r#type == r#type && e.currency == effect.currency)
+                .filter_map(|e| e.amount);
+            let (count, total) = applied.fold((0u32, Decimal::ZERO), |(count, total), amount| (count.saturating_add(1), total.saturating_add(amount)));
+
+            if let Some(limit) = rule.max_count.filter(|&limit| count >= limit) {
+                return Err(TxError::CountLimitError { limit, window });
+            }
+            if let Some(limit) = rule.max_total.filter(|&limit| total.saturating_add(amount) > limit) {
+                return Err(TxError::TotalLimitError { limit, window });
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::model::{Currency, Limits, TxType, Window};
+
+    #[test]
+    fn test_window() {
+        assert_eq!("day".parse(), Ok(Window::Day));
+        assert_eq!("90s".parse(), Ok(Window::Rolling(90)));
+        assert_eq!("10m".parse(), Ok(Window::Rolling(600)));
+        assert_eq!("24h".parse(), Ok(Window::Rolling(86400)));
+        for invalid in ["", "s", "0s", "10", "1d", "-1h"] {
+            assert!(invalid.parse::<Window>().is_err(), "{}", invalid);
+        }
+
+        assert_eq!(Window::Rolling(60).since(1000), 941);
+        assert_eq!(Window::Day.since(86400 * 3 + 5), 86400 * 3);
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = r#"{"limits": [
+            {"type": "withdrawal", "max_amount": "1000"},
+            {"type": "withdrawal", "window": "day", "max_total": "5000"},
+            {"type": "withdrawal", "currency": "EUR", "window": "1m", "max_count": 3},
+            {"type": "withdrawal", "client": 7, "max_amount": "50000"},
+            {"type": "deposit", "max_amount": "100000"}
+        ]}"#;
+        let limits = Limits::from_reader(limits.as_bytes()).unwrap();
+
+        let rules = |client, currency| limits.rules(&TxType::Withdrawal, client, currency).count();
+        assert_eq!(rules(1, Currency::USD), 2);
+        assert_eq!(rules(1, Currency::EUR), 3);
+        assert_eq!(rules(7, Currency::EUR), 1);
+
+        for invalid in [
+            r#"{"limits": [{"type": "dispute", "max_amount": "1"}]}"#,
+            r#"{"limits": [{"type": "deposit", "max_count": 1}]}"#,
+            r#"{"limits": [{"type": "deposit", "window": "1h"}]}"#,
+            r#"{"limits": [{"type": "deposit", "window": "1w", "max_count": 1}]}"#,
+        ] {
+            assert!(Limits::from_reader(invalid.as_bytes()).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,397 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead},
+    mem,
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Condvar, Mutex, MutexGuard, PoisonError,
+    },
+    thread,
+};
+
+use log::*;
+
+use super::{AccountStore, Bookkeeper, CsvSource, InputFormat, InputRecord, JsonLinesSource, Rejection, LedgerBound, RejectionSink, TxIdShards, TxSource, TxType, MAX_SHARDS};
+
+/// rows sent to a shard at once
+const BATCH_SIZE: usize = 1024;
+/// batches queued per shard before the reader waits
+const QUEUE_SIZE: usize = 8;
+
+type Batch = Vec<(u64, InputRecord)>;
+
+/// Shard owns the accounts of the clients routed to it, seq is the index of a row in the input
+struct Shard {
+    keeper: Bookkeeper,
+    rejections: Vec<Rejection>,
+    /// the accounts opened while processing, by the seq of the row opening them
+    opened: Vec<(u64, u16)>,
+}
+
+/// Progress is the count of rows processed by each shard, which the router waits on to drain them
+struct Progress {
+    done: Mutex<Vec<u64>>,
+    changed: Condvar,
+}
+
+impl Progress {
+    fn lock(&self) -> MutexGuard<'_, Vec<u64>> {
+        // a count is valid even if a shard panicked while holding the lock
+        self.done.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// add counts the rows of a batch processed by shard, everything they changed, e.g., in the tx index, is visible to the router
+    fn add(&self, shard: usize, count: u64) {
+        self.lock()[shard] += count;
+        self.changed.notify_all();
+    }
+
+    /// wait returns once each shard has processed the rows sent to it
+    fn wait(&self, sent: &[u64]) {
+        let done = self.lock();
+        let _done = self.changed.wait_while(done, |done| done.iter().zip(sent).any(|(done, sent)| done < sent)).unwrap_or_else(PoisonError::into_inner);
+    }
+}
+
+/// Stopped marks a shard as done with everything when it stops, even on a panic, so that the router never waits for it
+struct Stopped<'a>(&'a Progress, usize);
+
+impl Drop for Stopped<'_> {
+    fn drop(&mut self) {
+        self.0.lock()[self.1] = u64::MAX;
+        self.0.changed.notify_all();
+    }
+}
+
+impl Shard {
+    fn run(&mut self, rx: Receiver<Batch>, progress: &Progress, shard: usize) -> io::Result<()> {
+        let _stopped = Stopped(progress, shard);
+        for batch in rx {
+            let count = batch.len() as u64;
+            for (seq, record) in batch {
+                // the client of a row may get an account, even if the row is rejected, see AccountPolicy::AnyRow
+                let no_account = record.tx.as_ref().ok().map(|tx| tx.client_id).filter(|client_id| !self.keeper.accounts.contains_key(client_id));
+
+                if let Some(rejection) = self.keeper.process_record(record)? {
+                    self.rejections.push(rejection);
+                }
+
+                if let Some(client_id) = no_account.filter(|client_id| self.keeper.accounts.contains_key(client_id)) {
+                    self.opened.push((seq, client_id));
+                }
+            }
+
+            progress.add(shard, count);
+        }
+
+        Ok(())
+    }
+}
+
+/// Router reads the input, and sends every row to the shard of its client in order
+struct Router<'a> {
+    senders: Vec<SyncSender<Batch>>,
+    batches: Vec<Batch>,
+    sent: Vec<u64>,
+    progress: &'a Progress,
+    /// the shard of the rows using each tx id, or that they're in more than one
+    shards: TxIdShards,
+    /// the bound of the general ledger, with the rows routed so far
+    bound: LedgerBound,
+}
+
+impl Router<'_> {
+    /// within_bound adds the amount of record to the bound of the general ledger, and tells if it still holds
+    fn within_bound(&mut self, record: &InputRecord) -> bool {
+        match &record.tx {
+            Ok(tx) if tx.r#type == TxType::Deposit || tx.r#type == TxType::Withdrawal => self.bound.add(tx.amount.unwrap_or_default()),
+            _ => true,
+        }
+    }
+
+    fn route(&mut self, seq: u64, record: InputRecord) -> io::Result<()> {
+        let tx = match &record.tx {
+            Ok(tx) => tx,
+            // a malformed row is rejected by any shard without touching anything
+            Err(_) => return self.push(0, seq, record),
+        };
+
+        // the outcome of a row may depend on the rows of another client using the same tx id, which are
+        // in other shards, so it waits for all the rows before it to be processed. It's rare enough.
+        // The rows of the clients of the same shard are processed in order anyway.
+        let shard = tx.client_id as usize % self.senders.len();
+        if !tx.r#type.is_admin() && self.shards.route(tx.tx_id, shard) {
+            self.drain()?;
+        }
+
+        self.push(shard, seq, record)
+    }
+
+    fn push(&mut self, shard: usize, seq: u64, record: InputRecord) -> io::Result<()> {
+        self.batches[shard].push((seq, record));
+        if self.batches[shard].len() >= BATCH_SIZE {
+            self.send(shard)?;
+        }
+
+        Ok(())
+    }
+
+    fn send(&mut self, shard: usize) -> io::Result<()> {
+        let batch = mem::replace(&mut self.batches[shard], Vec::with_capacity(BATCH_SIZE));
+        self.sent[shard] += batch.len() as u64;
+        self.senders[shard].send(batch).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, format!("shard {} stopped", shard)))
+    }
+
+    /// drain waits for all the rows routed so far to be processed
+    fn drain(&mut self) -> io::Result<()> {
+        for shard in 0..self.senders.len() {
+            if !self.batches[shard].is_empty() {
+                self.send(shard)?;
+            }
+        }
+
+        self.progress.wait(&self.sent);
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        for shard in 0..self.senders.len() {
+            if !self.batches[shard].is_empty() {
+                self.send(shard)?;
+            }
+        }
+
+        // the shards stop once their queue is closed
+        Ok(())
+    }
+}
+
+impl Bookkeeper {
+    /// process_input_parallel is process_input on threads shards, see process_source_parallel
+    pub fn process_input_parallel<R, S>(&mut self, r: R, format: InputFormat, rejections: &mut S, threads: usize) -> io::Result<()>
+    where
+        R: BufRead,
+        S: RejectionSink + ?Sized,
+    {
+        match format {
+            InputFormat::Csv => self.process_source_parallel(&mut CsvSource::new(r)?, rejections, threads),
+            InputFormat::JsonLines => self.process_source_parallel(&mut JsonLinesSource::new(r), rejections, threads),
+        }
+    }
+
+    /// process_source_parallel reads source on the calling thread, and processes the rows on threads shards, at most MAX_SHARDS, each owning
+    /// the accounts of the clients routed to it by client id. The rows of a client are processed in order, and the result,
+    /// including the rejections and the insertion order of the accounts, is the same as process_source.
+    /// The rejections are reported once all the rows are processed. A journal needs a single thread.
+    ///
+    /// Each shard posts to its own general ledger, which is only the same as posting to the whole one while no balance
+    /// can overflow or be rounded, see LedgerBound. From the first row past the bound, the shards are merged and the
+    /// rest is processed on the calling thread, so that a row which can't be posted is rejected as process_source does.
+    pub fn process_source_parallel<T, S>(&mut self, source: &mut T, rejections: &mut S, threads: usize) -> io::Result<()>
+    where
+        T: TxSource + ?Sized,
+        S: RejectionSink + ?Sized,
+    {
+        let threads = threads.min(MAX_SHARDS);
+        if threads <= 1 {
+            return self.process_source(source, rejections);
+        }
+        if self.journaled() {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "the journal can't be used with more than one thread"));
+        }
+
+        let bound = self.ledger_bound();
+
+        // the accounts which are already open keep their place in the insertion order
+        let accounts = mem::take(&mut self.accounts).into_opened();
+        let preloaded: Vec<_> = accounts.iter().map(|acct| acct.client_id).collect();
+        let mut shards: Vec<_> = (0..threads)
+            .map(|_| Shard {
+                keeper: self.shard(),
+                rejections: Vec::new(),
+                opened: Vec::new(),
+            })
+            .collect();
+        for acct in accounts {
+            shards[acct.client_id as usize % threads].keeper.accounts.insert(acct);
+        }
+
+        let progress = Progress {
+            done: Mutex::new(vec![0; threads]),
+            changed: Condvar::new(),
+        };
+        let mut errors = Vec::new();
+        let mut rest = None;
+        thread::scope(|s| {
+            let mut senders = Vec::with_capacity(threads);
+            let mut handles = Vec::with_capacity(threads);
+            for (i, shard) in shards.iter_mut().enumerate() {
+                let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+                let progress = &progress;
+                senders.push(tx);
+                handles.push(s.spawn(move || shard.run(rx, progress, i)));
+            }
+
+            let mut router = Router {
+                senders,
+                batches: (0..threads).map(|_| Vec::with_capacity(BATCH_SIZE)).collect(),
+                sent: vec![0; threads],
+                progress: &progress,
+                shards: TxIdShards::new(),
+                bound,
+            };
+
+            let result = (|| {
+                let mut seq = 0;
+                while let Some(record) = source.next_record()? {
+                    if !router.within_bound(&record) {
+                        rest = Some(record);
+                        break;
+                    }
+                    router.route(seq, record)?;
+                    seq += 1;
+                }
+                router.finish()
+            })();
+            if let Err(e) = result {
+                errors.push(e);
+            }
+
+            for handle in handles {
+                match handle.join() {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => errors.push(e),
+                    Err(panic) => std::panic::resume_unwind(panic),
+                }
+            }
+        });
+
+        // put the accounts back in the order they were opened
+        let mut opened: Vec<_> = shards.iter_mut().flat_map(|shard| mem::take(&mut shard.opened)).collect();
+        opened.sort_unstable();
+        let mut accounts: BTreeMap<_, _> = shards
+            .iter_mut()
+            .flat_map(|shard| mem::take(&mut shard.keeper.accounts).into_opened())
+            .map(|acct| (acct.client_id, acct))
+            .collect();
+        let mut store = AccountStore::new();
+        for client_id in preloaded.into_iter().chain(opened.into_iter().map(|(_, client_id)| client_id)) {
+            if let Some(acct) = accounts.remove(&client_id) {
+                store.insert(acct);
+            }
+        }
+        self.accounts = store;
+        for shard in &mut shards {
+            self.merge_shard(&mut shard.keeper)?;
+        }
+
+        if let Some(e) = errors.into_iter().next() {
+            return Err(e);
+        }
+
+        let mut all: Vec<_> = shards.into_iter().flat_map(|shard| shard.rejections).collect();
+        all.sort_by_key(|rejection| rejection.line);
+        debug!("{} rejection(s) from {} shards", all.len(), threads);
+
+        if let Some(record) = &rest {
+            info!("the general ledger may overflow from line {}, going on on one thread", record.line);
+        }
+        let mut next = rest;
+        while let Some(record) = next {
+            if let Some(rejection) = self.process_record(record)? {
+                all.push(rejection);
+            }
+            next = source.next_record()?;
+        }
+
+        for rejection in &all {
+            rejections.on_rejection(rejection)?;
+        }
+        rejections.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fmt::Write;
+
+    use crate::model::{AccountPolicy, Bookkeeper, InputFormat, RejectionLog, ReportFormat, ReportOrder, ReportWriter, TrialBalance};
+
+    fn run(input: &str, policy: AccountPolicy, threads: usize) -> (String, String, Vec<(u64, &'static str)>, TrialBalance) {
+        let mut bkeeper = Bookkeeper::with_policy(policy);
+        bkeeper.register_client(7);
+        let mut rejections = RejectionLog::default();
+        bkeeper.process_input_parallel(input.as_bytes(), InputFormat::Csv, &mut rejections, threads).unwrap();
+
+        let report = |order| {
+            let mut buf = Vec::new();
+            bkeeper.write_report(&mut ReportWriter::new(&mut buf, ReportFormat::Csv), order).unwrap();
+            String::from_utf8(buf).unwrap()
+        };
+        let rejections = rejections.rejections.iter().map(|r| (r.line, r.reason.code())).collect();
+        (report(ReportOrder::ClientId), report(ReportOrder::Insertion), rejections, bkeeper.trial_balance())
+    }
+
+    #[test]
+    fn test_same_as_sequential() {
+        let mut input = String::from("type, client, tx, amount\n");
+        for tx in 1..=5000u32 {
+            let client = tx * 7 % 23;
+            match tx % 11 {
+                // tx ids of other clients, some of which are not applied yet
+                3 => writeln!(input, "deposit, {}, {}, 1.0", client, tx - 2),
+                4 => writeln!(input, "dispute, {}, {},", client, tx + 1),
+                5 => writeln!(input, "withdrawal, {}, {}, 2.5", client, tx),
+                6 => writeln!(input, "dispute, {}, {},", client, tx - 1),
+                7 => writeln!(input, "chargeback, {}, {},", client, tx - 1),
+                8 => writeln!(input, "unlock, {}, {},", client, tx),
+                9 => writeln!(input, "deposit, x, {}, 1.0", tx),
+                _ => writeln!(input, "deposit, {}, {}, {}.{}", client, tx, tx % 10, tx % 7),
+            }
+            .unwrap();
+        }
+
+        for policy in [AccountPolicy::OnDeposit, AccountPolicy::AnyRow] {
+            let expected = run(&input, policy, 1);
+            assert!(!expected.2.is_empty());
+            for threads in [2, 3, 8] {
+                assert!(run(&input, policy, threads) == expected);
+            }
+        }
+    }
+
+    #[test]
+    fn test_past_ledger_bound() {
+        // the rows after the huge deposit are processed on one thread, and some can't be posted any more
+        let mut input = String::from("type, client, tx, amount\n");
+        for tx in 1..=2000u32 {
+            let client = tx * 7 % 23;
+            match tx {
+                1000 => writeln!(input, "deposit, {}, {}, 7922816251426433759354389000", client, tx),
+                _ if tx % 5 == 0 => writeln!(input, "withdrawal, {}, {}, 0.5", client, tx),
+                _ if tx < 1000 => writeln!(input, "deposit, {}, {}, {}.{}", client, tx, tx % 10, tx % 7),
+                _ => writeln!(input, "deposit, {}, {}, {}0.{}", client + 30, tx, tx % 10, tx % 7),
+            }
+            .unwrap();
+        }
+
+        let expected = run(&input, AccountPolicy::OnDeposit, 1);
+        assert!(expected.2.iter().any(|&(line, reason)| line > 1001 && reason == "invalid_amount"));
+        for threads in [2, 3, 8] {
+            assert!(run(&input, AccountPolicy::OnDeposit, threads) == expected);
+        }
+    }
+
+    #[test]
+    fn test_duplicate_across_shards() {
+        // tx 1 is rejected for client 1, so it's taken by client 2 rather than by the later row of client 1
+        let input = "type, client, tx, amount
+deposit, 1, 1,
+deposit, 2, 1, 1.0
+deposit, 1, 1, 2.0
+dispute, 1, 1,";
+        let (report, _, rejections, _) = run(input, AccountPolicy::OnDeposit, 2);
+        assert_eq!(rejections, vec![(2, "missing_amount"), (4, "duplicate_tx_id"), (5, "client_mismatch")]);
+        assert_eq!(report, "client,currency,available,held,total,fees,locked,state\n2,USD,1.0000,0,1.0000,0,false,active\n7,USD,0,0,0,0,false,active\n");
+    }
+}
@@ -0,0 +1,181 @@
+use std::{collections::BTreeMap, str::FromStr};
+
+use rust_decimal::{Decimal, RoundingStrategy};
+
+use super::{Currency, TxError};
+
+/// the most decimal places a Decimal can hold
+const MAX_PLACES: u32 = 28;
+
+/// RoundingMode is what is done with an amount which has more decimal places than its currency
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RoundingMode {
+    /// to the nearest, a tie to the even digit
+    Bankers,
+    /// to the nearest, a tie away from zero
+    #[default]
+    HalfUp,
+    /// toward zero
+    Truncate,
+    /// the transaction is rejected with excess_precision
+    Reject,
+}
+
+impl FromStr for RoundingMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "bankers" | "half-even" => Ok(RoundingMode::Bankers),
+            "half-up" => Ok(RoundingMode::HalfUp),
+            "truncate" => Ok(RoundingMode::Truncate),
+            "reject" => Ok(RoundingMode::Reject),
+            _ => Err(format!("unknown rounding mode: {}, expecting bankers, half-up, truncate or reject", s)),
+        }
+    }
+}
+
+/// PrecisionRule is the decimal places of the amounts of a currency, and how the extra ones are rounded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrecisionRule {
+    pub places: u32,
+    pub mode: RoundingMode,
+}
+
+impl Default for PrecisionRule {
+    /// four places, rounded half-up
+    fn default() -> Self {
+        PrecisionRule {
+            places: 4,
+            mode: RoundingMode::default(),
+        }
+    }
+}
+
+impl PrecisionRule {
+    /// round returns amount with exactly the places of the rule, and the residue rounded away, i.e., amount - rounded
+    pub fn round(&self, amount: Decimal) -> Result<(Decimal, Decimal), TxError> {
+        let strategy = match self.mode {
+            RoundingMode::Bankers => RoundingStrategy::MidpointNearestEven,
+            RoundingMode::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            RoundingMode::Truncate => RoundingStrategy::ToZero,
+            RoundingMode::Reject if amount.normalize().scale() > self.places => return Err(TxError::ExcessPrecisionError),
+            RoundingMode::Reject => RoundingStrategy::ToZero,
+        };
+
+        let mut rounded = amount.round_dp_with_strategy(self.places, strategy);
+        // the report shows every amount of a currency with the same places, if the mantissa can hold them
+        rounded.rescale(self.places);
+        Ok((rounded, amount - rounded))
+    }
+}
+
+impl FromStr for PrecisionRule {
+    type Err = String;
+
+    /// from_str takes places[:mode], e.g., 2:bankers, the mode is half-up if it's left out
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (places, mode) = match s.split_once(':') {
+            Some((places, mode)) => (places, mode.trim().parse()?),
+            None => (s, RoundingMode::default()),
+        };
+        let places = places.trim().parse().ok().filter(|&p| p <= MAX_PLACES).ok_or_else(|| format!("invalid decimal places: {}", places))?;
+
+        Ok(PrecisionRule { places, mode })
+    }
+}
+
+/// PrecisionSetting is a rule for a currency, or for all the others if there is no currency
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrecisionSetting {
+    pub currency: Option<Currency>,
+    pub rule: PrecisionRule,
+}
+
+impl FromStr for PrecisionSetting {
+    type Err = String;
+
+    /// from_str takes [currency=]places[:mode], e.g., JPY=0:reject or 2:bankers
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((currency, rule)) => Ok(PrecisionSetting {
+                currency: Some(currency.trim().parse()?),
+                rule: rule.parse()?,
+            }),
+            None => Ok(PrecisionSetting { currency: None, rule: s.parse()? }),
+        }
+    }
+}
+
+/// Precision keeps the precision rule of every currency, see Bookkeeper::set_precision
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Precision {
+    /// the rule of the currencies without one of their own
+    pub default: PrecisionRule,
+    pub currencies: BTreeMap<Currency, PrecisionRule>,
+}
+
+impl Precision {
+    pub fn new() -> Precision {
+        Precision::default()
+    }
+
+    /// set sets the rule of the currency of setting, or the default one
+    pub fn set(&mut self, setting: PrecisionSetting) {
+        match setting.currency {
+            Some(currency) => {
+                self.currencies.insert(currency, setting.rule);
+            }
+            None => self.default = setting.rule,
+        }
+    }
+
+    /// rule returns the rule of currency
+    pub fn rule(&self, currency: Currency) -> PrecisionRule {
+        self.currencies.get(&currency).copied().unwrap_or(self.default)
+    }
+
+    /// places returns the most decimal places of any rule
+    pub fn places(&self) -> u32 {
+        self.currencies.values().map(|rule| rule.places).fold(self.default.places, u32::max)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::Decimal;
+
+    use crate::model::{Currency, Precision, PrecisionRule, PrecisionSetting, RoundingMode, TxError};
+
+    #[test]
+    fn test_round() {
+        let d = |n: i64, scale| Decimal::new(n, scale);
+        let round = |rule: &str, amount| rule.parse::<PrecisionRule>().unwrap().round(amount).map(|(rounded, residue)| (rounded.to_string(), residue));
+
+        assert_eq!(round("2", d(1005, 3)), Ok(("1.01".to_string(), d(-5, 3))));
+        assert_eq!(round("2:bankers", d(1005, 3)), Ok(("1.00".to_string(), d(5, 3))));
+        assert_eq!(round("2:bankers", d(1015, 3)), Ok(("1.02".to_string(), d(-5, 3))));
+        assert_eq!(round("2:truncate", d(1009, 3)), Ok(("1.00".to_string(), d(9, 3))));
+        assert_eq!(round("2:reject", d(1009, 3)), Err(TxError::ExcessPrecisionError));
+        // trailing zeros are not extra precision
+        assert_eq!(round("2:reject", d(10100, 4)), Ok(("1.01".to_string(), Decimal::ZERO)));
+        assert_eq!(round("4", d(15, 1)), Ok(("1.5000".to_string(), Decimal::ZERO)));
+        assert_eq!(round("0", d(25, 1)), Ok(("3".to_string(), d(-5, 1))));
+    }
+
+    #[test]
+    fn test_precision_settings() {
+        let mut precision = Precision::new();
+        for setting in ["2:bankers", "jpy=0:reject", "BTC=8"] {
+            precision.set(setting.parse().unwrap());
+        }
+
+        assert_eq!(precision.rule(Currency::EUR), PrecisionRule { places: 2, mode: RoundingMode::Bankers });
+        assert_eq!(precision.rule("JPY".parse().unwrap()), PrecisionRule { places: 0, mode: RoundingMode::Reject });
+        assert_eq!(precision.rule("BTC".parse().unwrap()), PrecisionRule { places: 8, mode: RoundingMode::HalfUp });
+
+        for invalid in ["", "29", "2:up", "EURO=2", "=2", "-1"] {
+            assert!(invalid.parse::<PrecisionSetting>().is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use std::{io, str::FromStr};
+
+use log::*;
+use serde::{Serialize, Serializer};
+
+use super::TxError;
+
+/// Rejection is a row which was not applied to any account, with the reason why
+#[derive(Debug, Serialize, Clone)]
+pub struct Rejection {
+    /// line number in the input, starting from 1 for the header
+    pub line: u64,
+    #[serde(rename(serialize = "client"))]
+    pub client_id: Option<u16>,
+    #[serde(rename(serialize = "tx"))]
+    pub tx_id: Option<u32>,
+    #[serde(serialize_with = "serialize_reason")]
+    pub reason: TxError,
+    /// human-readable details, e.g., the parsing error for a malformed row
+    pub detail: String,
+    /// the record as it was read, before any trimming, without its line break
+    pub record: String,
+}
+
+fn serialize_reason<S>(reason: &TxError, s: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    s.serialize_str(reason.code())
+}
+
+/// RejectionSink receives every rejected row in input order
+pub trait RejectionSink {
+    fn on_rejection(&mut self, rejection: &Rejection) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// LogRejections keeps the old behavior: one error line per rejection
+#[derive(Default)]
+pub struct LogRejections;
+
+impl RejectionSink for LogRejections {
+    fn on_rejection(&mut self, rejection: &Rejection) -> io::Result<()> {
+        error!(
+            "rejected line {} ({}): {} {}",
+            rejection.line,
+            rejection.record,
+            rejection.reason.code(),
+            rejection.detail
+        );
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectionFormat {
+    Csv,
+    JsonLines,
+}
+
+impl FromStr for RejectionFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "csv" => Ok(RejectionFormat::Csv),
+            "json" | "jsonl" | "ndjson" => Ok(RejectionFormat::JsonLines),
+            _ => Err(format!("unknown rejection format: {}", s)),
+        }
+    }
+}
+
+/// RejectionWriter writes rejections to w as CSV or JSON lines
+pub enum RejectionWriter<W: io::Write> {
+    Csv(Box<csv::Writer<W>>),
+    JsonLines(W),
+}
+
+impl<W: io::Write> RejectionWriter<W> {
+    pub fn new(w: W, format: RejectionFormat) -> RejectionWriter<W> {
+        match format {
+            RejectionFormat::Csv => RejectionWriter::Csv(Box::new(csv::Writer::from_writer(w))),
+            RejectionFormat::JsonLines => RejectionWriter::JsonLines(w),
+        }
+    }
+}
+
+impl<W: io::Write> RejectionSink for RejectionWriter<W> {
+    fn on_rejection(&mut self, rejection: &Rejection) -> io::Result<()> {
+        match self {
+            RejectionWriter::Csv(w) => w.serialize(rejection).map_err(io::Error::from),
+            RejectionWriter::JsonLines(w) => {
+                serde_json::to_writer(&mut *w, rejection)?;
+                w.write_all(b"\n")
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            RejectionWriter::Csv(w) => w.flush(),
+            RejectionWriter::JsonLines(w) => w.flush(),
+        }
+    }
+}
+
+/// RejectionLog collects rejections in memory, mostly for library users and tests
+#[derive(Default)]
+pub struct RejectionLog {
+    pub rejections: Vec<Rejection>,
+}
+
+impl RejectionSink for RejectionLog {
+    fn on_rejection(&mut self, rejection: &Rejection) -> io::Result<()> {
+        self.rejections.push(rejection.clone());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::model::{Rejection, RejectionFormat, RejectionSink, RejectionWriter, TxError};
+
+    fn rejection() -> Rejection {
+        Rejection {
+            line: 3,
+            client_id: Some(1),
+            tx_id: Some(7),
+            reason: TxError::InvalidTxIdError,
+            detail: "invalid Tx ID".to_string(),
+            record: "dispute,1,7,".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_csv_rejection() {
+        let mut buf = Vec::new();
+        {
+            let mut w = RejectionWriter::new(&mut buf, RejectionFormat::Csv);
+            w.on_rejection(&rejection()).unwrap();
+            w.flush().unwrap();
+        }
+
+        let out = String::from_utf8(buf).unwrap();
+        assert_eq!(
+            out,
+            "line,client,tx,reason,detail,record\n3,1,7,invalid_tx_id,invalid Tx ID,\"dispute,1,7,\"\n"
+        );
+    }
+
+    #[test]
+    fn test_json_lines_rejection() {
+        let mut buf = Vec::new();
+        {
+            let mut w = RejectionWriter::new(&mut buf, RejectionFormat::JsonLines);
+            w.on_rejection(&rejection()).unwrap();
+            w.flush().unwrap();
+        }
+
+        let out = String::from_utf8(buf).unwrap();
+        assert_eq!(
+            out,
+            "{\"line\":3,\"client\":1,\"tx\":7,\"reason\":\"invalid_tx_id\",\"detail\":\"invalid Tx ID\",\"record\":\"dispute,1,7,\"}\n"
+        );
+    }
+}
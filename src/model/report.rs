@@ -0,0 +1,178 @@
+use std::{io, str::FromStr};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use super::{Account, AccountState, Currency};
+
+/// ReportRow is a row of the balance report: the balances of an account in a currency
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReportRow {
+    pub client: u16,
+    pub currency: Currency,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    /// the fees charged, which are not in the balances any more
+    pub fees: Decimal,
+    pub locked: bool,
+    pub state: AccountState,
+}
+
+impl Account {
+    /// report_rows returns the report row of every currency of the account, see Account::currencies
+    pub fn report_rows(&self) -> impl Iterator<Item = ReportRow> + '_ {
+        self.currencies().into_iter().map(|currency| {
+            let balances = self.balances(currency);
+            ReportRow {
+                client: self.client_id,
+                currency,
+                available: balances.available,
+                held: balances.held,
+                total: balances.total,
+                fees: self.fees(currency),
+                locked: self.locked(),
+                state: self.state,
+            }
+        })
+    }
+}
+
+/// ReportSink receives every account of the balance report
+pub trait ReportSink {
+    /// on_account gets an account with all its currencies, see Account::report_rows
+    fn on_account(&mut self, account: &Account) -> io::Result<()>;
+
+    /// finish is called once after the last account, even if there is none
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Csv,
+    /// a single JSON array of accounts
+    Json,
+    /// one JSON object per line
+    JsonLines,
+}
+
+impl FromStr for ReportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "csv" => Ok(ReportFormat::Csv),
+            "json" => Ok(ReportFormat::Json),
+            "jsonl" | "ndjson" => Ok(ReportFormat::JsonLines),
+            _ => Err(format!("unknown report format: {}", s)),
+        }
+    }
+}
+
+/// ReportWriter writes the balance report to w as CSV, a JSON array or JSON lines, with a row per client per currency
+pub enum ReportWriter<W: io::Write> {
+    Csv(Box<csv::Writer<W>>),
+    Json { w: W, count: u64 },
+    JsonLines(W),
+}
+
+impl<W: io::Write> ReportWriter<W> {
+    pub fn new(w: W, format: ReportFormat) -> ReportWriter<W> {
+        match format {
+            ReportFormat::Csv => ReportWriter::Csv(Box::new(csv::Writer::from_writer(w))),
+            ReportFormat::Json => ReportWriter::Json { w, count: 0 },
+            ReportFormat::JsonLines => ReportWriter::JsonLines(w),
+        }
+    }
+}
+
+impl<W: io::Write> ReportSink for ReportWriter<W> {
+    fn on_account(&mut self, account: &Account) -> io::Result<()> {
+        for row in account.report_rows() {
+            match self {
+                ReportWriter::Csv(w) => w.serialize(row)?,
+                ReportWriter::Json { w, count } => {
+                    w.write_all(if *count == 0 { b"[" } else { b"," })?;
+                    *count += 1;
+                    serde_json::to_writer(&mut *w, &row)?;
+                }
+                ReportWriter::JsonLines(w) => {
+                    serde_json::to_writer(&mut *w, &row)?;
+                    w.write_all(b"\n")?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        match self {
+            ReportWriter::Csv(w) => w.flush(),
+            ReportWriter::Json { w, count } => {
+                w.write_all(if *count == 0 { b"[]\n" } else { b"]\n" })?;
+                w.flush()
+            }
+            ReportWriter::JsonLines(w) => w.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::Decimal;
+
+    use crate::model::{Account, Balances, Currency, ReportFormat, ReportSink, ReportWriter};
+
+    fn write(format: ReportFormat, accounts: &[Account]) -> String {
+        let mut buf = Vec::new();
+        {
+            let mut w = ReportWriter::new(&mut buf, format);
+            for acct in accounts {
+                w.on_account(acct).unwrap();
+            }
+            w.finish().unwrap();
+        }
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn test_report_formats() {
+        let mut acct = Account::new(1);
+        let balances = Balances {
+            available: Decimal::new(15, 1),
+            total: Decimal::new(15, 1),
+            ..Balances::default()
+        };
+        acct.buckets.insert(Currency::USD, balances);
+        let accounts = [acct, Account::new(2)];
+
+        assert_eq!(
+            write(ReportFormat::Csv, &accounts),
+            "client,currency,available,held,total,fees,locked,state\n1,USD,1.5,0,1.5,0,false,active\n2,USD,0,0,0,0,false,active\n"
+        );
+        assert_eq!(
+            write(ReportFormat::JsonLines, &accounts[..1]),
+            "{\"client\":1,\"currency\":\"USD\",\"available\":\"1.5\",\"held\":\"0\",\"total\":\"1.5\",\"fees\":\"0\",\"locked\":false,\"state\":\"active\"}\n"
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&write(ReportFormat::Json, &accounts)).unwrap();
+        assert_eq!(json.as_array().unwrap().len(), 2);
+        assert_eq!(json[1]["client"], 2);
+        assert_eq!(write(ReportFormat::Json, &[]), "[]\n");
+    }
+
+    /// Check that an account gets a row per currency, in the order of the currency codes
+    #[test]
+    fn test_report_currencies() {
+        let mut acct = Account::new(1);
+        for (currency, total) in [(Currency::USD, 2), (Currency::EUR, 1)] {
+            let total = Decimal::from(total);
+            acct.buckets.insert(currency, Balances { available: total, held: Decimal::ZERO, total });
+        }
+
+        assert_eq!(write(ReportFormat::Csv, &[acct]), "client,currency,available,held,total,fees,locked,state\n1,EUR,1,0,1,0,false,active\n1,USD,2,0,2,0,false,active\n");
+    }
+}
@@ -0,0 +1,31 @@
+use std::{borrow::Cow, io};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::{AccountSnapshot, GeneralLedger};
+
+/// SNAPSHOT_VERSION identifies the layout of a snapshot, and is bumped on any change of it. A snapshot of another
+/// version, or missing a field of this one, is rejected.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("snapshot io error: {0}")]
+    IoError(#[from] io::Error),
+
+    #[error("invalid snapshot: {0}")]
+    FormatError(#[from] serde_json::Error),
+
+    #[error("unsupported snapshot version {0}, expecting {SNAPSHOT_VERSION}")]
+    VersionError(u32),
+}
+
+/// Snapshot is the full state of a Bookkeeper, so that a batch can go on from where the previous one stopped.
+/// The tx index is not saved, as they're rebuilt from the account histories.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Snapshot<'a> {
+    pub version: u32,
+    pub accounts: Vec<AccountSnapshot<'a>>,
+    pub general_ledger: Cow<'a, GeneralLedger>,
+}
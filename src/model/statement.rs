@@ -0,0 +1,257 @@
+use std::{collections::BTreeMap, io, str::FromStr};
+
+use rust_decimal::Decimal;
+
+use super::{Account, AccountState, Balances, Currency, HistoryEntry, Precision, TxStatus};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatementFormat {
+    Csv,
+    /// aligned columns, for a human
+    Text,
+}
+
+impl StatementFormat {
+    /// extension is the extension of a statement file in this format
+    pub fn extension(&self) -> &'static str {
+        match self {
+            StatementFormat::Csv => "csv",
+            StatementFormat::Text => "txt",
+        }
+    }
+}
+
+impl FromStr for StatementFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "csv" => Ok(StatementFormat::Csv),
+            "text" | "txt" => Ok(StatementFormat::Text),
+            _ => Err(format!("unknown statement format: {}", s)),
+        }
+    }
+}
+
+/// Statement is what happened to an account over a period: the opening balances in every currency,
+/// every transaction applied with the running balances in its currency, and the closing balances
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub client: u16,
+    pub state: AccountState,
+    pub opening: BTreeMap<Currency, Balances>,
+    pub entries: Vec<HistoryEntry>,
+    pub closing: BTreeMap<Currency, Balances>,
+}
+
+impl Statement {
+    /// new makes the statement of acct from the event at index from on, e.g., Account::period_start, or 0 for its whole history.
+    /// Every amount and balance has the places of its currency, as in the report, even the ones which never changed.
+    pub fn new(acct: &Account, from: usize, precision: &Precision) -> Statement {
+        let currencies = acct.currencies();
+        let places = |c: Currency| precision.rule(c).places;
+        let balances = |c: Currency, b: Balances| {
+            (
+                c,
+                Balances {
+                    available: scaled(b.available, places(c)),
+                    held: scaled(b.held, places(c)),
+                    total: scaled(b.total, places(c)),
+                },
+            )
+        };
+        let entries = acct.history_since(from).into_iter().map(|e| HistoryEntry {
+            amount: e.amount.map(|amount| scaled(amount, places(e.currency))),
+            available: scaled(e.available, places(e.currency)),
+            held: scaled(e.held, places(e.currency)),
+            total: scaled(e.total, places(e.currency)),
+            ..e
+        });
+
+        Statement {
+            client: acct.client_id,
+            state: acct.state,
+            opening: currencies.iter().map(|&c| balances(c, acct.balances_after(from, c))).collect(),
+            entries: entries.collect(),
+            closing: currencies.iter().map(|&c| balances(c, acct.balances(c))).collect(),
+        }
+    }
+
+    /// write writes the statement to w. A csv has the opening rows first and the closing rows last, one per currency,
+    /// with the type column telling them apart.
+    pub fn write<W: io::Write>(&self, mut w: W, format: StatementFormat) -> io::Result<()> {
+        match format {
+            StatementFormat::Csv => {
+                let mut w = csv::WriterBuilder::new().has_headers(false).from_writer(w);
+                w.write_record(["tx", "type", "currency", "amount", "status", "available", "held", "total"])?;
+                let balances = |kind: &str, c: &Currency, b: &Balances| {
+                    [String::new(), kind.to_string(), c.to_string(), String::new(), String::new(), b.available.to_string(), b.held.to_string(), b.total.to_string()]
+                };
+                for (currency, opening) in &self.opening {
+                    w.write_record(balances("opening", currency, opening))?;
+                }
+                for entry in &self.entries {
+                    w.serialize(entry)?;
+                }
+                for (currency, closing) in &self.closing {
+                    w.write_record(balances("closing", currency, closing))?;
+                }
+                w.flush()
+            }
+            StatementFormat::Text => {
+                writeln!(w, "client {} ({})", self.client, format!("{:?}", self.state).to_lowercase())?;
+                for (currency, b) in &self.opening {
+                    writeln!(w, "opening {} available {}, held {}, total {}", currency, b.available, b.held, b.total)?;
+                }
+                writeln!(w, "{:>10}  {:<10}  {:<8}  {:>14}  {:<11}  {:>14}  {:>14}  {:>14}", "tx", "type", "currency", "amount", "status", "available", "held", "total")?;
+                for entry in &self.entries {
+                    writeln!(
+                        w,
+                        "{:>10}  {:<10}  {:<8}  {:>14}  {:<11}  {:>14}  {:>14}  {:>14}",
+                        entry.tx,
+                        format!("{:?}", entry.r#type).to_lowercase(),
+                        entry.currency,
+                        entry.amount.as_ref().map(Decimal::to_string).unwrap_or_default(),
+                        status(entry.status),
+                        entry.available,
+                        entry.held,
+                        entry.total
+                    )?;
+                }
+                for (currency, b) in &self.closing {
+                    writeln!(w, "closing {} available {}, held {}, total {}", currency, b.available, b.held, b.total)?;
+                }
+                w.flush()
+            }
+        }
+    }
+}
+
+/// scaled pads amount with zeros up to places, an amount with more places, e.g., from before the precision changed, is kept as is
+fn scaled(mut amount: Decimal, places: u32) -> Decimal {
+    if amount.scale() < places {
+        amount.rescale(places);
+    }
+    amount
+}
+
+fn status(status: Option<TxStatus>) -> String {
+    match status {
+        Some(TxStatus::Deposit(s)) => format!("{:?}", s).to_lowercase(),
+        Some(TxStatus::Withdrawal(s)) => format!("{:?}", s).to_lowercase(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::model::{Bookkeeper, Precision, StatementFormat};
+
+    #[test]
+    fn test_statement() {
+        let input = "type, client, tx, amount, reason
+deposit, 1, 1, 2.5,
+withdrawal, 1, 2, 1.0,
+dispute, 1, 2,,
+freeze, 1, 3,, kyc";
+
+        let mut bkeeper = Bookkeeper::new();
+        bkeeper.process_reader(input.as_bytes()).unwrap();
+
+        let mut buf = Vec::new();
+        bkeeper.statement(1).unwrap().write(&mut buf, StatementFormat::Text).unwrap();
+        let statement = String::from_utf8(buf).unwrap();
+        let lines: Vec<_> = statement.lines().map(|l| l.split_whitespace().collect::<Vec<_>>().join(" ")).collect();
+        assert_eq!(
+            lines,
+            vec![
+                "client 1 (frozen)",
+                "opening USD available 0.0000, held 0.0000, total 0.0000",
+                "tx type currency amount status available held total",
+                "1 deposit USD 2.5000 none 2.5000 0.0000 2.5000",
+                "2 withdrawal USD 1.0000 disputed 1.5000 0.0000 1.5000",
+                "2 dispute USD 1.0000 disputed 1.5000 1.0000 2.5000",
+                "3 freeze USD 1.5000 1.0000 2.5000",
+                "closing USD available 1.5000, held 1.0000, total 2.5000",
+            ]
+        );
+    }
+
+    /// Check that the statements of a batch start from the snapshot of the previous one
+    #[test]
+    fn test_write_statements() {
+        let september = "type, client, tx, amount
+deposit, 1, 1, 3.0
+deposit, 2, 2, 5.0";
+        let october = "type, client, tx, amount
+dispute, 1, 1,
+resolve, 1, 1,
+withdrawal, 1, 3, 1.0
+deposit, 3, 4, 1.0";
+
+        let mut bkeeper = Bookkeeper::new();
+        bkeeper.process_reader(september.as_bytes()).unwrap();
+        let mut snapshot = Vec::new();
+        bkeeper.save_snapshot(&mut snapshot).unwrap();
+
+        let mut bkeeper = Bookkeeper::new();
+        bkeeper.load_snapshot(&snapshot[..]).unwrap();
+        bkeeper.process_reader(october.as_bytes()).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("october");
+        assert_eq!(bkeeper.write_statements(&out, StatementFormat::Csv).unwrap(), 3);
+
+        let statement = fs::read_to_string(out.join("1.csv")).unwrap();
+        assert_eq!(
+            statement,
+            "tx,type,currency,amount,status,available,held,total
+,opening,USD,,,3.0000,0.0000,3.0000
+1,dispute,USD,3.0000,none,0.0000,3.0000,3.0000
+1,resolve,USD,3.0000,none,3.0000,0.0000,3.0000
+3,withdrawal,USD,1.0000,none,2.0000,0.0000,2.0000
+,closing,USD,,,2.0000,0.0000,2.0000
+"
+        );
+
+        // nothing happened to client 2 in october
+        let statement = fs::read_to_string(out.join("2.csv")).unwrap();
+        assert_eq!(statement.lines().skip(1).collect::<Vec<_>>(), vec![",opening,USD,,,5.0000,0.0000,5.0000", ",closing,USD,,,5.0000,0.0000,5.0000"]);
+
+        let statement = fs::read_to_string(out.join("3.csv")).unwrap();
+        assert!(statement.contains(",opening,USD,,,0.0000,0.0000,0.0000\n"));
+    }
+
+    /// Check that every balance has the places of its currency
+    #[test]
+    fn test_statement_places() {
+        let input = "type, client, tx, amount, currency
+deposit, 1, 1, 2, JPY
+deposit, 1, 2, 1.5, EUR
+dispute, 1, 2,, EUR";
+
+        let mut bkeeper = Bookkeeper::new();
+        let mut precision = Precision::new();
+        precision.set("JPY=0".parse().unwrap());
+        precision.set("2".parse().unwrap());
+        bkeeper.set_precision(precision);
+        bkeeper.process_reader(input.as_bytes()).unwrap();
+
+        let mut buf = Vec::new();
+        bkeeper.statement(1).unwrap().write(&mut buf, StatementFormat::Csv).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "tx,type,currency,amount,status,available,held,total
+,opening,EUR,,,0.00,0.00,0.00
+,opening,JPY,,,0,0,0
+1,deposit,JPY,2,none,2,0,2
+2,deposit,EUR,1.50,disputed,1.50,0.00,1.50
+2,dispute,EUR,1.50,disputed,0.00,1.50,1.50
+,closing,EUR,,,0.00,1.50,1.50
+,closing,JPY,,,2,0,2
+"
+        );
+    }
+}
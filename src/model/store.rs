@@ -0,0 +1,181 @@
+use std::{
+    cmp::Reverse,
+    collections::{btree_map, BTreeMap},
+    str::FromStr,
+};
+
+use super::{Account, Currency};
+
+/// ReportOrder is the order of the accounts in a report, every one of them is deterministic
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ReportOrder {
+    #[default]
+    ClientId,
+    /// the largest total in a currency first, ties by client id. An account without the currency has a total of 0.
+    TotalDesc(Currency),
+    /// the order the accounts were opened
+    Insertion,
+}
+
+impl FromStr for ReportOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "client" => Ok(ReportOrder::ClientId),
+            "insertion" => Ok(ReportOrder::Insertion),
+            // the totals of different currencies can't be compared, so it's by one of them
+            other => match other.strip_prefix("total-desc:").map(str::parse) {
+                Some(Ok(currency)) => Ok(ReportOrder::TotalDesc(currency)),
+                _ => Err(format!("unknown report order: {}, expecting client, total-desc:<currency>, e.g., total-desc:USD, or insertion", s)),
+            },
+        }
+    }
+}
+
+/// AccountStore keeps the accounts sorted by client id, and remembers the order they were opened
+pub struct AccountStore {
+    accounts: BTreeMap<u16, Account>,
+    opened: Vec<u16>,
+}
+
+impl AccountStore {
+    pub fn new() -> AccountStore {
+        AccountStore {
+            accounts: BTreeMap::new(),
+            opened: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+
+    pub fn contains_key(&self, client_id: &u16) -> bool {
+        self.accounts.contains_key(client_id)
+    }
+
+    pub fn get(&self, client_id: &u16) -> Option<&Account> {
+        self.accounts.get(client_id)
+    }
+
+    pub fn get_mut(&mut self, client_id: &u16) -> Option<&mut Account> {
+        self.accounts.get_mut(client_id)
+    }
+
+    /// keys returns the client ids in ascending order
+    pub fn keys(&self) -> btree_map::Keys<'_, u16, Account> {
+        self.accounts.keys()
+    }
+
+    /// values returns the accounts by client id
+    pub fn values(&self) -> btree_map::Values<'_, u16, Account> {
+        self.accounts.values()
+    }
+
+    /// ordered returns all the accounts in order
+    pub fn ordered(&self, order: ReportOrder) -> Vec<&Account> {
+        match order {
+            ReportOrder::ClientId => self.accounts.values().collect(),
+            ReportOrder::TotalDesc(currency) => {
+                let mut accounts: Vec<_> = self.accounts.values().collect();
+                // stable, so the ties stay by client id
+                accounts.sort_by_key(|acct| Reverse(acct.balances(currency).total));
+                accounts
+            }
+            ReportOrder::Insertion => self.opened.iter().map(|client_id| &self.accounts[client_id]).collect(),
+        }
+    }
+
+    /// ordered_at returns all the accounts as they were at time, in order, leaving out the ones which had no event yet,
+    /// see Account::at. TotalDesc is by the totals at time.
+    pub fn ordered_at(&self, order: ReportOrder, time: u64) -> Vec<Account> {
+        let by = if let ReportOrder::TotalDesc(_) = order { ReportOrder::ClientId } else { order };
+        let mut accounts: Vec<_> = self.ordered(by).into_iter().filter_map(|acct| acct.at(time)).collect();
+        if let ReportOrder::TotalDesc(currency) = order {
+            // stable, so the ties stay by client id
+            accounts.sort_by_key(|acct| Reverse(acct.balances(currency).total));
+        }
+        accounts
+    }
+
+    /// open returns the account of client_id, opening an empty one if needed
+    pub(crate) fn open(&mut self, client_id: u16) -> &mut Account {
+        match self.accounts.entry(client_id) {
+            btree_map::Entry::Occupied(e) => e.into_mut(),
+            btree_map::Entry::Vacant(e) => {
+                self.opened.push(client_id);
+                e.insert(Account::new(client_id))
+            }
+        }
+    }
+
+    /// insert adds acct, or replaces the account of the same client keeping its place in the insertion order
+    pub(crate) fn insert(&mut self, acct: Account) {
+        let client_id = acct.client_id;
+        if self.accounts.insert(client_id, acct).is_none() {
+            self.opened.push(client_id);
+        }
+    }
+
+    /// into_opened returns all the accounts in the order they were opened
+    pub(crate) fn into_opened(mut self) -> Vec<Account> {
+        self.opened.iter().filter_map(|client_id| self.accounts.remove(client_id)).collect()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.accounts.clear();
+        self.opened.clear();
+    }
+}
+
+impl Default for AccountStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rust_decimal::Decimal;
+
+    use crate::model::{AccountStore, Balances, Currency, ReportOrder};
+
+    #[test]
+    fn test_report_order() {
+        let mut store = AccountStore::new();
+        for (client_id, total) in [(3, 1), (1, 5), (2, 1), (4, 7)] {
+            let total = Decimal::from(total);
+            store.open(client_id).buckets.insert(Currency::USD, Balances { total, ..Balances::default() });
+        }
+        store.open(1);
+
+        let ids = |order| store.ordered(order).iter().map(|acct| acct.client_id).collect::<Vec<_>>();
+        assert_eq!(ids(ReportOrder::ClientId), vec![1, 2, 3, 4]);
+        assert_eq!(ids(ReportOrder::TotalDesc(Currency::USD)), vec![4, 1, 2, 3]);
+        assert_eq!(ids(ReportOrder::Insertion), vec![3, 1, 2, 4]);
+    }
+
+    #[test]
+    fn test_total_desc_currency() {
+        // none of them holds USD, and 5 holds no EUR
+        let mut store = AccountStore::new();
+        for (client_id, currency, total) in [(1, Currency::EUR, 2), (2, Currency::GBP, 9), (3, Currency::EUR, 7), (4, Currency::GBP, 1), (5, Currency::GBP, 3), (3, Currency::GBP, 2)] {
+            let total = Decimal::from(total);
+            store.open(client_id).buckets.insert(currency, Balances { total, ..Balances::default() });
+        }
+
+        let ids = |order| store.ordered(order).iter().map(|acct| acct.client_id).collect::<Vec<_>>();
+        assert_eq!(ids("total-desc:eur".parse().unwrap()), vec![3, 1, 2, 4, 5]);
+        assert_eq!(ids("total-desc:GBP".parse().unwrap()), vec![2, 5, 3, 4, 1]);
+        assert_eq!(ids("total-desc:USD".parse().unwrap()), vec![1, 2, 3, 4, 5]);
+
+        // a total can only be compared in one currency
+        assert!("total-desc".parse::<ReportOrder>().is_err());
+        assert!("total-desc:EURO".parse::<ReportOrder>().is_err());
+    }
+}
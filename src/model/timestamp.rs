@@ -0,0 +1,170 @@
+use std::{fmt, str::FromStr};
+
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+const DAY: i64 = 24 * 60 * 60;
+
+/// Timestamp is a time in seconds since the unix epoch. It's read as RFC 3339, e.g., 2026-09-30T23:59:59Z or
+/// 2026-10-01T01:59:59+02:00, or as a number of seconds, and written as RFC 3339 in UTC. A fraction of a second is dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(pub u64);
+
+impl FromStr for Timestamp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit()) {
+            return s.parse().map(Timestamp).map_err(|_| format!("invalid timestamp: {}", s));
+        }
+        parse_rfc3339(s).ok_or_else(|| format!("invalid timestamp: {}, expecting RFC 3339, e.g., 2026-09-30T23:59:59Z, or seconds since the unix epoch", s))
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let days = (self.0 / DAY as u64) as i64;
+        let seconds = self.0 % DAY as u64;
+        let (year, month, day) = civil_from_days(days);
+        write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60)
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    /// deserialize takes a string, or a number of seconds, e.g., from a JSON line
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TimestampVisitor;
+
+        impl de::Visitor<'_> for TimestampVisitor {
+            type Value = Timestamp;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("an RFC 3339 timestamp or seconds since the unix epoch")
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Timestamp, E> {
+                Ok(Timestamp(v))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Timestamp, E> {
+                u64::try_from(v).map(Timestamp).map_err(|_| E::custom(format!("invalid timestamp: {}", v)))
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Timestamp, E> {
+                v.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(TimestampVisitor)
+    }
+}
+
+/// parse_rfc3339 parses YYYY-MM-DDTHH:MM:SS[.fraction](Z|+HH:MM|-HH:MM), or None if it's not one, or before the epoch
+fn parse_rfc3339(s: &str) -> Option<Timestamp> {
+    let b = s.as_bytes();
+    let number = |from: usize, len: usize| -> Option<i64> {
+        let digits = b.get(from..from + len)?;
+        digits.iter().all(u8::is_ascii_digit).then(|| digits.iter().fold(0, |n, d| n * 10 + (d - b'0') as i64))
+    };
+    let separated = |at: usize, chars: &[u8]| b.get(at).is_some_and(|c| chars.contains(c));
+    if !(separated(4, b"-") && separated(7, b"-") && separated(10, b"Tt ") && separated(13, b":") && separated(16, b":")) {
+        return None;
+    }
+
+    let (year, month, day) = (number(0, 4)?, number(5, 2)?, number(8, 2)?);
+    let (hour, minute, second) = (number(11, 2)?, number(14, 2)?, number(17, 2)?);
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+
+    let mut rest = &s[19..];
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let digits = fraction.bytes().take_while(u8::is_ascii_digit).count();
+        if digits == 0 {
+            return None;
+        }
+        rest = &fraction[digits..];
+    }
+    let offset = match rest.as_bytes() {
+        [b'Z' | b'z'] => 0,
+        [sign @ (b'+' | b'-'), _, _, b':', _, _] => {
+            let (hours, minutes) = (rest[1..3].parse::<i64>().ok()?, rest[4..6].parse::<i64>().ok()?);
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            let offset = hours * 3600 + minutes * 60;
+            if *sign == b'-' {
+                -offset
+            } else {
+                offset
+            }
+        }
+        _ => return None,
+    };
+
+    let seconds = days_from_civil(year, month, day) * DAY + hour * 3600 + minute * 60 + second - offset;
+    u64::try_from(seconds).ok().map(Timestamp)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// days_from_civil returns the number of days since 1970-01-01 of a date of the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// civil_from_days is the reverse of days_from_civil
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    (year_of_era + era * 400 + (month <= 2) as i64, month, day)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::model::Timestamp;
+
+    #[test]
+    fn test_timestamp() {
+        let parse = |s: &str| s.parse::<Timestamp>().map(|t| t.0);
+        assert_eq!(parse("1970-01-01T00:00:00Z"), Ok(0));
+        assert_eq!(parse("2026-09-30T23:59:59Z"), Ok(1790812799));
+        assert_eq!(parse("2026-10-01T01:59:59.999+02:00"), Ok(1790812799));
+        assert_eq!(parse("2026-09-30 18:59:59-05:00"), Ok(1790812799));
+        assert_eq!(parse("2024-02-29T12:00:00z"), Ok(1709208000));
+        assert_eq!(parse(" 1790812799 "), Ok(1790812799));
+        for invalid in ["", "2026-09-30", "2026-09-30T23:59:59", "2026-02-29T00:00:00Z", "2026-09-30T24:00:00Z", "1969-12-31T23:59:59Z", "2026-09-30T23:59:59.Z", "-1", "1.5"] {
+            assert!(invalid.parse::<Timestamp>().is_err(), "{}", invalid);
+        }
+
+        assert_eq!(Timestamp(1790812799).to_string(), "2026-09-30T23:59:59Z");
+        assert_eq!(Timestamp(1709208000).to_string(), "2024-02-29T12:00:00Z");
+        assert_eq!(serde_json::from_str::<Timestamp>("1790812799").unwrap(), Timestamp(1790812799));
+        assert_eq!(serde_json::from_str::<Timestamp>("\"2026-09-30T23:59:59Z\"").unwrap(), Timestamp(1790812799));
+        assert_eq!(serde_json::to_string(&Timestamp(0)).unwrap(), "\"1970-01-01T00:00:00Z\"");
+    }
+}
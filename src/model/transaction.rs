@@ -1,6 +1,8 @@
 use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
 
+use super::{Currency, Timestamp};
+
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum TxType {
@@ -9,15 +11,35 @@ pub enum TxType {
     Dispute,
     Resolve,
     ChargeBack,
+    /// reinstates a frozen or locked account, e.g., after a chargeback investigation
+    Unlock,
+    Freeze,
+    Close,
+}
+
+impl TxType {
+    /// is_admin checks if it's an operation on the account itself, rather than on funds
+    pub fn is_admin(&self) -> bool {
+        *self == TxType::Unlock || *self == TxType::Freeze || *self == TxType::Close
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[serde(rename_all = "lowercase")]
 pub struct Transaction {
     pub r#type: TxType,
-    #[serde(rename(deserialize = "client"))]
+    #[serde(rename = "client")]
     pub client_id: u16,
-    #[serde(rename(deserialize = "tx"))]
+    #[serde(rename = "tx")]
     pub tx_id: u32,
     pub amount: Option<Decimal>,
+    /// the currency of amount, the default one if None. A dispute/resolve/chargeback may give it, it must then be the one of its tx.
+    #[serde(default)]
+    pub currency: Option<Currency>,
+    /// the admin reason for unlock/freeze/close
+    #[serde(default)]
+    pub reason: Option<String>,
+    /// when it happened, the time it's accepted at if None, see Bookkeeper::set_strict_time
+    #[serde(default)]
+    pub timestamp: Option<Timestamp>,
 }
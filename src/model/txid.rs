@@ -0,0 +1,171 @@
+use std::sync::{
+    atomic::{AtomicU16, AtomicU64, Ordering},
+    OnceLock,
+};
+
+const PAGE_BITS: u32 = 16;
+const PAGE_SIZE: usize = 1 << PAGE_BITS;
+const PAGE_WORDS: usize = PAGE_SIZE / 64;
+const PAGE_COUNT: usize = 1 << (32 - PAGE_BITS);
+
+/// Page is the ids of a range which are taken and the ones whose owner is set, a bit each, and the owner of each of them
+struct Page {
+    taken: Box<[AtomicU64]>,
+    owned: Box<[AtomicU64]>,
+    owners: Box<[AtomicU16]>,
+}
+
+/// TxIndex maps every tx id of the whole ledger to the client owning it.
+///
+/// It's split into 64K pages of 144KB each, bitmaps of the used ids and their owners, and a page is only allocated
+/// when an id in its range is used, so sparse ids cost little. It can be shared by the shards of the parallel engine,
+/// an id is only taken once, and its owner is visible to whoever sees the insert, e.g., the shard which took it.
+pub struct TxIndex {
+    pages: Box<[OnceLock<Page>]>,
+    len: AtomicU64,
+}
+
+impl TxIndex {
+    pub fn new() -> TxIndex {
+        TxIndex {
+            pages: (0..PAGE_COUNT).map(|_| OnceLock::new()).collect(),
+            len: AtomicU64::new(0),
+        }
+    }
+
+    pub fn contains(&self, tx_id: u32) -> bool {
+        self.owner(tx_id).is_some()
+    }
+
+    /// owner returns the client owning tx_id, if any
+    pub fn owner(&self, tx_id: u32) -> Option<u16> {
+        let (page, offset) = Self::locate(tx_id);
+        let p = self.pages[page].get()?;
+        if p.owned[offset / 64].load(Ordering::Acquire) & (1 << (offset % 64)) == 0 {
+            return None;
+        }
+        Some(p.owners[offset].load(Ordering::Acquire))
+    }
+
+    /// insert returns false, and keeps the existing owner, if tx_id is already in the index
+    pub fn insert(&self, tx_id: u32, client_id: u16) -> bool {
+        let (page, offset) = Self::locate(tx_id);
+        let p = self.pages[page].get_or_init(|| Page {
+            taken: (0..PAGE_WORDS).map(|_| AtomicU64::new(0)).collect(),
+            owned: (0..PAGE_WORDS).map(|_| AtomicU64::new(0)).collect(),
+            owners: (0..PAGE_SIZE).map(|_| AtomicU16::new(0)).collect(),
+        });
+
+        // the id is taken once, and only seen once its owner is set
+        let bit = 1 << (offset % 64);
+        if p.taken[offset / 64].fetch_or(bit, Ordering::AcqRel) & bit != 0 {
+            return false;
+        }
+        p.owners[offset].store(client_id, Ordering::Relaxed);
+        p.owned[offset / 64].fetch_or(bit, Ordering::Release);
+        self.len.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn locate(tx_id: u32) -> (usize, usize) {
+        ((tx_id >> PAGE_BITS) as usize, (tx_id as usize) & (PAGE_SIZE - 1))
+    }
+}
+
+impl Default for TxIndex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// a slot of TxIdShards for an id used by the rows of more than one shard
+const CONTESTED: u8 = u8::MAX;
+
+/// MAX_SHARDS is the most shards the parallel engine runs, the others take the slots of TxIdShards
+pub const MAX_SHARDS: usize = CONTESTED as usize - 1;
+
+/// TxIdShards tells the shard of the parallel engine the rows using a tx id are routed to, or that it's contested.
+///
+/// It's one byte per id, in pages allocated as the ones of TxIndex, so the memory doesn't grow with the number of shards.
+pub struct TxIdShards {
+    pages: Box<[Option<Box<[u8]>>]>,
+}
+
+impl TxIdShards {
+    pub fn new() -> TxIdShards {
+        TxIdShards {
+            pages: (0..PAGE_COUNT).map(|_| None).collect(),
+        }
+    }
+
+    /// route records that a row of shard uses tx_id, and returns true once it's used by the rows of more than one shard
+    pub fn route(&mut self, tx_id: u32, shard: usize) -> bool {
+        assert!(shard < MAX_SHARDS, "shard {} out of {}", shard, MAX_SHARDS);
+        let (page, offset) = TxIndex::locate(tx_id);
+        let slot = &mut self.pages[page].get_or_insert_with(|| vec![0; PAGE_SIZE].into_boxed_slice())[offset];
+
+        // 0 is an unused id, and the others are the shard + 1
+        let shard = shard as u8 + 1;
+        if *slot == 0 {
+            *slot = shard;
+        } else if *slot != shard {
+            *slot = CONTESTED;
+        }
+        *slot == CONTESTED
+    }
+}
+
+impl Default for TxIdShards {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::model::{TxIdShards, TxIndex, MAX_SHARDS};
+
+    #[test]
+    fn test_insert_owner() {
+        let ids = TxIndex::new();
+        assert!(ids.is_empty());
+
+        for (i, id) in [0, 1, 63, 64, 65535, 65536, u32::MAX].into_iter().enumerate() {
+            let client_id = i as u16;
+            assert!(ids.owner(id).is_none());
+            assert!(ids.insert(id, client_id));
+            assert_eq!(ids.owner(id), Some(client_id));
+            assert!(!ids.insert(id, client_id + 1));
+            assert_eq!(ids.owner(id), Some(client_id));
+        }
+
+        assert_eq!(ids.len(), 7);
+        assert!(!ids.contains(2));
+        assert!(!ids.contains(u32::MAX - 1));
+        assert!(ids.insert(1 << 20, u16::MAX));
+        assert_eq!(ids.owner(1 << 20), Some(u16::MAX));
+    }
+
+    #[test]
+    fn test_shards() {
+        let mut shards = TxIdShards::new();
+        assert!(!shards.route(1, 0));
+        assert!(!shards.route(1, 0));
+        assert!(!shards.route(2, MAX_SHARDS - 1));
+        assert!(!shards.route(u32::MAX, 3));
+
+        // once contested, an id stays so whatever the shard
+        assert!(shards.route(1, 1));
+        assert!(shards.route(1, 0));
+        assert!(shards.route(2, 0));
+        assert!(!shards.route(u32::MAX, 3));
+    }
+}
@@ -0,0 +1,8 @@
+pub mod ledger;
+pub use ledger::*;
+
+pub mod tcp;
+pub use tcp::*;
+
+pub mod http;
+pub use http::*;
@@ -0,0 +1,345 @@
+use std::{future::Future, io};
+
+use axum::{
+    extract::{
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+        Path, Query, State,
+    },
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+
+use crate::model::{Account, Bookkeeper, HistoryEntry, InputRecord, ReportOrder, Timestamp, TxError};
+
+use super::{Ack, Ledger};
+
+/// serve_http serves the REST API on listener until shutdown completes, see router.
+/// It returns keeper once the requests in progress are done.
+pub async fn serve_http<F>(listener: TcpListener, keeper: Bookkeeper, shutdown: F) -> io::Result<Bookkeeper>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let (ledger, task) = Ledger::start(keeper);
+    serve_api(listener, ledger, shutdown).await?;
+
+    task.await.map_err(io::Error::other)?
+}
+
+/// serve_api serves the REST API of ledger on listener until shutdown completes
+pub async fn serve_api<F>(listener: TcpListener, ledger: Ledger, shutdown: F) -> io::Result<()>
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    axum::serve(listener, router(ledger)).with_graceful_shutdown(shutdown).await
+}
+
+/// router is the REST API of ledger, with JSON bodies:
+///
+/// POST /transactions, a transaction object, or an array of them applied in order
+/// GET /accounts, the report rows of all the accounts, one per client per currency, ?order=client|total-desc:<currency>|insertion,
+/// and ?as_of=2026-09-30T23:59:59Z for the balances at that time, see Account::at
+/// GET /accounts/{client}, the report rows of the account
+/// GET /accounts/{client}/transactions, the history of the account with its running balances, see Account::history
+pub fn router(ledger: Ledger) -> Router {
+    Router::new()
+        .route("/transactions", post(post_transactions))
+        .route("/accounts", get(get_accounts))
+        .route("/accounts/{client}", get(get_account))
+        .route("/accounts/{client}/transactions", get(get_transactions))
+        .with_state(ledger)
+}
+
+/// ApiError is an error response, with a reason code as in the rejection report
+struct ApiError {
+    status: StatusCode,
+    reason: &'static str,
+    detail: String,
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(json!({ "reason": self.reason, "detail": self.detail }))).into_response()
+    }
+}
+
+impl From<io::Error> for ApiError {
+    fn from(e: io::Error) -> Self {
+        ApiError {
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            reason: "unavailable",
+            detail: e.to_string(),
+        }
+    }
+}
+
+/// a body which isn't a JSON transaction, or an array of them, is rejected as a malformed row
+impl From<JsonRejection> for ApiError {
+    fn from(e: JsonRejection) -> Self {
+        ApiError {
+            status: e.status(),
+            reason: TxError::InvaidFormatError.code(),
+            detail: e.body_text(),
+        }
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(e: PathRejection) -> Self {
+        ApiError {
+            status: e.status(),
+            reason: "invalid_path",
+            detail: e.body_text(),
+        }
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(e: QueryRejection) -> Self {
+        ApiError {
+            status: e.status(),
+            reason: "invalid_query",
+            detail: e.body_text(),
+        }
+    }
+}
+
+fn no_account(client_id: u16) -> ApiError {
+    ApiError {
+        status: StatusCode::NOT_FOUND,
+        reason: TxError::InvalidClientError.code(),
+        detail: format!("no account for client {}", client_id),
+    }
+}
+
+/// status is the status code of a rejected transaction
+fn status(reason: &TxError) -> StatusCode {
+    match reason {
+        TxError::InvaidFormatError | TxError::MissingAmountError | TxError::InvalidAmountError | TxError::ExcessPrecisionError => StatusCode::UNPROCESSABLE_ENTITY,
+        TxError::InvalidClientError => StatusCode::NOT_FOUND,
+        TxError::InvalidTxIdError
+        | TxError::DuplicateTxIdError
+        | TxError::ClientMismatchError { .. }
+        | TxError::CurrencyMismatchError
+        | TxError::InvalidOperatioonError
+        | TxError::OutOfOrderError { .. } => StatusCode::CONFLICT,
+        TxError::LockedAccountError | TxError::FrozenAccountError | TxError::ClosedAccountError => StatusCode::LOCKED,
+        TxError::AmountLimitError { .. } | TxError::TotalLimitError { .. } => StatusCode::FORBIDDEN,
+        TxError::CountLimitError { .. } => StatusCode::TOO_MANY_REQUESTS,
+    }
+}
+
+/// post_transactions answers a single transaction with its ack, 201 if it's applied or the status of its rejection.
+/// A batch is applied in order, each transaction on its own, and answered 200 with all the acks.
+async fn post_transactions(State(ledger): State<Ledger>, body: Result<Json<Value>, JsonRejection>) -> Result<Response, ApiError> {
+    let Json(body) = body?;
+    let record = |line, value: Value| InputRecord::from_json(line, value.to_string(), value);
+
+    match body {
+        Value::Array(batch) => {
+            // all of them are queued first, the ledger keeps their order
+            let mut pending = Vec::with_capacity(batch.len());
+            for (i, value) in batch.into_iter().enumerate() {
+                pending.push(ledger.submit(record(i as u64 + 1, value)).await?);
+            }
+
+            let mut acks = Vec::with_capacity(pending.len());
+            for ack in pending {
+                acks.push(ack.await.map_err(io::Error::other)?);
+            }
+            Ok((StatusCode::OK, Json(acks)).into_response())
+        }
+        value => {
+            let ack = ledger.apply(record(1, value)).await?;
+            let status = match &ack {
+                Ack::Ok { .. } => StatusCode::CREATED,
+                Ack::Rejected(rejection) => status(&rejection.reason),
+            };
+            Ok((status, Json(ack)).into_response())
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct AccountsParams {
+    order: Option<String>,
+    as_of: Option<String>,
+}
+
+/// json is a response with a body serialized on the ledger, keeping the field order of the report
+fn json(body: serde_json::Result<String>) -> Result<Response, ApiError> {
+    let body = body.map_err(io::Error::from)?;
+    Ok(([(header::CONTENT_TYPE, "application/json")], body).into_response())
+}
+
+async fn get_accounts(State(ledger): State<Ledger>, params: Result<Query<AccountsParams>, QueryRejection>) -> Result<Response, ApiError> {
+    let Query(params) = params?;
+    let order = match params.order.as_deref().map(str::parse::<ReportOrder>) {
+        None => ReportOrder::default(),
+        Some(Ok(order)) => order,
+        Some(Err(e)) => {
+            return Err(ApiError {
+                status: StatusCode::BAD_REQUEST,
+                reason: "invalid_order",
+                detail: e,
+            })
+        }
+    };
+
+    let as_of = match params.as_of.as_deref().map(str::parse::<Timestamp>) {
+        None => None,
+        Some(Ok(time)) => Some(time),
+        Some(Err(e)) => {
+            return Err(ApiError {
+                status: StatusCode::BAD_REQUEST,
+                reason: "invalid_as_of",
+                detail: e,
+            })
+        }
+    };
+
+    json(
+        ledger
+            .query(move |keeper| {
+                match as_of {
+                    Some(time) => serde_json::to_string(&keeper.accounts.ordered_at(order, time.0).iter().flat_map(Account::report_rows).collect::<Vec<_>>()),
+                    None => serde_json::to_string(&keeper.accounts.ordered(order).into_iter().flat_map(Account::report_rows).collect::<Vec<_>>()),
+                }
+            })
+            .await?,
+    )
+}
+
+async fn get_account(State(ledger): State<Ledger>, client_id: Result<Path<u16>, PathRejection>) -> Result<Response, ApiError> {
+    let Path(client_id) = client_id?;
+    let account = ledger.query(move |keeper| keeper.accounts.get(&client_id).map(|acct| serde_json::to_string(&acct.report_rows().collect::<Vec<_>>()))).await?;
+    json(account.ok_or_else(|| no_account(client_id))?)
+}
+
+async fn get_transactions(State(ledger): State<Ledger>, client_id: Result<Path<u16>, PathRejection>) -> Result<Json<Vec<HistoryEntry>>, ApiError> {
+    let Path(client_id) = client_id?;
+    let transactions = ledger.query(move |keeper| keeper.history(client_id)).await?;
+    transactions.map(Json).ok_or_else(|| no_account(client_id))
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{
+        body::{self, Body},
+        http::{Request, StatusCode},
+        Router,
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    use crate::{model::Bookkeeper, server::Ledger};
+
+    use super::router;
+
+    async fn call(app: &Router, method: &str, uri: &str, body: Option<Value>) -> (StatusCode, Value) {
+        send(app, method, uri, body.map_or_else(Body::empty, |body| Body::from(body.to_string()))).await
+    }
+
+    async fn send(app: &Router, method: &str, uri: &str, body: Body) -> (StatusCode, Value) {
+        let request = Request::builder().method(method).uri(uri).header("content-type", "application/json");
+        let request = request.body(body).unwrap();
+
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn test_api() {
+        let (ledger, _) = Ledger::start(Bookkeeper::new());
+        let app = router(ledger);
+
+        let deposit = json!({"type": "deposit", "client": 1, "tx": 1, "amount": 2.5});
+        let (status, ack) = call(&app, "POST", "/transactions", Some(deposit.clone())).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(ack["status"], "ok");
+
+        let (status, ack) = call(&app, "POST", "/transactions", Some(deposit)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(ack["reason"], "invalid_tx_id");
+
+        let batch = json!([
+            {"type": "withdrawal", "client": 1, "tx": 2, "amount": "5.0"},
+            {"type": "withdrawal", "client": 1, "tx": 3, "amount": "0.5"},
+            {"type": "dispute", "client": 1, "tx": 3},
+            {"type": "deposit", "client": 2, "tx": 4},
+            {"type": "freeze", "client": 1, "tx": 5, "reason": "kyc"},
+            {"type": "deposit", "client": 1, "tx": 6, "amount": "1"}
+        ]);
+        let (status, acks) = call(&app, "POST", "/transactions", Some(batch)).await;
+        assert_eq!(status, StatusCode::OK);
+        let reasons: Vec<_> = acks.as_array().unwrap().iter().map(|ack| ack.get("reason").and_then(Value::as_str).unwrap_or("ok")).collect();
+        assert_eq!(reasons, vec!["invalid_amount", "ok", "ok", "missing_amount", "ok", "frozen_account"]);
+
+        let (status, ack) = call(&app, "POST", "/transactions", Some(json!({"type": "deposit", "client": 1, "tx": 7, "amount": 1}))).await;
+        assert_eq!(status, StatusCode::LOCKED);
+        assert_eq!(ack["line"], 1);
+        let (status, _) = call(&app, "POST", "/transactions", Some(json!({"type": "deposit", "client": 3, "tx": 8}))).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+
+        let (status, account) = call(&app, "GET", "/accounts/1", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(account, json!([{"client": 1, "currency": "USD", "available": "2.0000", "held": "0.5000", "total": "2.5000", "fees": "0", "locked": false, "state": "frozen"}]));
+
+        let (status, error) = call(&app, "GET", "/accounts/2", None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(error["reason"], "invalid_client");
+
+        let (status, transactions) = call(&app, "GET", "/accounts/1/transactions", None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            transactions,
+            json!([
+                {"tx": 1, "type": "deposit", "currency": "USD", "amount": "2.5000", "status": "none", "available": "2.5000", "held": "0", "total": "2.5000"},
+                {"tx": 3, "type": "withdrawal", "currency": "USD", "amount": "0.5000", "status": "disputed", "available": "2.0000", "held": "0", "total": "2.0000"},
+                {"tx": 3, "type": "dispute", "currency": "USD", "amount": "0.5000", "status": "disputed", "available": "2.0000", "held": "0.5000", "total": "2.5000"},
+                {"tx": 5, "type": "freeze", "currency": "USD", "amount": null, "status": null, "available": "2.0000", "held": "0.5000", "total": "2.5000"}
+            ])
+        );
+
+        call(&app, "POST", "/transactions", Some(json!({"type": "deposit", "client": 9, "tx": 9, "amount": 9}))).await;
+        let (_, accounts) = call(&app, "GET", "/accounts?order=total-desc:USD", None).await;
+        let clients: Vec<_> = accounts.as_array().unwrap().iter().map(|acct| acct["client"].clone()).collect();
+        assert_eq!(clients, vec![json!(9), json!(1)]);
+        let (status, _) = call(&app, "GET", "/accounts?order=random", None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        // the transactions without a timestamp are counted at any time, unlike the ones which happened later
+        call(&app, "POST", "/transactions", Some(json!({"type": "deposit", "client": 10, "tx": 10, "amount": 1, "timestamp": "2026-09-30T23:59:59Z"}))).await;
+        let (status, accounts) = call(&app, "GET", "/accounts?as_of=1970-01-01T00:00:00Z", None).await;
+        let clients: Vec<_> = accounts.as_array().unwrap().iter().map(|acct| acct["client"].clone()).collect();
+        assert_eq!((status, clients), (StatusCode::OK, vec![json!(1), json!(9)]));
+        let (status, error) = call(&app, "GET", "/accounts?as_of=yesterday", None).await;
+        assert_eq!((status, error["reason"].clone()), (StatusCode::BAD_REQUEST, json!("invalid_as_of")));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_requests() {
+        let (ledger, _) = Ledger::start(Bookkeeper::new());
+        let app = router(ledger);
+
+        // every error is a JSON body with a reason, even the ones of the extractors
+        let reason = |(status, error): (StatusCode, Value)| (status, error["reason"].as_str().map(str::to_string), error["detail"].is_string());
+        let malformed = |reason: &str, status| (status, Some(reason.to_string()), true);
+        assert_eq!(reason(send(&app, "POST", "/transactions", Body::from("{\"type\": \"deposit\",")).await), malformed("invalid_format", StatusCode::BAD_REQUEST));
+        assert_eq!(reason(send(&app, "POST", "/transactions", Body::from("deposit, 1, 1, 1.0")).await), malformed("invalid_format", StatusCode::BAD_REQUEST));
+        assert_eq!(reason(send(&app, "POST", "/transactions", Body::empty()).await), malformed("invalid_format", StatusCode::BAD_REQUEST));
+        assert_eq!(reason(call(&app, "GET", "/accounts/abc", None).await), malformed("invalid_path", StatusCode::BAD_REQUEST));
+        assert_eq!(reason(call(&app, "GET", "/accounts/70000/transactions", None).await), malformed("invalid_path", StatusCode::BAD_REQUEST));
+        assert_eq!(reason(call(&app, "GET", "/accounts?as_of=1&as_of=2", None).await), malformed("invalid_query", StatusCode::BAD_REQUEST));
+
+        // a well formed JSON value which isn't a transaction is rejected by the ledger as any other row
+        let (status, ack) = call(&app, "POST", "/transactions", Some(json!({"type": "deposit"}))).await;
+        assert_eq!((status, ack["reason"].clone()), (StatusCode::UNPROCESSABLE_ENTITY, json!("invalid_format")));
+    }
+}
@@ -0,0 +1,100 @@
+use std::io;
+
+use serde::Serialize;
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::{self, JoinHandle},
+};
+
+use crate::model::{Bookkeeper, InputRecord, Rejection};
+
+/// requests waiting for the ledger
+const QUEUE_SIZE: usize = 4096;
+
+/// Ack is the answer to a submitted record
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum Ack {
+    Ok { line: u64 },
+    Rejected(Rejection),
+}
+
+type Query = Box<dyn FnOnce(&Bookkeeper) + Send>;
+
+enum Request {
+    Apply(InputRecord, oneshot::Sender<Ack>),
+    Query(Query),
+}
+
+/// Ledger is a handle to a Bookkeeper running on its own thread, which applies the records of all the handles one at a
+/// time in the order they arrive, so the ledger is always consistent. It stops once all the handles are dropped.
+#[derive(Clone)]
+pub struct Ledger {
+    requests: mpsc::Sender<Request>,
+}
+
+impl Ledger {
+    /// start runs keeper, the task returns it once all the handles are dropped, or a journal failure
+    pub fn start(keeper: Bookkeeper) -> (Ledger, JoinHandle<io::Result<Bookkeeper>>) {
+        let (requests, rx) = mpsc::channel(QUEUE_SIZE);
+        // the ledger has its own thread, as the journal may block on a fsync
+        let task = task::spawn_blocking(move || run(keeper, rx));
+
+        (Ledger { requests }, task)
+    }
+
+    /// submit queues record, the ack comes once it's applied or rejected
+    pub async fn submit(&self, record: InputRecord) -> io::Result<oneshot::Receiver<Ack>> {
+        let (reply, ack) = oneshot::channel();
+        self.send(Request::Apply(record, reply)).await?;
+        Ok(ack)
+    }
+
+    /// apply applies record, or rejects it
+    pub async fn apply(&self, record: InputRecord) -> io::Result<Ack> {
+        self.submit(record).await?.await.map_err(|_| stopped())
+    }
+
+    /// query runs f on the ledger, after all the records submitted before
+    pub async fn query<T, F>(&self, f: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Bookkeeper) -> T + Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        self.send(Request::Query(Box::new(move |keeper| {
+            let _ = reply.send(f(keeper));
+        })))
+        .await?;
+
+        result.await.map_err(|_| stopped())
+    }
+
+    async fn send(&self, request: Request) -> io::Result<()> {
+        self.requests.send(request).await.map_err(|_| stopped())
+    }
+}
+
+fn stopped() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "the ledger stopped")
+}
+
+fn run(mut keeper: Bookkeeper, mut requests: mpsc::Receiver<Request>) -> io::Result<Bookkeeper> {
+    while let Some(request) = requests.blocking_recv() {
+        match request {
+            Request::Apply(record, reply) => {
+                let line = record.line;
+                let ack = match keeper.process_record(record)? {
+                    None => Ack::Ok { line },
+                    Some(rejection) => Ack::Rejected(rejection),
+                };
+
+                // the requester may be gone already, the record is applied anyway
+                let _ = reply.send(ack);
+            }
+            Request::Query(query) => query(&keeper),
+        }
+    }
+
+    Ok(keeper)
+}
@@ -0,0 +1,284 @@
+use std::{future::Future, io};
+
+use log::*;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, oneshot, watch},
+};
+
+use crate::model::{Bookkeeper, LineDecoder, Rejection, TxError};
+
+use super::{Ack, Ledger};
+
+/// lines of a feed waiting for their ack, before reading more of it
+const IN_FLIGHT: usize = 1024;
+
+/// the longest line of a feed, only its start is kept for the rejection of a longer one
+const MAX_LINE: usize = 64 * 1024;
+
+/// serve accepts feeds on listener until shutdown completes, and applies all of them to keeper, see serve_feeds.
+/// It returns keeper once the lines already read are applied.
+pub async fn serve<F>(listener: TcpListener, keeper: Bookkeeper, shutdown: F) -> io::Result<Bookkeeper>
+where
+    F: Future<Output = ()>,
+{
+    let (ledger, task) = Ledger::start(keeper);
+    serve_feeds(listener, ledger, shutdown).await;
+
+    task.await.map_err(io::Error::other)?
+}
+
+/// serve_feeds accepts feeds on listener until shutdown completes. A feed is a csv with a header row, or JSON lines,
+/// and every line gets an Ack back on the same connection, one JSON object per line in the order of the lines.
+/// A line which is not valid UTF-8, or longer than MAX_LINE, is rejected with invalid_format, and so is a header
+/// which can't be read, the next line being taken as the header then.
+/// The feeds in progress stop reading on shutdown, and go on until the lines already read are acked.
+pub async fn serve_feeds<F>(listener: TcpListener, ledger: Ledger, shutdown: F)
+where
+    F: Future<Output = ()>,
+{
+    let (stop, stopped) = watch::channel(false);
+
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    debug!("feed from {}", peer);
+                    let (ledger, stopped) = (ledger.clone(), stopped.clone());
+                    tokio::spawn(async move {
+                        if let Err(e) = handle(stream, ledger, stopped).await {
+                            warn!("feed from {} failed: {}", peer, e);
+                        }
+                    });
+                }
+                // e.g., too many open files, the feeds in progress go on
+                Err(e) => error!("failed to accept a feed: {}", e),
+            },
+            _ = &mut shutdown => break,
+        }
+    }
+
+    info!("no more feeds");
+    stop.send_replace(true);
+}
+
+async fn handle(stream: TcpStream, ledger: Ledger, mut stopped: watch::Receiver<bool>) -> io::Result<()> {
+    let (r, w) = stream.into_split();
+    // the acks to write, in the order of the lines
+    let (pending, mut acks) = mpsc::channel::<oneshot::Receiver<Ack>>(IN_FLIGHT);
+
+    let writer = tokio::spawn(async move {
+        let mut w = BufWriter::new(w);
+        while let Some(ack) = acks.recv().await {
+            let ack = ack.await.map_err(io::Error::other)?;
+            let mut buf = serde_json::to_vec(&ack)?;
+            buf.push(b'\n');
+            w.write_all(&buf).await?;
+
+            if acks.is_empty() {
+                w.flush().await?;
+            }
+        }
+        w.shutdown().await
+    });
+
+    let mut r = BufReader::new(r);
+    let mut buf = Vec::new();
+    let mut decoder: Option<LineDecoder> = None;
+    let mut line = 0;
+    loop {
+        let too_long = tokio::select! {
+            read = read_line(&mut r, &mut buf) => match read? {
+                Some(too_long) => too_long,
+                None => break,
+            },
+            _ = stopped.wait_for(|stopped| *stopped) => break,
+        };
+
+        line += 1;
+        let ack = match (too_long, std::str::from_utf8(&buf)) {
+            (true, _) => reject(line, &buf, format!("line longer than {} bytes", MAX_LINE)),
+            (false, Err(e)) => reject(line, &buf, e.to_string()),
+            (false, Ok(raw)) if raw.trim().is_empty() => continue,
+            (false, Ok(raw)) => match &decoder {
+                Some(format) => ledger.submit(format.decode(line, raw)).await?,
+                // the first line is the csv header, or the first JSON object
+                None => match LineDecoder::detect(raw) {
+                    Ok(LineDecoder::Csv(header)) => {
+                        decoder = Some(LineDecoder::Csv(header));
+                        continue;
+                    }
+                    Ok(detected) => ledger.submit(decoder.insert(detected).decode(line, raw)).await?,
+                    Err(e) => reject(line, &buf, e.to_string()),
+                },
+            },
+        };
+
+        if pending.send(ack).await.is_err() {
+            // the writer failed, its error is below
+            break;
+        }
+    }
+
+    drop(pending);
+    writer.await.map_err(io::Error::other)?
+}
+
+/// read_line reads the next line of r into buf, without its line break, and tells if it's longer than MAX_LINE,
+/// in which case buf only has its start. It returns None at the end of the feed.
+async fn read_line<R: AsyncBufRead + Unpin>(r: &mut R, buf: &mut Vec<u8>) -> io::Result<Option<bool>> {
+    buf.clear();
+    let mut too_long = false;
+    loop {
+        let available = r.fill_buf().await?;
+        if available.is_empty() {
+            return Ok((!buf.is_empty() || too_long).then_some(too_long));
+        }
+
+        let (chunk, used, end) = match available.iter().position(|&b| b == b'\n') {
+            Some(i) => (&available[..i], i + 1, true),
+            None => (available, available.len(), false),
+        };
+        let room = MAX_LINE - buf.len();
+        too_long |= chunk.len() > room;
+        buf.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        r.consume(used);
+
+        if end {
+            if buf.last() == Some(&b'\r') {
+                buf.pop();
+            }
+            return Ok(Some(too_long));
+        }
+    }
+}
+
+/// reject returns the ack of a line rejected before it's decoded
+fn reject(line: u64, raw: &[u8], detail: String) -> oneshot::Receiver<Ack> {
+    let (reply, ack) = oneshot::channel();
+    let _ = reply.send(Ack::Rejected(Rejection {
+        line,
+        client_id: None,
+        tx_id: None,
+        reason: TxError::InvaidFormatError,
+        detail,
+        record: String::from_utf8_lossy(raw).into_owned(),
+    }));
+    ack
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use rust_decimal::Decimal;
+    use serde_json::Value;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        sync::oneshot,
+        task::JoinHandle,
+    };
+
+    use crate::model::{Bookkeeper, Currency};
+
+    use crate::server::serve;
+
+    async fn start() -> (SocketAddr, oneshot::Sender<()>, JoinHandle<std::io::Result<Bookkeeper>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, Bookkeeper::new(), async {
+            let _ = stopped.await;
+        }));
+        (addr, stop, server)
+    }
+
+    /// feed sends input and returns the acks
+    async fn feed(addr: SocketAddr, input: impl AsRef<[u8]>) -> Vec<Value> {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(input.as_ref()).await.unwrap();
+        stream.shutdown().await.unwrap();
+
+        let mut acks = String::new();
+        stream.read_to_string(&mut acks).await.unwrap();
+        acks.lines().map(|ack| serde_json::from_str(ack).unwrap()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_acks() {
+        let (addr, stop, server) = start().await;
+
+        let acks = feed(addr, "type, client, tx, amount\ndeposit, 1, 1, 2.0\n\nwithdrawal, 1, 2, 5.0\n".to_string()).await;
+        assert_eq!(acks.len(), 2);
+        assert_eq!(acks[0], serde_json::json!({"status": "ok", "line": 2}));
+        assert_eq!(acks[1]["status"], "rejected");
+        assert_eq!(acks[1]["line"], 4);
+        assert_eq!(acks[1]["reason"], "invalid_amount");
+
+        let acks = feed(
+            addr,
+            "{\"type\": \"deposit\", \"client\": 2, \"tx\": 1, \"amount\": 1}\n{\"type\": \"deposit\", \"client\": 2, \"tx\": 3, \"amount\": 1}\nnot json\n"
+                .to_string(),
+        )
+        .await;
+        let reasons: Vec<_> = acks.iter().map(|ack| ack.get("reason").and_then(Value::as_str).unwrap_or("ok")).collect();
+        assert_eq!(reasons, vec!["duplicate_tx_id", "ok", "invalid_format"]);
+
+        stop.send(()).unwrap();
+        let bkeeper = server.await.unwrap().unwrap();
+        assert!(bkeeper.accounts.get(&1).unwrap().balances(Currency::USD).total == Decimal::from(2));
+        assert!(bkeeper.accounts.get(&2).unwrap().balances(Currency::USD).total == Decimal::from(1));
+    }
+
+    /// Check that a line which can't be read is rejected, and the feed goes on
+    #[tokio::test]
+    async fn test_bad_lines() {
+        let (addr, stop, server) = start().await;
+
+        let mut input = b"\xff\xfe\r\ntype, client, tx, amount\r\ndeposit, 1, 1, \xff\r\n".to_vec();
+        input.extend(std::iter::repeat_n(b'1', super::MAX_LINE + 1));
+        input.extend(b"\ndeposit, 1, 2, 1.0\n");
+        let acks = feed(addr, input).await;
+        let reasons: Vec<_> = acks.iter().map(|ack| (ack["line"].as_u64().unwrap(), ack.get("reason").and_then(Value::as_str).unwrap_or("ok"))).collect();
+        assert_eq!(reasons, vec![(1, "invalid_format"), (3, "invalid_format"), (4, "invalid_format"), (5, "ok")]);
+        assert_eq!(acks[0]["record"], "\u{fffd}\u{fffd}");
+        assert_eq!(acks[2]["record"].as_str().unwrap().len(), super::MAX_LINE);
+
+        stop.send(()).unwrap();
+        let bkeeper = server.await.unwrap().unwrap();
+        assert!(bkeeper.accounts.get(&1).unwrap().balances(Currency::USD).total == Decimal::from(1));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_feeds() {
+        let (addr, stop, server) = start().await;
+
+        // every feed deposits 1.0 100 times for its own client, and a shared tx id which only one of them gets
+        let feeds: Vec<_> = (0..16u32)
+            .map(|client| {
+                let mut input = String::from("type, client, tx, amount\ndeposit, 0, 1000000, 1.0\n");
+                for i in 0..100 {
+                    input.push_str(&format!("deposit, {}, {}, 1.0\n", client, client * 1000 + i));
+                }
+                tokio::spawn(feed(addr, input))
+            })
+            .collect();
+
+        let mut rejected = 0;
+        for f in feeds {
+            let acks = f.await.unwrap();
+            assert_eq!(acks.len(), 101);
+            rejected += acks.iter().filter(|ack| ack["status"] == "rejected").count();
+        }
+        assert_eq!(rejected, 15);
+
+        stop.send(()).unwrap();
+        let bkeeper = server.await.unwrap().unwrap();
+        assert_eq!(bkeeper.accounts.len(), 16);
+        assert!(bkeeper.accounts.get(&3).unwrap().balances(Currency::USD).total == Decimal::from(100));
+        assert!(bkeeper.accounts.get(&0).unwrap().balances(Currency::USD).total == Decimal::from(101));
+    }
+}
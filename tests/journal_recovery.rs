@@ -0,0 +1,92 @@
+use std::fmt::Write as _;
+use std::fs;
+use std::io::Write as _;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const BKEEPER: &str = env!("CARGO_BIN_EXE_bkeeper");
+const ROWS: u32 = 50_000;
+
+/// transactions returns deposits/withdrawals across 97 clients, with some disputes/resolves on the way
+fn transactions() -> String {
+    let mut input = String::from("type, client, tx, amount\n");
+    for tx in 1..=ROWS {
+        let client = tx % 97 + 1;
+        match tx % 13 {
+            5 => writeln!(input, "withdrawal, {}, {}, 0.5", client, tx),
+            7 if tx > 97 => writeln!(input, "dispute, {}, {},", client, tx - 97),
+            11 if tx > 194 => writeln!(input, "resolve, {}, {},", client, tx - 194),
+            _ => writeln!(input, "deposit, {}, {}, {}.{}", client, tx, tx % 10, tx % 7),
+        }
+        .unwrap();
+    }
+    input
+}
+
+/// journaled returns the number of complete entries of the journal at path
+fn journaled(path: &Path) -> usize {
+    let content = fs::read_to_string(path).unwrap_or_default();
+    content.matches('\n').count().saturating_sub(1)
+}
+
+fn run(args: &[&str]) -> String {
+    let output = Command::new(BKEEPER).args(args).env("RUST_LOG", "off").output().unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+// the input is a fifo, so that the run can't get past the rows written to it before it's killed
+#[cfg(unix)]
+#[test]
+fn test_kill_and_recover() {
+    let dir = tempfile::tempdir().unwrap();
+    let input = dir.path().join("transactions.csv");
+    let fifo = dir.path().join("transactions.fifo");
+    let journal = dir.path().join("journal");
+    let rejections = dir.path().join("rejections.csv");
+    let content = transactions();
+    fs::write(&input, &content).unwrap();
+    assert!(Command::new("mkfifo").arg(&fifo).status().unwrap().success());
+    let input = input.to_str().unwrap();
+    let journal_arg = journal.to_str().unwrap();
+    let rejections_arg = rejections.to_str().unwrap();
+
+    let expected = run(&[input, "--rejections", rejections_arg]);
+    let expected_rejections = fs::read_to_string(&rejections).unwrap();
+    assert!(expected_rejections.lines().count() > 1);
+
+    // kill it once every row written so far is journaled, each row being either applied or rejected
+    let mut child = Command::new(BKEEPER)
+        .args([fifo.to_str().unwrap(), "--journal", journal_arg, "--journal-sync", "never", "--rejections", rejections_arg])
+        .env("RUST_LOG", "off")
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    let half = ROWS as usize / 2;
+    let mut w = fs::OpenOptions::new().write(true).open(&fifo).unwrap();
+    for line in content.split_inclusive('\n').take(1 + half) {
+        w.write_all(line.as_bytes()).unwrap();
+    }
+    w.flush().unwrap();
+    let deadline = Instant::now() + Duration::from_secs(60);
+    while journaled(&journal) < half {
+        assert!(Instant::now() < deadline, "only {} of {} rows journaled", journaled(&journal), half);
+        thread::sleep(Duration::from_millis(10));
+    }
+    child.kill().unwrap();
+    child.wait().unwrap();
+    drop(w);
+    assert_eq!(journaled(&journal), half);
+
+    // the report is deterministic, so it must be the same byte for byte, and so must the rejections
+    let recovered = run(&[input, "--journal", journal_arg, "--recover", "--rejections", rejections_arg]);
+    assert_eq!(recovered, expected);
+    assert_eq!(fs::read_to_string(&rejections).unwrap(), expected_rejections);
+
+    // recovering a complete journal changes nothing
+    let again = run(&[input, "--journal", journal_arg, "--recover", "--rejections", rejections_arg]);
+    assert_eq!(again, expected);
+    assert_eq!(fs::read_to_string(&rejections).unwrap(), expected_rejections);
+}
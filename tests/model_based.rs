@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+use proptest::prelude::*;
+use rust_decimal::Decimal;
+
+use bkeeper::model::{AccountState, Bookkeeper, Currency, InputFormat, LogRejections, ReportFormat, ReportOrder, ReportWriter, Transaction, TxError, TxType};
+
+const CLIENTS: u16 = 5;
+const TX_IDS: u32 = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    None,
+    Disputed,
+    ChargedBack,
+}
+
+/// ModelAccount is the reference account: the rules of the README written as plainly as possible
+#[derive(Debug, Clone)]
+struct ModelAccount {
+    /// available and held by currency
+    balances: HashMap<Currency, (Decimal, Decimal)>,
+    state: AccountState,
+    deposits: HashMap<u32, (Decimal, Currency, Status)>,
+    withdrawals: HashMap<u32, (Decimal, Currency, Status)>,
+}
+
+impl ModelAccount {
+    fn new() -> ModelAccount {
+        ModelAccount {
+            balances: HashMap::new(),
+            state: AccountState::Active,
+            deposits: HashMap::new(),
+            withdrawals: HashMap::new(),
+        }
+    }
+
+    fn available(&self, currency: Currency) -> Decimal {
+        self.balances.get(&currency).map_or(Decimal::ZERO, |b| b.0)
+    }
+
+    fn held(&self, currency: Currency) -> Decimal {
+        self.balances.get(&currency).map_or(Decimal::ZERO, |b| b.1)
+    }
+
+    fn total(&self, currency: Currency) -> Decimal {
+        self.available(currency) + self.held(currency)
+    }
+
+    /// apply may leave the account half changed on an error, the model throws it away then
+    fn apply(&mut self, tx: &Transaction) -> Result<(), TxError> {
+        match tx.r#type {
+            TxType::Deposit | TxType::Withdrawal => {
+                match self.state {
+                    AccountState::Active => {}
+                    AccountState::Frozen => return Err(TxError::FrozenAccountError),
+                    AccountState::Locked => return Err(TxError::LockedAccountError),
+                    AccountState::Closed => return Err(TxError::ClosedAccountError),
+                }
+                let amount = tx.amount.ok_or(TxError::MissingAmountError)?;
+                if amount <= Decimal::ZERO {
+                    return Err(TxError::InvalidAmountError);
+                }
+                let known = self.deposits.contains_key(&tx.tx_id) || self.withdrawals.contains_key(&tx.tx_id);
+                let currency = tx.currency.unwrap_or_default();
+                let balances = self.balances.entry(currency).or_default();
+
+                if tx.r#type == TxType::Deposit {
+                    if known {
+                        return Err(TxError::InvalidTxIdError);
+                    }
+                    balances.0 += amount;
+                    self.deposits.insert(tx.tx_id, (amount, currency, Status::None));
+                } else {
+                    if amount > balances.0 {
+                        return Err(TxError::InvalidAmountError);
+                    }
+                    if known {
+                        return Err(TxError::InvalidTxIdError);
+                    }
+                    balances.0 -= amount;
+                    self.withdrawals.insert(tx.tx_id, (amount, currency, Status::None));
+                }
+            }
+            TxType::Dispute | TxType::Resolve | TxType::ChargeBack => {
+                match self.state {
+                    AccountState::Active | AccountState::Frozen => {}
+                    AccountState::Locked => return Err(TxError::LockedAccountError),
+                    AccountState::Closed => return Err(TxError::ClosedAccountError),
+                }
+                let (from, to) = match tx.r#type {
+                    TxType::Dispute => (Status::None, Status::Disputed),
+                    TxType::Resolve => (Status::Disputed, Status::None),
+                    _ => (Status::Disputed, Status::ChargedBack),
+                };
+
+                let (is_withdrawal, (amount, currency, status)) = match (self.withdrawals.get_mut(&tx.tx_id), self.deposits.get_mut(&tx.tx_id)) {
+                    (Some(withdrawal), _) => (true, withdrawal),
+                    (None, Some(deposit)) => (false, deposit),
+                    (None, None) => return Err(TxError::InvalidTxIdError),
+                };
+                if tx.currency.is_some_and(|c| c != *currency) {
+                    return Err(TxError::CurrencyMismatchError);
+                }
+                if *status != from {
+                    return Err(TxError::InvalidOperatioonError);
+                }
+                *status = to;
+                let amount = *amount;
+                let (available, held) = self.balances.entry(*currency).or_default();
+
+                if is_withdrawal {
+                    // a disputed withdrawal is back in held, until it stands or is charged back to available
+                    match to {
+                        Status::Disputed => *held += amount,
+                        Status::None => *held -= amount,
+                        Status::ChargedBack => {
+                            *held -= amount;
+                            *available += amount;
+                            self.state = AccountState::Locked;
+                        }
+                    }
+                } else {
+                    match to {
+                        Status::Disputed if *available < amount => return Err(TxError::InvalidAmountError),
+                        Status::Disputed => {
+                            *available -= amount;
+                            *held += amount;
+                        }
+                        Status::None => {
+                            *held -= amount;
+                            *available += amount;
+                        }
+                        Status::ChargedBack => {
+                            *held -= amount;
+                            self.state = AccountState::Locked;
+                        }
+                    }
+                }
+            }
+            TxType::Unlock | TxType::Freeze | TxType::Close => {
+                if tx.r#type == TxType::Close && self.balances.keys().any(|&c| !self.total(c).is_zero()) {
+                    return Err(TxError::InvalidOperatioonError);
+                }
+                if self.state == AccountState::Closed {
+                    return Err(TxError::ClosedAccountError);
+                }
+                self.state = match (tx.r#type.clone(), self.state) {
+                    (TxType::Unlock, AccountState::Frozen | AccountState::Locked) => AccountState::Active,
+                    (TxType::Freeze, AccountState::Active) => AccountState::Frozen,
+                    (TxType::Close, _) => AccountState::Closed,
+                    _ => return Err(TxError::InvalidOperatioonError),
+                };
+            }
+        }
+
+        Ok(())
+    }
+
+    /// funds is what the client brought in and didn't take out in currency: the deposits not charged back, less the withdrawals which stand
+    fn funds(&self, currency: Currency) -> Decimal {
+        let deposits: Decimal = self.deposits.values().filter(|(_, c, s)| *c == currency && *s != Status::ChargedBack).map(|(a, _, _)| a).sum();
+        let withdrawals: Decimal = self.withdrawals.values().filter(|(_, c, s)| *c == currency && *s == Status::None).map(|(a, _, _)| a).sum();
+        deposits - withdrawals
+    }
+}
+
+/// Model is the reference ledger, with the default account policy: an account is opened by a deposit
+#[derive(Default)]
+struct Model {
+    accounts: HashMap<u16, ModelAccount>,
+    owners: HashMap<u32, u16>,
+}
+
+impl Model {
+    fn apply(&mut self, tx: &Transaction) -> Result<(), TxError> {
+        let new_tx = tx.r#type == TxType::Deposit || tx.r#type == TxType::Withdrawal;
+        if !tx.r#type.is_admin() {
+            match self.owners.get(&tx.tx_id) {
+                Some(&owner) if owner != tx.client_id => {
+                    return Err(if new_tx { TxError::DuplicateTxIdError } else { TxError::ClientMismatchError { owner } });
+                }
+                None if !new_tx => return Err(TxError::InvalidTxIdError),
+                _ => {}
+            }
+        }
+
+        let mut acct = match self.accounts.get(&tx.client_id) {
+            Some(acct) => acct.clone(),
+            None if tx.r#type == TxType::Deposit => ModelAccount::new(),
+            None => return Err(TxError::InvalidClientError),
+        };
+        acct.apply(tx)?;
+
+        if new_tx {
+            self.owners.insert(tx.tx_id, tx.client_id);
+        }
+        self.accounts.insert(tx.client_id, acct);
+        Ok(())
+    }
+}
+
+fn tx_type() -> impl Strategy<Value = TxType> {
+    prop_oneof![
+        4 => Just(TxType::Deposit),
+        3 => Just(TxType::Withdrawal),
+        3 => Just(TxType::Dispute),
+        2 => Just(TxType::Resolve),
+        2 => Just(TxType::ChargeBack),
+        1 => Just(TxType::Unlock),
+        1 => Just(TxType::Freeze),
+        1 => Just(TxType::Close),
+    ]
+}
+
+/// CURRENCIES are the currencies of the transactions, a dispute/resolve/chargeback mostly gives none
+const CURRENCIES: [Currency; 2] = [Currency::USD, Currency::EUR];
+
+/// transaction generates any transaction, valid or not, on a few clients and tx ids so that they often collide
+fn transaction() -> impl Strategy<Value = Transaction> {
+    // amounts on a coarse grid, so that a withdrawal or a dispute often takes exactly what is available
+    let amount = prop_oneof![
+        8 => (1i64..=40).prop_map(|n| Some(Decimal::new(n * 25, 2))),
+        1 => (-1_000i64..=0).prop_map(|n| Some(Decimal::new(n, 2))),
+        1 => Just(None),
+    ];
+
+    let currency = prop_oneof![
+        2 => Just(None),
+        1 => Just(Some(Currency::USD)),
+        2 => Just(Some(Currency::EUR)),
+    ];
+
+    (tx_type(), 0..CLIENTS, 0..TX_IDS, amount, currency).prop_map(|(r#type, client_id, tx_id, amount, currency)| {
+        // a dispute/resolve/chargeback mostly refers to its tx without a currency
+        let currency = match r#type {
+            TxType::Deposit | TxType::Withdrawal => currency,
+            _ => currency.filter(|_| tx_id % 3 == 0),
+        };
+        Transaction {
+            r#type,
+            client_id,
+            tx_id,
+            amount,
+            currency,
+            reason: None,
+            timestamp: None,
+        }
+    })
+}
+
+/// the balances of every currency, the state and the number of events of an account
+type AccountFingerprint = (Vec<(Currency, Decimal, Decimal, Decimal)>, AccountState, usize);
+
+/// fingerprint is what a rejected transaction must not change
+fn fingerprint(keeper: &Bookkeeper, client_id: u16) -> (usize, Option<AccountFingerprint>) {
+    let acct = keeper.accounts.get(&client_id).map(|a| {
+        let buckets = a.buckets.iter().map(|(&c, b)| (c, b.available, b.held, b.total)).collect();
+        (buckets, a.state, a.events().len())
+    });
+    (keeper.accounts.len(), acct)
+}
+
+fn csv(txs: &[Transaction]) -> String {
+    let mut input = String::from("type, client, tx, amount, currency\n");
+    for tx in txs {
+        let amount = tx.amount.map(|a| a.to_string()).unwrap_or_default();
+        let currency = tx.currency.map(|c| c.to_string()).unwrap_or_default();
+        writeln!(input, "{}, {}, {}, {}, {}", format!("{:?}", tx.r#type).to_lowercase(), tx.client_id, tx.tx_id, amount, currency).unwrap();
+    }
+    input
+}
+
+fn report(keeper: &Bookkeeper) -> String {
+    let mut buf = Vec::new();
+    keeper.write_report(&mut ReportWriter::new(&mut buf, ReportFormat::Csv), ReportOrder::Insertion).unwrap();
+    String::from_utf8(buf).unwrap()
+}
+
+proptest! {
+    /// Every transaction gets the same answer as from the model, a rejected one changes nothing,
+    /// and the balances are the model's after every step
+    #[test]
+    fn test_same_as_model(txs in prop::collection::vec(transaction(), 0..300)) {
+        let mut keeper = Bookkeeper::new();
+        let mut model = Model::default();
+
+        for (line, tx) in txs.iter().enumerate() {
+            let before = fingerprint(&keeper, tx.client_id);
+            let got = keeper.process_tx(line as u64 + 2, tx).unwrap();
+            prop_assert_eq!(&got, &model.apply(tx), "{:?}", tx);
+            if got.is_err() {
+                prop_assert_eq!(fingerprint(&keeper, tx.client_id), before);
+            }
+
+            prop_assert_eq!(keeper.accounts.len(), model.accounts.len());
+            let acct = keeper.accounts.get(&tx.client_id);
+            if let (Some(acct), Some(expected)) = (acct, model.accounts.get(&tx.client_id)) {
+                prop_assert_eq!(acct.state, expected.state);
+                for c in CURRENCIES {
+                    let b = acct.balances(c);
+                    prop_assert_eq!((b.available, b.held, b.total), (expected.available(c), expected.held(c), expected.total(c)));
+                }
+            }
+        }
+
+        // conservation of money: every account holds what its client brought in and didn't take out,
+        // and the general ledger agrees
+        for (client_id, expected) in &model.accounts {
+            let acct = keeper.accounts.get(client_id).unwrap();
+            for c in CURRENCIES {
+                let b = acct.balances(c);
+                prop_assert_eq!(b.total, expected.funds(c));
+                prop_assert!(b.available >= Decimal::ZERO && b.held >= Decimal::ZERO);
+            }
+        }
+        prop_assert!(keeper.trial_balance().is_balanced());
+        prop_assert_eq!(keeper.audit(), vec![]);
+    }
+
+    /// The parallel engine gives the same accounts as one thread
+    #[test]
+    fn test_parallel_same_as_sequential(txs in prop::collection::vec(transaction(), 0..300), threads in 2usize..5) {
+        let input = csv(&txs);
+
+        let mut sequential = Bookkeeper::new();
+        sequential.process_input(input.as_bytes(), InputFormat::Csv, &mut LogRejections).unwrap();
+        let mut parallel = Bookkeeper::new();
+        parallel.process_input_parallel(input.as_bytes(), InputFormat::Csv, &mut LogRejections, threads).unwrap();
+
+        prop_assert_eq!(report(&parallel), report(&sequential));
+        prop_assert_eq!(parallel.trial_balance(), sequential.trial_balance());
+    }
+}